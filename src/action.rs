@@ -0,0 +1,51 @@
+
+use std::path::PathBuf;
+use super::loopable::{Pattern, Phrase};
+use super::mixer::CrossfadeGroup;
+use super::quantize::Quantizer;
+use super::groove::Groove;
+use super::channel::Routing;
+use super::TickRange;
+
+/*
+ * What front ends ask the process thread to do. Lines typed in the console & OSC messages are
+ * turned into these, the process handler carries them out as a single edit so they can be undone
+ */
+pub enum Action {
+    // Save project, to the path we loaded from when no path is given
+    Save(Option<PathBuf>),
+    // Export timeline as standard midi file
+    Export(PathBuf),
+    // Imported patterns, these are put in channel starting at pattern index. Patterns & phrase are
+    // checked to fit in channel before they're handed over
+    LoadPatterns { channel: usize, pattern: u8, patterns: Vec<Pattern>, phrase: Option<(u8, Phrase)> },
+    // Tempo change at start of bar, removes tempo change when no tempo is given
+    Tempo { bar: u32, beats_per_minute: Option<f64>, is_ramp: bool },
+    // Beats per bar & beat type starting at bar, removes meter change when no meter is given
+    Meter { bar: u32, meter: Option<(u8, u8)> },
+    // Put channel on side of crossfader, channel is not affected by crossfader when no group is given
+    Crossfade { channel: usize, group: Option<CrossfadeGroup> },
+    // Quantize recorded & drawn notes with quantizer, stop quantizing when no quantizer is given
+    Quantize(Option<Quantizer>),
+    // Groove of channel or of pattern in channel, removes groove when no groove is given
+    Groove { channel: usize, pattern: Option<u8>, groove: Option<Groove> },
+    // Port, midi channel & transposition channel plays on
+    Output { channel: usize, routing: Routing },
+    // Instrument channel plays, removes instrument when no name is given
+    Instrument { channel: usize, name: Option<String> },
+
+    // Sent by OSC clients, these are not typed in the console
+    Play,
+    Stop,
+    Reset,
+    ShowChannel(u8),
+    ShowPattern(u8),
+    ShowPhrase(u8),
+    ShowSequence(u8),
+    // Toggle note spanning tick range in pattern
+    Note { channel: usize, pattern: u8, tick_range: TickRange, note: u8 },
+    PatternLength { channel: usize, pattern: u8, length: u32 },
+    PhraseLength { channel: usize, phrase: u8, length: u32 },
+    QueueSequence(usize),
+    BeatsPerMinute(f64),
+}
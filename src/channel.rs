@@ -1,111 +1,432 @@
 
-use super::port::*;
 use super::loopable::*;
 use super::cycle::*;
 use super::events::*;
 use super::message::*;
+use super::project::{ChannelState, ProjectChange};
+use super::groove::Groove;
+use super::instrument::Instrument;
+use super::history::{Edit, Part};
+
+/*
+ * Port & midi channel a channel sends to, channels can share a port when they use different midi
+ * channels. Notes are transposed on their way out
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Routing {
+    pub port: usize,
+    pub midi_channel: u8,
+    pub transpose: i8,
+}
+
+impl Routing {
+    pub fn new(port: usize, midi_channel: u8, transpose: i8) -> Self {
+        Routing { port, midi_channel, transpose }
+    }
+
+    // Notes transposed out of midi range are not played
+    pub fn note(&self, note: u8) -> Option<u8> {
+        let note = note as i16 + self.transpose as i16;
+        if (0 ..= 127).contains(&note) { Some(note as u8) } else { None }
+    }
+
+    // Status is without midi channel
+    fn note_message(&self, frame: u32, status: u8, note: u8, velocity: u8) -> Option<TimedMessage> {
+        self.note(note).map(|note| TimedMessage::new(frame, Message::Note([status + self.midi_channel, note, velocity])))
+    }
+
+    fn control_message(&self, frame: u32, controller: u8, value: u8) -> TimedMessage {
+        TimedMessage::new(frame, Message::ControlChange([0xB0 + self.midi_channel, controller, value]))
+    }
+
+    fn program_message(&self, frame: u32, program: u8) -> TimedMessage {
+        TimedMessage::new(frame, Message::ProgramChange([0xC0 + self.midi_channel, program]))
+    }
+}
 
 pub struct Channel {
     // TODO - these are public as we're testing with premade patterns
-    pub patterns: [Pattern; 5],
-    pub phrases: [Phrase; 5],
+    pub patterns: Vec<Pattern>,
+    pub phrases: Vec<Phrase>,
     pub timeline: Timeline,
+    // Groove of patterns that don't have their own groove
+    pub groove: Option<Groove>,
 
     playing_notes: Vec<PlayingNoteEvent>,
+    // Notes that are delayed by groove into a next cycle
+    pending_notes: Vec<PlayingNoteEvent>,
+    // Messages are collected during the cycle, as jack clears the port every time we write to it
+    queued_messages: Vec<TimedMessage>,
+
+    // Remember what loopables were touched since last autosave
+    changed_patterns: Vec<bool>,
+    changed_phrases: Vec<bool>,
+    changed_timeline: bool,
+    changed_groove: bool,
+    changed_routing: bool,
+    changed_instrument: bool,
+    // Loopables as they were before they were changed, collected while editing so edits can be undone
+    edit: Option<Edit>,
 
-    //knob_values: [u8; 128],
+    // Last value of every controller we sent, so controller can show & pick up these values
+    knob_values: [u8; 128],
 
     id: u8,
-    output: MidiOut,
+    routing: Routing,
+    // Instrument we play, it decides where our midi goes
+    instrument: Option<Instrument>,
 }
 
 impl Channel {
-    pub fn new(client: &jack::Client, id: u8) -> Self {
-        let patterns = [Pattern::new(), Pattern::new(), Pattern::new(), Pattern::new(), Pattern::new()];
-        let phrases = [Phrase::new(), Phrase::new(), Phrase::new(), Phrase::new(), Phrase::new()];
+    // Effect knobs send these controllers, starting at first undefined controller
+    pub const KNOB_CONTROLLER_OFFSET: u8 = 20;
+    // Patterns & phrases come in banks, one loopable for every side button
+    pub const BANK_SIZE: u8 = 5;
+    pub const BANKS: u8 = 8;
+    pub const LOOPABLES: usize = Self::BANK_SIZE as usize * Self::BANKS as usize;
 
-        //let input = client.register_port("APC20 in", jack::MidiIn::default()).unwrap();
-        let output = client.register_port(format!("channel_{}", id).as_str(), jack::MidiOut::default()).unwrap();
+    pub fn new(id: u8) -> Self {
+        let patterns = (0 .. Self::LOOPABLES).map(|_| Pattern::new()).collect();
+        let phrases = (0 .. Self::LOOPABLES).map(|_| Phrase::new()).collect();
 
         Channel {
             phrases,
             patterns,
             timeline: Timeline::new(),
+            groove: None,
 
             playing_notes: vec![],
+            pending_notes: vec![],
+            queued_messages: vec![],
+
+            changed_patterns: vec![false; Self::LOOPABLES],
+            changed_phrases: vec![false; Self::LOOPABLES],
+            changed_timeline: false,
+            changed_groove: false,
+            changed_routing: false,
+            changed_instrument: false,
+            edit: None,
+
+            knob_values: [0; 128],
 
             id,
-            output: MidiOut::new(output),
+            // Every channel has it's own port by default
+            routing: Routing::new(id as usize, id, 0),
+            instrument: None,
         }
     }
 
     pub fn pattern(&self, index: u8) -> &Pattern { &self.patterns[index as usize] }
-    pub fn pattern_mut(&mut self, index: u8) -> &mut Pattern { &mut self.patterns[index as usize] }
+    pub fn pattern_mut(&mut self, index: u8) -> &mut Pattern {
+        if let Some(edit) = &mut self.edit {
+            let (channel, patterns) = (self.id as usize, &self.patterns);
+            edit.remember(Part::Pattern(channel, index as usize), || ProjectChange::Pattern(channel, index as usize, patterns[index as usize].clone()));
+        }
+        self.changed_patterns[index as usize] = true;
+        &mut self.patterns[index as usize]
+    }
 
     pub fn phrase(&self, index: u8) -> &Phrase { &self.phrases[index as usize] }
-    pub fn phrase_mut(&mut self, index: u8) -> &mut Phrase { &mut self.phrases[index as usize] }
+    pub fn phrase_mut(&mut self, index: u8) -> &mut Phrase {
+        if let Some(edit) = &mut self.edit {
+            let (channel, phrases) = (self.id as usize, &self.phrases);
+            edit.remember(Part::Phrase(channel, index as usize), || ProjectChange::Phrase(channel, index as usize, phrases[index as usize].clone()));
+        }
+        self.changed_phrases[index as usize] = true;
+        &mut self.phrases[index as usize]
+    }
+
+    pub fn timeline_mut(&mut self) -> &mut Timeline {
+        if let Some(edit) = &mut self.edit {
+            let (channel, timeline) = (self.id as usize, &self.timeline);
+            edit.remember(Part::Timeline(channel), || ProjectChange::Timeline(channel, timeline.clone()));
+        }
+        self.changed_timeline = true;
+        &mut self.timeline
+    }
+
+    // Queued sequences write to the timeline, that's playing, not editing
+    pub fn add_timeline_event(&mut self, event: LoopablePhraseEvent) {
+        self.changed_timeline = true;
+        self.timeline.add_complete_event(event);
+    }
+
+    pub fn set_groove(&mut self, groove: Option<Groove>) {
+        if let Some(edit) = &mut self.edit {
+            let (channel, previous) = (self.id as usize, &self.groove);
+            edit.remember(Part::Groove(channel), || ProjectChange::Groove(channel, previous.clone()));
+        }
+        self.replace_groove(groove);
+    }
+
+    // Swap parts without remembering them, used to undo & redo edits
+    pub fn replace_pattern(&mut self, index: u8, pattern: Pattern) -> Pattern {
+        self.changed_patterns[index as usize] = true;
+        std::mem::replace(&mut self.patterns[index as usize], pattern)
+    }
+
+    pub fn replace_phrase(&mut self, index: u8, phrase: Phrase) -> Phrase {
+        self.changed_phrases[index as usize] = true;
+        std::mem::replace(&mut self.phrases[index as usize], phrase)
+    }
+
+    pub fn replace_timeline(&mut self, timeline: Timeline) -> Timeline {
+        self.changed_timeline = true;
+        std::mem::replace(&mut self.timeline, timeline)
+    }
+
+    pub fn replace_groove(&mut self, groove: Option<Groove>) -> Option<Groove> {
+        self.changed_groove = true;
+        std::mem::replace(&mut self.groove, groove)
+    }
+
+    pub fn begin_edit(&mut self) {
+        self.edit = Some(Edit::new());
+    }
+
+    // States of loopables before they were changed by edit
+    pub fn end_edit(&mut self) -> Option<Edit> {
+        self.edit.take()
+    }
 
     pub fn clone_pattern(&mut self, from: u8, to: u8) {
-        self.patterns[to as usize] = self.patterns[from as usize].clone();
+        *self.pattern_mut(to) = self.patterns[from as usize].clone();
     }
 
     pub fn clone_phrase(&mut self, from: u8, to: u8) {
-        self.phrases[to as usize] = self.phrases[from as usize].clone();
+        *self.phrase_mut(to) = self.phrases[from as usize].clone();
+    }
+
+    // Copy of loopables, used to save the channel
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            patterns: self.patterns.clone(),
+            phrases: self.phrases.clone(),
+            timeline: self.timeline.clone(),
+            groove: self.groove.clone(),
+            routing: self.routing,
+            instrument: self.instrument.as_ref().map(|instrument| instrument.name.clone()),
+        }
+    }
+
+    // Copies of the parts that changed since we last asked
+    pub fn take_changes(&mut self) -> Vec<ProjectChange> {
+        let channel = self.id as usize;
+        let mut changes = vec![];
+
+        for (index, pattern) in self.patterns.iter().enumerate().filter(|(index, _)| self.changed_patterns[*index]) {
+            changes.push(ProjectChange::Pattern(channel, index, pattern.clone()));
+        }
+        for (index, phrase) in self.phrases.iter().enumerate().filter(|(index, _)| self.changed_phrases[*index]) {
+            changes.push(ProjectChange::Phrase(channel, index, phrase.clone()));
+        }
+        if self.changed_timeline {
+            changes.push(ProjectChange::Timeline(channel, self.timeline.clone()));
+        }
+        if self.changed_groove {
+            changes.push(ProjectChange::Groove(channel, self.groove.clone()));
+        }
+        if self.changed_routing {
+            changes.push(ProjectChange::Routing(channel, self.routing));
+        }
+        if self.changed_instrument {
+            changes.push(ProjectChange::Instrument(channel, self.instrument.as_ref().map(|instrument| instrument.name.clone())));
+        }
+
+        self.clear_changes();
+        changes
+    }
+
+    // Forget what changed, used when our state was saved as a whole
+    pub fn clear_changes(&mut self) {
+        self.changed_patterns.iter_mut().for_each(|changed| *changed = false);
+        self.changed_phrases.iter_mut().for_each(|changed| *changed = false);
+        self.changed_timeline = false;
+        self.changed_groove = false;
+        self.changed_routing = false;
+        self.changed_instrument = false;
+    }
+
+    pub fn load_state(&mut self, state: ChannelState) {
+        // Loaded projects could hold more loopables than we start out with, never less
+        self.patterns = state.patterns;
+        self.patterns.resize_with(self.patterns.len().max(Self::LOOPABLES), Pattern::new);
+        self.phrases = state.phrases;
+        self.phrases.resize_with(self.phrases.len().max(Self::LOOPABLES), Phrase::new);
+        self.changed_patterns = vec![false; self.patterns.len()];
+        self.changed_phrases = vec![false; self.phrases.len()];
+        self.timeline = state.timeline;
+        self.groove = state.groove;
+        self.replace_routing(state.routing);
+        self.clear_changes();
+    }
+
+    pub fn routing(&self) -> Routing { self.routing }
+
+    pub fn set_routing(&mut self, routing: Routing) {
+        if let Some(edit) = &mut self.edit {
+            let (channel, previous) = (self.id as usize, self.routing);
+            edit.remember(Part::Routing(channel), || ProjectChange::Routing(channel, previous));
+        }
+        self.replace_routing(routing);
+    }
+
+    // Notes that are playing are stopped first, their note offs would not reach them otherwise
+    pub fn replace_routing(&mut self, routing: Routing) -> Routing {
+        self.stop_playing_notes();
+        self.clear_playing_notes();
+        self.changed_routing = true;
+        std::mem::replace(&mut self.routing, routing)
+    }
+
+    pub fn instrument(&self) -> Option<&Instrument> { self.instrument.as_ref() }
+
+    pub fn set_instrument(&mut self, instrument: Option<Instrument>) {
+        if let Some(edit) = &mut self.edit {
+            let (channel, previous, routing) = (self.id as usize, &self.instrument, self.routing);
+            edit.remember(Part::Instrument(channel), || ProjectChange::Instrument(channel, previous.as_ref().map(|instrument| instrument.name.clone())));
+            // Instrument routes the channel, undoing it should put back where we were routed to
+            edit.remember(Part::Routing(channel), || ProjectChange::Routing(channel, routing));
+        }
+        self.replace_instrument(instrument);
+    }
+
+    // Route to instrument & select it's program, transposition is kept
+    pub fn replace_instrument(&mut self, instrument: Option<Instrument>) {
+        if let Some(instrument) = &instrument {
+            self.replace_routing(Routing::new(instrument.port, instrument.midi_channel, self.routing.transpose));
+
+            // Bank select msb & lsb before program change
+            if let Some((msb, lsb)) = instrument.bank {
+                self.queued_messages.push(self.routing.control_message(0, 0, msb));
+                self.queued_messages.push(self.routing.control_message(0, 32, lsb));
+            }
+            if let Some(program) = instrument.program {
+                self.queued_messages.push(self.routing.program_message(0, program));
+            }
+        }
+
+        self.changed_instrument = true;
+        self.instrument = instrument;
+    }
+
+    pub fn default_velocity(&self) -> u8 {
+        self.instrument.as_ref().map(|instrument| instrument.default_velocity).unwrap_or(127)
+    }
+
+    // Lowest & highest note we can play
+    pub fn note_range(&self) -> (u8, u8) {
+        self.instrument.as_ref().map(|instrument| (instrument.lowest_note, instrument.highest_note)).unwrap_or((0, 127))
     }
 
     pub fn clear_playing_notes(&mut self) {
         self.playing_notes = vec![];
+        self.pending_notes = vec![];
     }
 
     // Start all notes in playing notes array. Used when starting mid-track
-    pub fn start_playing_notes(&mut self, cycle: &ProcessCycle) {
-        let mut messages = self.playing_notes.iter()
-            .map(|note| TimedMessage::new(0, Message::Note([0x90 + self.id, note.note, note.start_velocity])))
-            .collect();
+    pub fn start_playing_notes(&mut self) {
+        let routing = self.routing;
+        let messages = self.playing_notes.iter()
+            .filter_map(|note| routing.note_message(0, 0x90, note.note, note.start_velocity));
 
-        self.output.write_midi(cycle.scope, &mut messages);
+        self.queued_messages.extend(messages);
     }
 
     // Stop playing notes, used when stopping mid-track
-    pub fn stop_playing_notes(&mut self, cycle: &ProcessCycle) {
-        let mut messages = self.playing_notes.iter()
-            .map(|note| TimedMessage::new(0, Message::Note([0x80 + self.id, note.note, note.stop_velocity])))
-            .collect();
+    pub fn stop_playing_notes(&mut self) {
+        let routing = self.routing;
+        let messages = self.playing_notes.iter()
+            .filter_map(|note| routing.note_message(0, 0x80, note.note, note.stop_velocity));
+
+        self.queued_messages.extend(messages);
+    }
+
+    // Note on or off played directly on channel, status is without channel
+    pub fn note(&mut self, frame: u32, status: u8, note: u8, velocity: u8) {
+        self.queued_messages.extend(self.routing.note_message(frame, status, note, velocity));
+    }
+
+    pub fn knob_value(&self, controller: u8) -> u8 { self.knob_values[controller as usize] }
 
-        self.output.write_midi(cycle.scope, &mut messages);
+    pub fn control_change(&mut self, frame: u32, controller: u8, value: u8) {
+        self.knob_values[controller as usize] = value;
+        self.queued_messages.push(self.routing.control_message(frame, controller, value));
     }
 
-    pub fn output_midi(&mut self, cycle: &ProcessCycle, starting_notes: Vec<PlayingNoteEvent>) {
+    pub fn output_midi(&mut self, cycle: &ProcessCycle, starting_notes: Vec<PlayingNoteEvent>, control_events: Vec<ControlEvent>) {
         // Always play note off messages
         let mut messages = vec![];
-        let id = self.id;
+        let routing = self.routing;
 
         self.playing_notes.retain(|note| {
             // Play & remove notes that fall in cycle
             if cycle.tick_range.contains(note.stop) {
                 let frame = cycle.tick_to_frame(note.stop);
-                messages.push(TimedMessage::new(frame, Message::Note([0x80 + id, note.note, note.stop_velocity])));
+                messages.extend(routing.note_message(frame, 0x80, note.note, note.stop_velocity));
                 false
             } else {
                 true
             }
         });
 
+        // Play notes delayed into this cycle, keep notes delayed into a next cycle
+        let (mut starting_notes, pending_notes): (Vec<PlayingNoteEvent>, Vec<PlayingNoteEvent>) = self.pending_notes.drain(..)
+            .chain(starting_notes)
+            .partition(|note| note.start < cycle.tick_range.stop);
+        self.pending_notes = pending_notes;
+        starting_notes.sort_by_key(|note| note.start);
+
         // Create actual midi from note representations
         let note_on = starting_notes.iter()
-            .map(|note| {
-                let frame = cycle.tick_to_frame(note.start);
-                TimedMessage::new(frame, Message::Note([0x90 + self.id, note.note, note.start_velocity]))
+            .filter_map(|note| {
+                // Delayed notes could be late when transport did not continue where it left off
+                let frame = cycle.tick_to_frame(note.start.max(cycle.tick_range.start));
+                routing.note_message(frame, 0x90, note.note, note.start_velocity)
             });
 
         messages.extend(note_on);
 
+        // Recorded automation
+        for event in control_events {
+            let frame = cycle.tick_to_frame(event.tick);
+            messages.push(routing.control_message(frame, event.controller, event.value));
+            self.knob_values[event.controller as usize] = event.value;
+        }
 
         // Remember playing notes to later trigger note off message & output note on messages
         self.playing_notes.extend(starting_notes);
 
-        // Output note off mesassages && write midi
-        //self.output.output_messages(&mut messages);
-        self.output.write_midi(cycle.scope, &mut messages);
+        self.queued_messages.append(&mut messages);
+    }
+
+    // Messages queued this cycle, these are written to the port of our routing
+    pub fn take_messages(&mut self) -> Vec<TimedMessage> {
+        std::mem::take(&mut self.queued_messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routing() {
+        let routing = Routing::new(0, 3, -12);
+        assert_eq!(routing.note(60), Some(48));
+        assert_eq!(routing.note(5), None);
+        assert_eq!(routing.note_message(0, 0x90, 60, 100).map(|message| message.message), Some(Message::Note([0x93, 48, 100])));
+        assert_eq!(Routing::new(0, 0, 12).note(120), None);
+    }
+
+    #[test]
+    fn take_changes() {
+        let mut channel = Channel::new(2);
+        assert!(channel.take_changes().is_empty());
+
+        channel.pattern_mut(4);
+        channel.set_groove(Some(Groove::swing(60)));
+        assert!(matches!(channel.take_changes().as_slice(), [ProjectChange::Pattern(2, 4, _), ProjectChange::Groove(2, Some(_))]));
+        assert!(channel.take_changes().is_empty());
     }
 }
@@ -0,0 +1,235 @@
+
+use super::TickRange;
+use super::TimebaseHandler;
+use super::cycle::ProcessCycle;
+use super::message::{TimedMessage, Message};
+use super::port::{MidiOut, MidiIn};
+
+/*
+ * Midi clock output for hardware that needs to follow our transport. Start / stop / continue &
+ * song position pointer are derived from transport changes, that way they are also sent when
+ * another jack client starts or repositions the transport
+ */
+pub struct Clock {
+    output: MidiOut,
+
+    was_rolling: bool,
+    // Tick where we expect the next cycle to start, used to detect repositioning
+    next_tick: Option<u32>,
+}
+
+impl Clock {
+    // 24 pulses per quarter note
+    pub const TICKS_PER_PULSE: u32 = TimebaseHandler::TICKS_PER_BEAT as u32 / 24;
+    // Song position pointer counts in 16th notes
+    const TICKS_PER_MIDI_BEAT: u32 = TimebaseHandler::TICKS_PER_BEAT as u32 / 4;
+
+    const START: u8 = 0xFA;
+    const CONTINUE: u8 = 0xFB;
+    const STOP: u8 = 0xFC;
+    const PULSE: u8 = 0xF8;
+
+    pub fn new(client: Option<&jack::Client>) -> Self {
+        Clock {
+            output: MidiOut::new(client, "clock_out"),
+            was_rolling: false,
+            next_tick: None,
+        }
+    }
+
+    // Song position pointer is rounded down to the 16th note we're in
+    pub fn song_position(tick: u32) -> Message {
+        let midi_beats = (tick / Self::TICKS_PER_MIDI_BEAT).min(0x3FFF);
+        Message::SongPosition([0xF2, (midi_beats & 0x7F) as u8, (midi_beats >> 7) as u8])
+    }
+
+    // Pulses that fall within tick range
+    pub fn pulses(tick_range: &TickRange) -> impl Iterator<Item = u32> {
+        let first = (tick_range.start + Self::TICKS_PER_PULSE - 1) / Self::TICKS_PER_PULSE * Self::TICKS_PER_PULSE;
+        (first .. tick_range.stop).step_by(Self::TICKS_PER_PULSE as usize)
+    }
+
+    pub fn output_midi(&mut self, cycle: &ProcessCycle) {
+        let mut messages = vec![];
+        let tick = cycle.tick_range.start;
+        let is_repositioned = self.next_tick.map(|next_tick| next_tick != tick).unwrap_or(true);
+
+        match (self.was_rolling, cycle.is_rolling) {
+            (false, true) => {
+                if tick == 0 {
+                    messages.push(TimedMessage::new(0, Message::Realtime([Self::START])));
+                } else {
+                    messages.push(TimedMessage::new(0, Self::song_position(tick)));
+                    messages.push(TimedMessage::new(0, Message::Realtime([Self::CONTINUE])));
+                }
+            },
+            (true, false) => messages.push(TimedMessage::new(0, Message::Realtime([Self::STOP]))),
+            // Song position can only be changed while stopped
+            (true, true) if is_repositioned => {
+                messages.push(TimedMessage::new(0, Message::Realtime([Self::STOP])));
+                messages.push(TimedMessage::new(0, Self::song_position(tick)));
+                messages.push(TimedMessage::new(0, Message::Realtime([Self::CONTINUE])));
+            },
+            (false, false) if is_repositioned => messages.push(TimedMessage::new(0, Self::song_position(tick))),
+            _ => (),
+        }
+
+        if cycle.is_rolling {
+            for pulse in Self::pulses(&cycle.tick_range) {
+                messages.push(TimedMessage::new(cycle.tick_to_frame(pulse), Message::Realtime([Self::PULSE])));
+            }
+        }
+
+        self.was_rolling = cycle.is_rolling;
+        self.next_tick = Some(if cycle.is_rolling { cycle.tick_range.stop } else { tick });
+
+        self.output.write_midi(cycle, &mut messages);
+    }
+
+    pub fn take_rendered(&mut self) -> Vec<TimedMessage> {
+        self.output.take_rendered()
+    }
+}
+
+pub enum ClockEvent {
+    Start,
+    Continue,
+    Stop,
+    // Tick to move transport to
+    SongPosition(u32),
+    // Estimated tempo of incoming clock
+    BeatsPerMinute(f64),
+}
+
+/*
+ * Follows midi clock of external device. Tempo is estimated from the time between pulses, as
+ * these are jittery we smooth the estimate. We also keep track of the tick the clock is at, so
+ * we can correct the tempo when our transport drifts away from it
+ */
+pub struct ClockInput {
+    input: MidiIn,
+
+    usecs_per_pulse: Option<f64>,
+    last_pulse_usecs: Option<u64>,
+    // Position of external clock, pulses are counted from start / song position
+    position: u32,
+    pulses: u32,
+}
+
+impl ClockInput {
+    // Amount of every new measurement that goes into tempo estimate
+    const SMOOTHING: f64 = 0.1;
+    // How fast we try to catch up with clock, in tempo factor per beat we're off
+    const DRIFT_CORRECTION: f64 = 0.1;
+    const MAX_DRIFT_CORRECTION: f64 = 0.05;
+
+    pub fn new(client: Option<&jack::Client>) -> Self {
+        ClockInput {
+            input: MidiIn::new(client, "clock_in"),
+            usecs_per_pulse: None,
+            last_pulse_usecs: None,
+            position: 0,
+            pulses: 0,
+        }
+    }
+
+    // Smooth tempo estimate, pauses in the clock start a new estimate
+    pub fn estimate(usecs_per_pulse: Option<f64>, interval: f64) -> Option<f64> {
+        match usecs_per_pulse {
+            Some(estimate) if interval < estimate * 4.0 => Some(estimate + (interval - estimate) * Self::SMOOTHING),
+            Some(_) => None,
+            None => Some(interval),
+        }
+    }
+
+    fn clock_tick(&self) -> u32 {
+        self.position + self.pulses * Clock::TICKS_PER_PULSE
+    }
+
+    pub fn process(&mut self, cycle: &ProcessCycle) -> Vec<ClockEvent> {
+        let mut events = vec![];
+        // Position of external clock & of our transport at last pulse in this cycle
+        let mut last_pulse = None;
+        let mut is_repositioned = false;
+
+        for message in self.input.messages(cycle) {
+            match message.bytes {
+                [0xF8, ..] => {
+                    let usecs = cycle.time_at_frame(message.time);
+
+                    if let Some(last_usecs) = self.last_pulse_usecs {
+                        self.usecs_per_pulse = Self::estimate(self.usecs_per_pulse, (usecs - last_usecs) as f64);
+                    }
+
+                    let frame_tick = cycle.tick_range.start as f64 + cycle.ticks() as f64 * message.time as f64 / cycle.n_frames as f64;
+                    last_pulse = Some((self.clock_tick(), frame_tick));
+
+                    self.last_pulse_usecs = Some(usecs);
+                    self.pulses += 1;
+                },
+                [0xFA, ..] => {
+                    self.position = 0;
+                    self.pulses = 0;
+                    is_repositioned = true;
+                    events.push(ClockEvent::Start);
+                },
+                [0xFB, ..] => events.push(ClockEvent::Continue),
+                [0xFC, ..] => {
+                    self.last_pulse_usecs = None;
+                    events.push(ClockEvent::Stop);
+                },
+                [0xF2, lsb, msb, ..] => {
+                    self.position = (*lsb as u32 | (*msb as u32) << 7) * Clock::TICKS_PER_MIDI_BEAT;
+                    self.pulses = 0;
+                    is_repositioned = true;
+                    events.push(ClockEvent::SongPosition(self.position));
+                },
+                _ => (),
+            }
+        }
+
+        if let (Some(usecs_per_pulse), Some((clock_tick, frame_tick))) = (self.usecs_per_pulse, last_pulse) {
+            let mut beats_per_minute = 60000000.0 / (usecs_per_pulse * 24.0);
+
+            // Speed up or slow down a little when our transport is not where the clock is
+            if cycle.is_rolling && ! is_repositioned {
+                let drift = (clock_tick as f64 - frame_tick) / TimebaseHandler::TICKS_PER_BEAT;
+                let correction = (drift * Self::DRIFT_CORRECTION).max(-Self::MAX_DRIFT_CORRECTION).min(Self::MAX_DRIFT_CORRECTION);
+                beats_per_minute *= 1.0 + correction;
+            }
+
+            events.push(ClockEvent::BeatsPerMinute(beats_per_minute));
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn song_position() {
+        assert_eq!(Clock::song_position(0), Message::SongPosition([0xF2, 0, 0]));
+        // 16 bars of 16 16th notes, rounded down
+        assert_eq!(Clock::song_position(1920 * 4 * 16 + 100), Message::SongPosition([0xF2, 0, 2]));
+    }
+
+    #[test]
+    fn pulses() {
+        let pulses: Vec<u32> = Clock::pulses(&TickRange::new(0, 200)).collect();
+        assert_eq!(pulses, vec![0, 80, 160]);
+
+        let pulses: Vec<u32> = Clock::pulses(&TickRange::new(81, 160)).collect();
+        assert!(pulses.is_empty());
+    }
+
+    #[test]
+    fn estimate() {
+        assert_eq!(ClockInput::estimate(None, 20000.0), Some(20000.0));
+        assert_eq!(ClockInput::estimate(Some(20000.0), 21000.0), Some(20100.0));
+        // Clock paused, start over
+        assert_eq!(ClockInput::estimate(Some(20000.0), 100000.0), None);
+    }
+}
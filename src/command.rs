@@ -0,0 +1,260 @@
+
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use super::loopable::*;
+use super::events::*;
+use super::smf::MidiFile;
+use super::quantize::Quantizer;
+use super::groove::Groove;
+use super::channel::{Channel, Routing};
+use super::sequencer::Sequencer;
+use super::action::Action;
+
+/*
+ * Import track of midi file into patterns of a channel
+ */
+#[derive(Debug)]
+pub struct Import {
+    path: PathBuf,
+    channel: usize,
+    pattern: u8,
+    // First track with notes when no track is given
+    track: Option<usize>,
+    // Split track in patterns of this amount of bars
+    split_bars: Option<u32>,
+    // Create phrase that plays imported patterns after each other
+    phrase: Option<u8>,
+}
+
+impl Import {
+    fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let path = PathBuf::from(words.next()?);
+        let channel = words.next()?.parse().ok().filter(|channel| *channel < 16)?;
+        let pattern = words.next()?.parse().ok().filter(|pattern| (*pattern as usize) < Channel::LOOPABLES)?;
+        let mut import = Import { path, channel, pattern, track: None, split_bars: None, phrase: None };
+
+        while let Some(option) = words.next() {
+            let value = words.next()?;
+
+            match option {
+                "track" => import.track = Some(value.parse().ok()?),
+                "split" => import.split_bars = Some(value.parse().ok()
+                    .filter(|bars: &u32| *bars > 0 && bars.checked_mul(Pattern::minimum_length()).is_some())?),
+                "phrase" => import.phrase = Some(value.parse().ok().filter(|phrase| (*phrase as usize) < Channel::LOOPABLES)?),
+                _ => return None,
+            }
+        }
+
+        Some(import)
+    }
+
+    // Read midi file, done on console thread so we don't do file I/O in process thread
+    fn load(&self) -> io::Result<Action> {
+        let file = MidiFile::load(&self.path)?;
+        let track = self.track.or_else(|| file.first_note_track())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "midi file does not contain notes"))?;
+
+        let patterns = file.patterns(track, self.split_bars.map(|bars| bars * Pattern::minimum_length()))?;
+
+        // Process thread puts patterns in channel as is, they should all fit
+        if self.pattern as usize + patterns.len() > Channel::LOOPABLES {
+            let message = format!("{} patterns do not fit in channel starting at pattern {}", patterns.len(), self.pattern);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        let phrase = self.phrase.map(|phrase_index| {
+            let pattern_length = patterns[0].length();
+            let mut phrase = Phrase::new();
+            phrase.set_length(pattern_length * patterns.len() as u32);
+
+            for index in 0 .. patterns.len() as u32 {
+                let mut event = LoopablePatternEvent::new(index * pattern_length, self.pattern + index as u8);
+                event.stop = Some((index + 1) * pattern_length);
+                phrase.pattern_events.push(event);
+            }
+
+            (phrase_index, phrase)
+        });
+
+        Ok(Action::LoadPatterns { channel: self.channel, pattern: self.pattern, patterns, phrase })
+    }
+}
+
+/*
+ * Console reads commands from stdin and passes them to the process handler
+ */
+pub struct Console {
+    action_send: Sender<Action>,
+}
+
+impl Console {
+    pub fn new(action_send: Sender<Action>) -> Self {
+        Console { action_send }
+    }
+
+    // Ask a yes / no question before we start reading commands
+    pub fn confirm(question: &str) -> bool {
+        println!("{} [y/N]", question);
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+    }
+
+    // Start reading stdin, this function halts until stdin is closed
+    pub fn start(&mut self) {
+        let stdin = io::stdin();
+
+        for line in stdin.lock().lines() {
+            let line = match line { Ok(line) => line, Err(_) => break };
+
+            if ! self.handle(&line) {
+                break;
+            }
+        }
+    }
+
+    // Pass command on line to process thread, false when process thread is gone
+    pub fn handle(&self, line: &str) -> bool {
+        if line.trim().is_empty() {
+            return true;
+        }
+
+        // Midi files are read here, process thread only receives the resulting patterns
+        let mut words = line.split_whitespace();
+        let action = match words.next() {
+            Some("import") => match Import::parse(words) {
+                Some(import) => match import.load() {
+                    Ok(action) => Some(action),
+                    Err(e) => { println!("Error: could not import {:?}: {}", import.path, e); return true },
+                },
+                None => None,
+            },
+            _ => Self::parse(line),
+        };
+
+        match action {
+            Some(action) => self.action_send.send(action).is_ok(),
+            None => { println!("Unknown command: {}", line.trim()); true },
+        }
+    }
+
+    // Console commands, except for import which is read by the console itself
+    fn parse(line: &str) -> Option<Action> {
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            Some("save") => Some(Action::Save(words.next().map(PathBuf::from))),
+            Some("export") => words.next().map(|path| Action::Export(PathBuf::from(path))),
+            Some("tempo") => Self::parse_tempo(words),
+            Some("meter") => Self::parse_meter(words),
+            Some("crossfade") => Self::parse_crossfade(words),
+            Some("quantize") => Self::parse_quantize(words),
+            Some("groove") => Self::parse_groove(words),
+            Some("output") => Self::parse_output(words),
+            Some("instrument") => Self::parse_instrument(words),
+            _ => None,
+        }
+    }
+
+    // Bars are counted from 1 in commands, like the transport shows them
+    fn parse_bar(word: Option<&str>) -> Option<u32> {
+        word?.parse::<u32>().ok()?.checked_sub(1)
+    }
+
+    fn parse_tempo<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let bar = Self::parse_bar(words.next())?;
+        let beats_per_minute = match words.next()? {
+            "-" => None,
+            value => Some(value.parse().ok().filter(|beats_per_minute: &f64| *beats_per_minute > 0.0)?),
+        };
+        let is_ramp = match words.next() {
+            Some("ramp") => true,
+            None => false,
+            _ => return None,
+        };
+
+        Some(Action::Tempo { bar, beats_per_minute, is_ramp })
+    }
+
+    fn parse_meter<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let bar = Self::parse_bar(words.next())?;
+        let meter = match words.next()? {
+            "-" => None,
+            value => {
+                let mut values = value.split('/');
+                let beats_per_bar = values.next()?.parse().ok().filter(|beats: &u8| *beats > 0)?;
+                let beat_type = values.next()?.parse().ok().filter(|beat_type: &u8| beat_type.is_power_of_two())?;
+                Some((beats_per_bar, beat_type))
+            },
+        };
+
+        Some(Action::Meter { bar, meter })
+    }
+
+    fn parse_crossfade<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let channel = words.next()?.parse().ok().filter(|channel| *channel < 16)?;
+        let group = match words.next()? {
+            "-" => None,
+            value => Some(value.parse().ok()?),
+        };
+
+        Some(Action::Crossfade { channel, group })
+    }
+
+    // Division is 4, 8, 16 or 32 followed by t for triplets, strength is in percent
+    fn parse_quantize<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let grid = words.next()?;
+        if grid == "-" {
+            return Some(Action::Quantize(None));
+        }
+
+        let is_triplet = grid.ends_with('t');
+        let division = grid.trim_end_matches('t').parse().ok().filter(|division| Quantizer::DIVISIONS.contains(division))?;
+        let strength = match words.next() {
+            Some(strength) => strength.parse().ok().filter(|strength| *strength <= 100)?,
+            None => 100,
+        };
+
+        Some(Action::Quantize(Some(Quantizer::new(division, is_triplet, strength))))
+    }
+
+    fn parse_groove<'a>(words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let mut words = words.peekable();
+        let channel = words.next()?.parse().ok().filter(|channel| *channel < 16)?;
+        let pattern = match words.peek() {
+            Some(&"pattern") => { words.next(); Some(words.next()?.parse().ok()?) },
+            _ => None,
+        };
+        let groove = match *words.peek()? {
+            "-" => None,
+            "swing" => { words.next(); Some(Groove::swing(words.next()?.parse().ok()?)) },
+            _ => Some(Groove::parse(words)?),
+        };
+
+        Some(Action::Groove { channel, pattern, groove })
+    }
+
+    fn parse_instrument<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let channel = words.next()?.parse().ok().filter(|channel| *channel < 16)?;
+        let name = match words.next()? {
+            "-" => None,
+            name => Some(String::from(name)),
+        };
+
+        Some(Action::Instrument { channel, name })
+    }
+
+    // Transposition is in semitones & optional
+    fn parse_output<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Action> {
+        let channel = words.next()?.parse().ok().filter(|channel| *channel < 16)?;
+        let port = words.next()?.parse().ok().filter(|port| *port < Sequencer::OUTPUTS)?;
+        let midi_channel = words.next()?.parse().ok().filter(|midi_channel| *midi_channel < 16)?;
+        let transpose = match words.next() {
+            Some(transpose) => transpose.parse().ok()?,
+            None => 0,
+        };
+
+        Some(Action::Output { channel, routing: Routing::new(port, midi_channel, transpose) })
+    }
+}
@@ -0,0 +1,195 @@
+
+use super::Controller;
+use super::input::*;
+use super::super::cycle::ProcessCycle;
+use super::super::message::Message;
+use super::super::sequencer::Sequencer;
+use super::super::surface::Surface;
+
+/*
+ * Virtual controller living in our process instead of on the other side of a midi cable. Events
+ * are injected instead of played on the controller, leds the controller would light up are kept
+ * around so we can look at them. This way controllers can be played without hardware or jack
+ */
+pub struct Emulator<C: Controller> {
+    pub controller: C,
+    // Events injected since last cycle, these arrive at the start of next cycle
+    events: Vec<InputEvent>,
+    // Led values by midi channel & note, as the controller would show them
+    leds: [[u8; 128]; 16],
+}
+
+impl<C: Controller> Emulator<C> {
+    pub fn new() -> Self {
+        Emulator { controller: C::new(None), events: vec![], leds: [[0; 128]; 16] }
+    }
+
+    pub fn inject(&mut self, event_type: InputEventType) {
+        self.events.push(InputEvent { time: 0, event_type });
+    }
+
+    // Answer inquiry like APC with device id would
+    pub fn identify(&mut self, device_id: u8) { self.inject(InputEventType::InquiryResponse(0, device_id)); }
+
+    pub fn press(&mut self, button_type: ButtonType) { self.inject(InputEventType::ButtonPressed(button_type)); }
+    pub fn release(&mut self, button_type: ButtonType) { self.inject(InputEventType::ButtonReleased(button_type)); }
+
+    pub fn click(&mut self, button_type: ButtonType) {
+        self.press(button_type);
+        self.release(button_type);
+    }
+
+    pub fn turn_knob(&mut self, knob_type: KnobType, value: u8) { self.inject(InputEventType::KnobTurned { value, knob_type }); }
+    pub fn move_fader(&mut self, fader_type: FaderType, value: u8) { self.inject(InputEventType::FaderMoved { value, fader_type }); }
+
+    /*
+     * Controller part of a process cycle, process injected events & output to the leds. Like the
+     * process handler does, sequencer output should happen in between
+     */
+    pub fn process_input(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
+        let events = self.events.drain(0..).collect();
+        self.controller.process_input_events(events, cycle, sequencer, surface);
+    }
+
+    pub fn output(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
+        self.controller.output_midi(cycle, sequencer, surface);
+
+        for message in self.controller.output().take_rendered() {
+            if let Message::Note([status, note, velocity]) = message.message {
+                let channel = (status & 0x0F) as usize;
+                // Note off turns led off, whatever it's velocity
+                self.leds[channel][note as usize] = if status & 0xF0 == 0x90 { velocity } else { 0 };
+            }
+        }
+    }
+
+    pub fn process(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
+        self.process_input(cycle, sequencer, surface);
+        self.output(cycle, sequencer, surface);
+    }
+
+    pub fn led(&self, channel: u8, note: u8) -> u8 {
+        self.leds[channel as usize][note as usize]
+    }
+
+    // Grid leds by row & column, rows are numbered from the bottom like grid buttons are
+    pub fn grid(&self) -> [[u8; 8]; 5] {
+        let mut grid = [[0; 8]; 5];
+
+        for (y, row) in grid.iter_mut().enumerate() {
+            for (x, led) in row.iter_mut().enumerate() {
+                *led = self.led(x as u8, 0x35 + 4 - y as u8);
+            }
+        }
+
+        grid
+    }
+
+    pub fn side(&self) -> [u8; 5] {
+        let mut side = [0; 5];
+        side.iter_mut().enumerate().for_each(|(index, led)| *led = self.led(0, 0x52 + 4 - index as u8));
+        side
+    }
+
+    // Leds of row of buttons sharing a note, like indicator (0x34) or channel (0x33) buttons
+    pub fn row(&self, note: u8) -> [u8; 8] {
+        let mut row = [0; 8];
+        row.iter_mut().enumerate().for_each(|(index, led)| *led = self.led(index as u8, note));
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use super::super::super::hardware::APC40;
+    use super::super::super::options::SyncSource;
+    use super::super::super::surface::View;
+    use super::super::super::transport::SyntheticTransport;
+    use super::super::super::loopable::Loopable;
+
+    struct Setup {
+        transport: SyntheticTransport,
+        sequencer: Sequencer,
+        surface: Surface,
+        apc40: Emulator<APC40>,
+    }
+
+    impl Setup {
+        // APC40 that identified itself & drew it's first state
+        fn new() -> Self {
+            let (sender, _receiver) = mpsc::channel();
+            let mut setup = Setup {
+                transport: SyntheticTransport::new(48000, 1024),
+                sequencer: Sequencer::new(None, sender, SyncSource::Internal),
+                surface: Surface::new(),
+                apc40: Emulator::new(),
+            };
+
+            setup.apc40.identify(0x73);
+            (0 .. 4).for_each(|_| setup.cycle());
+            setup
+        }
+
+        fn cycle(&mut self) {
+            let cycle = ProcessCycle::synthetic(&self.transport, None, self.sequencer.tempo_map());
+            self.apc40.process(&cycle, &mut self.sequencer, &mut self.surface);
+            self.transport.advance();
+        }
+
+        // Let time pass without anybody touching the controller
+        fn wait(&mut self, usecs: u64) {
+            let until = self.transport.usecs() + usecs;
+            while self.transport.usecs() < until { self.cycle(); }
+        }
+    }
+
+    #[test]
+    fn drag_note_length() {
+        let mut setup = Setup::new();
+        let base_note = setup.surface.pattern_base_note(0);
+        assert_eq!(setup.apc40.grid(), [[0; 8]; 5]);
+
+        // Hold first button of note & press last one
+        setup.apc40.press(ButtonType::Grid(1, 2));
+        setup.apc40.click(ButtonType::Grid(4, 2));
+        setup.apc40.release(ButtonType::Grid(1, 2));
+        setup.cycle();
+
+        let ticks_per_button = setup.apc40.controller.loopable_ticks_per_button(&setup.surface);
+        let notes = setup.sequencer.channel(0).pattern(0).events().clone();
+        assert_eq!(notes.len(), 1);
+        assert_eq!((notes[0].start, notes[0].stop, notes[0].note), (ticks_per_button, Some(ticks_per_button * 5), base_note));
+        assert_eq!(setup.apc40.grid()[2], [0, 1, 5, 5, 5, 0, 0, 0]);
+
+        // Clicking head without modifier removes note
+        setup.apc40.click(ButtonType::Grid(1, 2));
+        setup.cycle();
+        assert!(setup.sequencer.channel(0).pattern(0).events().is_empty());
+        assert_eq!(setup.apc40.grid(), [[0; 8]; 5]);
+    }
+
+    #[test]
+    fn double_click_queues_sequence() {
+        let mut setup = Setup::new();
+        setup.apc40.click(ButtonType::Master);
+        setup.cycle();
+        assert!(matches!(setup.surface.view, View::Sequence));
+
+        // Single clicks show sequence
+        setup.apc40.click(ButtonType::Side(2));
+        setup.cycle();
+        setup.wait(400000);
+        setup.apc40.click(ButtonType::Side(2));
+        setup.cycle();
+        assert_eq!((setup.surface.sequence_shown(), setup.sequencer.sequence_queued), (2, None));
+        assert_eq!(setup.apc40.side()[2], 1);
+
+        // Double click queues it
+        setup.wait(100000);
+        setup.apc40.click(ButtonType::Side(2));
+        setup.cycle();
+        assert_eq!(setup.sequencer.sequence_queued, Some(2));
+    }
+}
@@ -6,6 +6,11 @@ pub struct CueKnob {
     delta: i8,
 }
 
+// Remember where effect knobs are, so we can pick up parameters instead of making them jump
+pub struct KnobTakeover {
+    values: [Option<u8>; 16],
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ButtonType {
     Grid(u8, u8),
@@ -19,6 +24,10 @@ pub enum ButtonType {
     Quantization,
     Play,
     Stop,
+    Record,
+    TapTempo,
+    NudgeDown,
+    NudgeUp,
     Up,
     Down,
     Right,
@@ -55,77 +64,7 @@ pub struct InputEvent {
     pub event_type: InputEventType,
 }
 
-impl ButtonType {
-    fn new(channel: u8, note: u8) -> Self {
-        match note {
-            0x5B => ButtonType::Play,
-            0x5C => ButtonType::Stop,
-            0x33 => ButtonType::Channel(channel),
-            0x3F => ButtonType::Quantization,
-            // These used to be sequence buttons, but will now be more control groups for plugin parameters
-            //0x57 ..= 0x5A => ButtonType::Sequence(note - 0x57),
-            // Side grid is turned upside down as we draw the phrases upside down as we draw notes
-            // updside down due to lower midi nodes having lower numbers, therefore the 4 -
-            0x52 ..= 0x56 => ButtonType::Side(4 - (note - 0x52)),
-            0x51 => ButtonType::Shift,
-            0x50 => ButtonType::Master,
-            // Grid should add notes & add phrases
-            0x35 ..= 0x39 => ButtonType::Grid(channel, 4 - (note - 0x35)),
-            0x5E => ButtonType::Up,
-            0x5F => ButtonType::Down,
-            0x60 => ButtonType::Right,
-            0x61 => ButtonType::Left,
-            0x62 => ButtonType::Shift,
-            0x30 => ButtonType::Arm(channel),
-            0x31 => ButtonType::Solo(channel),
-            0x32 => ButtonType::Activator(channel),
-            _ => ButtonType::Unknown,
-        }
-    }
-}
-
-/*
- * Get input event type from sent bytes
- */
-impl InputEventType {
-    pub fn new(bytes: &[u8]) -> Self {
-         match bytes[0] {
-            0xF0 => {
-                // 0x06 = inquiry e, 0x02 = inquiry response 0x47 = akai manufacturer, 0x73 = APC40, 0x7b = APC20
-                if bytes[3] == 0x06 && bytes[4] == 0x02 && bytes[5] == 0x47 && (bytes[6] == 0x73 || bytes[6] == 0x7b) {
-                    Self::InquiryResponse(bytes[13], bytes[6])
-                } else {
-                    Self::Unknown
-                }
-            },
-            0x90 ..= 0x9F => Self::ButtonPressed(ButtonType::new(bytes[0] - 0x90, bytes[1])),
-            0x80 ..= 0x8F => Self::ButtonReleased(ButtonType::new(bytes[0] - 0x80, bytes[1])),
-            0xB0 ..= 0xB8 => {
-                match bytes[1] {
-                    0x30 ..= 0x37 | 0x10 ..= 0x17 => {
-                        // APC effect knobs are ordered weird, reorder them from to 0..16
-                        let modifier = if (0x30 ..= 0x37).contains(&bytes[1]) { 48 } else { 8 };
-                        let index = bytes[1] - modifier;
-
-                        Self::KnobTurned { value: bytes[2], knob_type: KnobType::Control(index) }
-                    },
-                    0x7 => Self::FaderMoved { value: bytes[2], fader_type: FaderType::Channel(bytes[0] - 0xB0) },
-                    0xE => Self::FaderMoved { value: bytes[2], fader_type: FaderType::Master },
-                    0xF => Self::FaderMoved { value: bytes[2], fader_type: FaderType::CrossFade },
-                    0x2F => Self::KnobTurned { value: bytes[2], knob_type: KnobType::Cue },
-                    _ => Self::Unknown,
-                }
-            },
-            _ => Self::Unknown,
-        }
-    }
-}
-
 impl InputEvent {
-    pub fn new(time: u32, bytes: &[u8]) -> Self {
-        Self { time, event_type: InputEventType::new(bytes) }
-    }
-
     pub fn is_cue_knob(event_type: &InputEventType) -> bool { 
         matches!(event_type, InputEventType::KnobTurned { knob_type: KnobType::Cue, .. }) 
     }
@@ -167,10 +106,14 @@ impl CueKnob {
 
     pub fn new() -> Self { CueKnob { delta: 0 } }
 
+    // Transform 0->up / 128->down to -delta / +delta
+    pub fn delta(value: u8) -> i8 {
+        (value as i8).rotate_left(1) / 2
+    }
+
     // TODO - Use time for this aswell, so that turning knob instantly moves grid
     pub fn process_turn(&mut self, value: u8, is_first_turn: bool) -> i8 {
-        // Transform 0->up / 128->down to -delta / +delta
-        let delta = (value as i8).rotate_left(1) / 2;
+        let delta = Self::delta(value);
 
         // Reset on first turn and return 1 step
         if is_first_turn {
@@ -192,3 +135,44 @@ impl CueKnob {
     }
 }
 
+/*
+ * Soft takeover, knob only controls parameter when it was at the parameter value or crosses it
+ */
+impl KnobTakeover {
+    pub fn new() -> Self { KnobTakeover { values: [None; 16] } }
+
+    // Knob was moved to value by us, for example by setting its led ring
+    pub fn set_value(&mut self, index: u8, value: u8) {
+        self.values[index as usize] = Some(value);
+    }
+
+    pub fn takes_over(&mut self, index: u8, value: u8, parameter_value: u8) -> bool {
+        match self.values[index as usize].replace(value) {
+            // Nothing to jump from when we don't know where knob was
+            None => true,
+            Some(previous) => (previous.min(value) ..= previous.max(value)).contains(&parameter_value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knob_takeover() {
+        let mut takeover = KnobTakeover::new();
+        assert!(takeover.takes_over(0, 10, 64));
+
+        // Knob is at 10, parameter at 64
+        takeover.set_value(0, 10);
+        assert!(! takeover.takes_over(0, 20, 64));
+        assert!(takeover.takes_over(0, 70, 64));
+        assert!(takeover.takes_over(0, 71, 70));
+
+        // Crossing from above picks up parameter aswell
+        takeover.set_value(1, 100);
+        assert!(! takeover.takes_over(1, 90, 30));
+        assert!(takeover.takes_over(1, 20, 30));
+    }
+}
@@ -1,17 +1,14 @@
 
 use std::mem;
-use super::super::message::*;
+use super::mapping::Led;
 
 // TODO - We could probably macro these grids, but.. alas, i'm not familiar enough with macros
 
+/*
+ * Leds that changed since last output, controllers turn them into midi with their mapping
+ */
 pub trait Drawable {
-    fn output_messages(&mut self, frame: u32) -> Vec<TimedMessage> {
-        self.output().into_iter()
-            .map(|(channel, note, velocity)| TimedMessage::new(frame, Message::Note([channel, note, velocity])))
-            .collect()
-    }
-
-    fn output(&mut self) -> Vec<(u8, u8, u8)>;
+    fn output(&mut self) -> Vec<(Led, u8)>;
 
     fn reset(&mut self);
 }
@@ -107,24 +104,26 @@ impl IlluminatedButtonGrid {
     }
 }
 
-// 40 boi at the top
+// Buttons above the side column, sized by the mapping of the controller
 pub struct Grid {
-    state: [u8; 40],
-    next_state: [u8; 40],
+    width: u8,
+    height: u8,
+    state: Vec<u8>,
+    next_state: Vec<u8>,
 }
 
-// 5 high grid
+// Column of buttons next to the grid
 pub struct Side {
-    state: [u8; 5],
-    next_state: [u8; 5],
+    state: Vec<u8>,
+    next_state: Vec<u8>,
 }
 
-// 8 wide grid
+// Row of buttons under the grid, one for every column
 pub struct WideRow {
-    state: [u8; 8],
-    next_state: [u8; 8],
+    state: Vec<u8>,
+    next_state: Vec<u8>,
 
-    note: u8,
+    led: fn(u8) -> Led,
 }
 
 // 4 wide grid
@@ -137,24 +136,31 @@ pub struct NarrowRow {
 }
 */
 
+// LED rings around the 16 effect knobs, values are 0 - 127
+pub struct KnobRings {
+    state: [u8; 16],
+    next_state: [u8; 16],
+}
+
 pub struct Single {
     state: u8,
     next_state: u8,
     
-    note: u8,
+    led: Led,
 }
 
 impl Grid {
-    pub fn new() -> Self {
+    pub fn new(width: u8, height: u8) -> Self {
+        let length = width as usize * height as usize;
         // 9 does not exist, this way we force redraw of *all* leds first run
-        Grid { state: [9; 40], next_state: [0; 40] }
+        Grid { width, height, state: vec![9; length], next_state: vec![0; length] }
     }
 
-    pub fn width(&self) -> u8 { 8 }
-    pub fn height(&self) -> u8 { 5 }
+    pub fn width(&self) -> u8 { self.width }
+    pub fn height(&self) -> u8 { self.height }
 
-    fn index(x: u8, y: u8) -> usize {
-        y as usize * 8 + x as usize
+    fn index(&self, x: u8, y: u8) -> usize {
+        y as usize * self.width as usize + x as usize
     }
 
     pub fn try_draw(&mut self, x: i32, y: u8, value: u8) {
@@ -165,89 +171,81 @@ impl Grid {
 
     pub fn draw(&mut self, x: u8, y: u8, value: u8) {
         if x < self.width() && y < self.height() {
-            // 4 - as grid & side are flipped upside down to make MIDI notes go up..
-            self.next_state[Self::index(x, 4 - y)] = value;
+            let index = self.index(x, y);
+            self.next_state[index] = value;
         }
     }
 }
 
 impl Drawable for Grid {
     fn reset(&mut self) {
-        self.state = [9; 40];
+        self.state.iter_mut().for_each(|state| *state = 9);
     }
 
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
+    fn output(&mut self) -> Vec<(Led, u8)> {
         let mut output = vec![];
 
-        // Meeeeeh, rust array comparison works up to 32 elements...
-        // https://doc.rust-lang.org/std/primitive.array.html#impl-PartialEq%3C%5BB%3B%20N%5D%3E
-        if self.next_state[0..20] != self.state[0..20] || self.next_state[20..] != self.state[20..]  {
+        if self.next_state != self.state {
             for x in 0 .. self.width() {
                 for y in 0 .. self.height() {
-                    let index = Self::index(x, y);
+                    let index = self.index(x, y);
 
                     if self.next_state[index] != self.state[index] {
-                        let channel = x as u8 + if self.next_state[index] > 0 { 0x90 } else { 0x80 };
-                        let note = 0x35 + y as u8;
-
-                        output.push((channel, note, self.next_state[index]));
+                        output.push((Led::Grid(x, y), self.next_state[index]));
                     }
                 }
             }
         }
 
-        self.state = self.next_state;
-        self.next_state = [0; 40];
+        // Swap buffers instead of allocating new ones, this runs every cycle
+        mem::swap(&mut self.state, &mut self.next_state);
+        self.next_state.iter_mut().for_each(|state| *state = 0);
         output
     }
 }
 
 impl Side {
-    pub fn new() -> Self {
-        Side { state: [9; 5], next_state: [0; 5] }
+    pub fn new(height: u8) -> Self {
+        Side { state: vec![9; height as usize], next_state: vec![0; height as usize] }
     }
 
-    pub fn height(&self) -> u8 { 5 }
+    pub fn height(&self) -> u8 { self.state.len() as u8 }
 
     pub fn draw(&mut self, index: u8, value: u8) {
         if index < self.height() {
-            // 4 - as grid & side are flipped upside down to make MIDI notes go up..
-            self.next_state[4 - index as usize] = value;
+            self.next_state[index as usize] = value;
         }
     }
 }
 
 impl Drawable for Side {
     fn reset(&mut self) {
-        self.state = [9; 5];
+        self.state.iter_mut().for_each(|state| *state = 9);
     }
 
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
+    fn output(&mut self) -> Vec<(Led, u8)> {
         let mut output = vec![];
 
         if self.next_state != self.state {
-            for index in 0 .. self.height() as usize {
-                if self.next_state[index] != self.state[index] {
-                    let channel = if self.next_state[index] == 1 { 0x90 } else { 0x80 };
-                    let note = 0x52 + index as u8;
-
-                    output.push((channel, note, self.next_state[index]));
+            for index in 0 .. self.height() {
+                if self.next_state[index as usize] != self.state[index as usize] {
+                    output.push((Led::Side(index), self.next_state[index as usize]));
                 }
             }
         }
 
-        self.state = self.next_state;
-        self.next_state = [0; 5];
+        mem::swap(&mut self.state, &mut self.next_state);
+        self.next_state.iter_mut().for_each(|state| *state = 0);
         output
     }
 }
 
 impl WideRow {
-    pub fn new(note: u8) -> Self {
-        WideRow { state: [9; 8], next_state: [0; 8], note, }
+    pub fn new(width: u8, led: fn(u8) -> Led) -> Self {
+        WideRow { state: vec![9; width as usize], next_state: vec![0; width as usize], led, }
     }
 
-    pub fn width(&self) -> u8 { 8 }
+    pub fn width(&self) -> u8 { self.state.len() as u8 }
 
     pub fn draw(&mut self, index: u8, value: u8) {
         if index < self.width() {
@@ -258,24 +256,22 @@ impl WideRow {
 
 impl Drawable for WideRow {
     fn reset(&mut self) {
-        self.state = [9; 8];
+        self.state.iter_mut().for_each(|state| *state = 9);
     }
 
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
+    fn output(&mut self) -> Vec<(Led, u8)> {
         let mut output = vec![];
 
         if self.next_state != self.state {
-            for index in 0 .. self.width() as usize {
-                if self.next_state[index] != self.state[index] {
-                    let channel = if self.next_state[index] == 1 { 0x90 } else { 0x80 };
-
-                    output.push((channel + index as u8, self.note, self.next_state[index]));
+            for index in 0 .. self.width() {
+                if self.next_state[index as usize] != self.state[index as usize] {
+                    output.push(((self.led)(index), self.next_state[index as usize]));
                 }
             }
         }
 
-        self.state = self.next_state;
-        self.next_state = [0; 8];
+        mem::swap(&mut self.state, &mut self.next_state);
+        self.next_state.iter_mut().for_each(|state| *state = 0);
         output
     }
 }
@@ -297,8 +293,8 @@ impl NarrowRow {
 */
 
 impl Single {
-    pub fn new(note: u8) -> Self {
-        Single { state: 9, next_state: 0, note, }
+    pub fn new(led: Led) -> Self {
+        Single { state: 9, next_state: 0, led, }
     }
 
     pub fn draw(&mut self, value: u8) {
@@ -311,12 +307,11 @@ impl Drawable for Single {
         self.state = 9;
     }
 
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
+    fn output(&mut self) -> Vec<(Led, u8)> {
         let mut output = vec![];
 
         if self.next_state != self.state {
-            let channel = if self.next_state == 1 { 0x90 } else { 0x80 };
-            output.push((channel, self.note, self.next_state));
+            output.push((self.led, self.next_state));
         }
 
         self.state = self.next_state;
@@ -324,3 +319,43 @@ impl Drawable for Single {
         output
     }
 }
+
+impl KnobRings {
+    pub fn new() -> Self {
+        // 128 is no knob value, force setting all rings first run
+        KnobRings { state: [128; 16], next_state: [0; 16] }
+    }
+
+    pub fn width(&self) -> u8 { 16 }
+
+    // Value ring is showing, or will be showing after next output
+    pub fn value(&self, index: u8) -> u8 { self.state[index as usize] }
+
+    pub fn draw(&mut self, index: u8, value: u8) {
+        if index < self.width() {
+            self.next_state[index as usize] = value;
+        }
+    }
+}
+
+impl Drawable for KnobRings {
+    fn reset(&mut self) {
+        self.state = [128; 16];
+    }
+
+    fn output(&mut self) -> Vec<(Led, u8)> {
+        let mut output = vec![];
+
+        if self.next_state != self.state {
+            for index in 0 .. self.width() {
+                if self.next_state[index as usize] != self.state[index as usize] {
+                    output.push((Led::KnobRing(index), self.next_state[index as usize]));
+                }
+            }
+        }
+
+        self.state = self.next_state;
+        self.next_state = [0; 16];
+        output
+    }
+}
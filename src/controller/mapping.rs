@@ -0,0 +1,41 @@
+
+use super::super::message::Message;
+use super::input::InputEventType;
+
+// Lights a controller can show, numbered like the buttons they belong to
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Led {
+    Grid(u8, u8),
+    Side(u8),
+    Indicator(u8),
+    Channel(u8),
+    Activator(u8),
+    Solo(u8),
+    Arm(u8),
+    Master,
+    // Ring around effect knob, values are 0 - 127
+    KnobRing(u8),
+}
+
+/*
+ * How a controller speaks midi. The controller trait only knows about buttons & leds, mappings
+ * translate those from & to the notes, controllers & sysex of a specific piece of hardware
+ */
+pub trait Mapping {
+    // Controllers next to each other show consecutive channels, this is the first one we show
+    const CHANNEL_OFFSET: u8;
+    // Buttons of the grid, the rows under it are as wide as the grid
+    const GRID_WIDTH: u8;
+    const GRID_HEIGHT: u8;
+    // Buttons in the column next to the grid
+    const SIDE_BUTTONS: u8;
+
+    // Ask controller to identify itself, controllers that don't answer are used right away
+    fn inquiry() -> Option<Message>;
+    // Put controller in the mode we want after it answered the inquiry
+    fn introduction(local_id: u8, device_id: u8) -> Option<Message>;
+
+    fn input_event_type(bytes: &[u8]) -> InputEventType;
+    // Leds hardware does not have don't result in a message
+    fn led_message(led: Led, value: u8) -> Option<Message>;
+}
@@ -1,19 +1,22 @@
 
 pub mod input;
 pub mod lights;
+pub mod mapping;
+pub mod emulator;
 
 use std::ops::Range;
 use super::TickRange;
-use super::message::{TimedMessage, Message};
+use super::message::TimedMessage;
 use super::cycle::ProcessCycle;
 use super::loopable::*;
 use super::sequencer::*;
 use super::surface::*;
-use super::port::MidiOut;
+use super::port::{MidiOut, MidiIn};
 use super::TimebaseHandler;
 use super::events::*;
 use input::*;
 use lights::*;
+use mapping::*;
 
 
 const SEQUENCE_COLOR: u8 = 1;
@@ -24,14 +27,21 @@ const TIMELINE_TAIL_COLOR: u8 = 3;
 const IDENTIFY_CYCLES: u8 = 3;
 const LENGTH_INDICATOR_USECS: u64 = 200000;
 const DOUBLE_CLICK_USECS: u64 = 300000;
+// Beats per minute per cue knob step when fine tuning tempo
+const FINE_TEMPO_STEP: f64 = 0.1;
 const PLAYING_LOOPABLE_INDICATOR_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32;
 const PLAYING_SEQUENCE_INDICATOR_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32;
 const QUEUED_SEQUENCE_INDICATOR_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32 / 2;
 
-pub trait APC {
+/*
+ * Sequencing UI of a controller with a grid, side buttons & rows of buttons under the grid. What
+ * buttons & leds are called in midi & how many there are is up to the mapping, so hardware only
+ * has to say which of the lights it has & what it does with the buttons only it has
+ */
+pub trait Controller {
     type Loopable: Loopable;
+    type Mapping: Mapping;
 
-    const CHANNEL_OFFSET: u8;
     const HEAD_COLOR: u8;
     const TAIL_COLOR: u8;
 
@@ -55,23 +65,33 @@ pub trait APC {
     fn playing_loopable_indexes(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<u8>;
     fn playing_loopable_ranges(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<(TickRange, u32)>;
 
-    fn cue_knob(&mut self) -> &mut CueKnob;
     fn master(&mut self) -> &mut Single;
     fn grid(&mut self) -> &mut Grid;
-    fn side(&mut self) -> &mut Side;
-    fn channel(&mut self) -> &mut WideRow;
-    fn indicator(&mut self) -> &mut WideRow;
-    fn activator(&mut self) -> &mut WideRow;
-    fn solo(&mut self) -> &mut WideRow;
+    // Every controller has a grid, what else it has is up to the hardware
+    fn cue_knob(&mut self) -> Option<&mut CueKnob> { None }
+    fn side(&mut self) -> Option<&mut Side> { None }
+    fn channel(&mut self) -> Option<&mut WideRow> { None }
+    fn indicator(&mut self) -> Option<&mut WideRow> { None }
+    fn activator(&mut self) -> Option<&mut WideRow> { None }
+    fn solo(&mut self) -> Option<&mut WideRow> { None }
+    fn arm(&mut self) -> Option<&mut WideRow> { None }
+    fn knob_rings(&mut self) -> Option<&mut KnobRings> { None }
 
     fn reset_grids(&mut self) {
         self.master().reset();
         self.grid().reset();
-        self.side().reset();
-        self.channel().reset();
-        self.indicator().reset();
-        self.activator().reset();
-        self.solo().reset();
+
+        if let Some(side) = self.side() {
+            side.reset();
+        }
+        for row in [Self::channel, Self::indicator, Self::activator, Self::solo, Self::arm].iter() {
+            if let Some(row) = row(self) {
+                row.reset();
+            }
+        }
+        if let Some(knob_rings) = self.knob_rings() {
+            knob_rings.reset();
+        }
     }
 
     /*
@@ -113,41 +133,47 @@ pub trait APC {
                 let state = 1 - (cycle.tick_range.start / PLAYING_LOOPABLE_INDICATOR_TICKS) % 2;
 
                 // Draw blinking playing loopables
-                for index in playing_indexes.into_iter() {
-                    self.side().draw(index, state as u8);
+                for button in playing_indexes.into_iter().filter_map(|index| surface.bank_button(index)) {
+                    if let Some(side) = self.side() { side.draw(button, state as u8); }
                 }
 
                 // Always show selected loopable
-                self.side().draw(showed_index, 1);
+                if let Some(button) = surface.bank_button(showed_index) {
+                    if let Some(side) = self.side() { side.draw(button, 1); }
+                }
 
                 // Switch on correct frame
                 if cycle.tick_range.stop % PLAYING_LOOPABLE_INDICATOR_TICKS < cycle.tick_range.length() {
-                    frame = (((cycle.tick_range.stop % PLAYING_LOOPABLE_INDICATOR_TICKS) as f64 / cycle.tick_range.length() as f64) * cycle.scope.n_frames() as f64) as u32;
+                    frame = (((cycle.tick_range.stop % PLAYING_LOOPABLE_INDICATOR_TICKS) as f64 / cycle.tick_range.length() as f64) * cycle.n_frames as f64) as u32;
                 }
             },
             View::Sequence => {
                 // Draw blinking playing sequences
                 let playing_state = 1 - (cycle.tick_range.start / PLAYING_SEQUENCE_INDICATOR_TICKS) % 2;
-                self.side().draw(sequencer.sequence_playing as u8, playing_state as u8);
+                if let Some(button) = surface.bank_button(sequencer.sequence_playing as u8) {
+                    if let Some(side) = self.side() { side.draw(button, playing_state as u8); }
+                }
 
                 // Playable selector
-                self.side().draw(surface.sequence_shown() as u8, 1);
+                if let Some(button) = surface.bank_button(surface.sequence_shown() as u8) {
+                    if let Some(side) = self.side() { side.draw(button, 1); }
+                }
 
                 // If theres something queued, make sure that blinks like crazy
-                if let Some(index) = sequencer.sequence_queued {
+                if let Some(button) = sequencer.sequence_queued.and_then(|index| surface.bank_button(index as u8)) {
                     let queued_state = 1 - (cycle.tick_range.start / QUEUED_SEQUENCE_INDICATOR_TICKS) % 2;
-                    self.side().draw(index as u8, queued_state as u8);
+                    if let Some(side) = self.side() { side.draw(button, queued_state as u8); }
                 }
 
                 // Switch on correct frame
                 if cycle.tick_range.stop % PLAYING_SEQUENCE_INDICATOR_TICKS < cycle.tick_range.length() {
-                    frame = (((cycle.tick_range.stop % PLAYING_SEQUENCE_INDICATOR_TICKS) as f64 / cycle.tick_range.length() as f64) * cycle.scope.n_frames() as f64) as u32;
+                    frame = (((cycle.tick_range.stop % PLAYING_SEQUENCE_INDICATOR_TICKS) as f64 / cycle.tick_range.length() as f64) * cycle.n_frames as f64) as u32;
                 }
             },
             _ => (),
         }
 
-        self.side().output_messages(frame)
+        Self::optional_led_messages(frame, self.side())
     }
 
     // TODO - only draw length indicator at position 0 only when we are precisely at 0
@@ -155,10 +181,18 @@ pub trait APC {
         // Default to output immediately
         let mut frame = 0;
         let loopable_length = self.shown_loopable(sequencer, surface).length();
+        let width = match self.indicator() { Some(indicator) => indicator.width(), None => return vec![] };
+
+        // Show bank of side buttons while holding shift
+        if surface.button_memory.is_pressed(ButtonType::Shift) {
+            let bank = surface.bank();
+            if let Some(indicator) = self.indicator() { indicator.draw(bank, 1); }
+            return Self::optional_led_messages(frame, self.indicator());
+        }
 
         match surface.view {
             View::Channel => {
-                let usecs = cycle.time_stop - LENGTH_INDICATOR_USECS;
+                let usecs = cycle.time_stop.saturating_sub(LENGTH_INDICATOR_USECS);
                 let ticks_per_button = self.loopable_ticks_per_button(surface);
                 let offset_buttons = self.shown_loopable_offset(surface) / ticks_per_button;
                 let controller_filters = [
@@ -171,7 +205,7 @@ pub trait APC {
                 let global_filters = [InputEvent::is_crossfader];
                 // Show length/offset indicator when events occurred that changed length/offset
                 let last_occurred_controller_event = surface.event_memory
-                    .last_occurred_controller_event_after(Self::CHANNEL_OFFSET, &controller_filters, usecs)
+                    .last_occurred_controller_event_after(Self::Mapping::CHANNEL_OFFSET, &controller_filters, usecs)
                     .or_else(|| surface.event_memory.last_occurred_global_event_after(&global_filters, usecs));
 
                 // TODO - move this timing logic to seperate function when we need it for other things
@@ -181,13 +215,13 @@ pub trait APC {
                     let hide_in_usecs = LENGTH_INDICATOR_USECS - usecs_ago;
 
                     if hide_in_usecs < cycle.usecs() {
-                        frame = hide_in_usecs as u32 * cycle.scope.n_frames() / cycle.usecs() as u32;
+                        frame = hide_in_usecs as u32 * cycle.n_frames / cycle.usecs() as u32;
                     } else {
-                        let length_buttons = (self.indicator().width() as u32 * self.loopable_ticks_in_grid(surface) / loopable_length) as u8;
-                        let start_button = offset_buttons as u8 * length_buttons / self.indicator().width();
+                        let length_buttons = (width as u32 * self.loopable_ticks_in_grid(surface) / loopable_length) as u8;
+                        let start_button = offset_buttons as u8 * length_buttons / width;
                         let stop_button = start_button + length_buttons;
                         for index in start_button .. stop_button {
-                            self.indicator().draw(index as u8, 1);
+                            if let Some(indicator) = self.indicator() { indicator.draw(index as u8, 1); }
                         }
                     }
                 } else {
@@ -199,33 +233,45 @@ pub trait APC {
                         let button = ticks_into_playable / ticks_per_button;
 
                         if button >= offset_buttons {
-                            self.indicator().draw((button - offset_buttons) as u8, 1);
+                            if let Some(indicator) = self.indicator() { indicator.draw((button - offset_buttons) as u8, 1); }
                         }
 
                         // If transition falls within current cycle, switch on correct frame
                         if range.stop % ticks_per_button < range.length() {
-                            frame = (((range.stop % ticks_per_button) as f64 / range.length() as f64) * cycle.scope.n_frames() as f64) as u32;
+                            frame = (((range.stop % ticks_per_button) as f64 / range.length() as f64) * cycle.n_frames as f64) as u32;
                         }
                     }
                 }
             },
             View::Timeline => {
                 let button = cycle.tick_range.start / Surface::TIMELINE_TICKS_PER_BUTTON;
-                let offset_buttons = surface.timeline_offset() / Surface::TIMELINE_TICKS_PER_BUTTON + Self::CHANNEL_OFFSET as u32;
+                let offset_buttons = surface.timeline_offset() / Surface::TIMELINE_TICKS_PER_BUTTON + Self::Mapping::CHANNEL_OFFSET as u32;
 
                 if button >= offset_buttons {
-                    self.indicator().draw((button - offset_buttons) as u8, 1);
+                    if let Some(indicator) = self.indicator() { indicator.draw((button - offset_buttons) as u8, 1); }
                 }
 
                 // If transition falls within current cycle, switch on correct frame
                 if cycle.tick_range.stop % Surface::TIMELINE_TICKS_PER_BUTTON < cycle.tick_range.length() {
-                    frame = (((cycle.tick_range.stop % Surface::TIMELINE_TICKS_PER_BUTTON) as f64 / cycle.tick_range.length() as f64) * cycle.scope.n_frames() as f64) as u32;
+                    frame = (((cycle.tick_range.stop % Surface::TIMELINE_TICKS_PER_BUTTON) as f64 / cycle.tick_range.length() as f64) * cycle.n_frames as f64) as u32;
                 }
             },
             _ => (),
         }
 
-        self.indicator().output_messages(frame)
+        Self::optional_led_messages(frame, self.indicator())
+    }
+
+    fn led_messages(frame: u32, leds: Vec<(Led, u8)>) -> Vec<TimedMessage> {
+        leds.into_iter()
+            .filter_map(|(led, value)| Self::Mapping::led_message(led, value))
+            .map(|message| TimedMessage::new(frame, message))
+            .collect()
+    }
+
+    // Lights the hardware does not have don't output anything
+    fn optional_led_messages(frame: u32, drawable: Option<&mut impl Drawable>) -> Vec<TimedMessage> {
+        Self::led_messages(frame, drawable.map(|drawable| drawable.output()).unwrap_or_default())
     }
 
     /*
@@ -235,7 +281,7 @@ pub trait APC {
         offset_x: u32, offset_y: u8, ticks_in_grid: u32, head_color: u8, tail_color: u8) 
     {
         let grid_stop = offset_x + ticks_in_grid;
-        let ticks_per_button = (ticks_in_grid / 8) as i32;
+        let ticks_per_button = (ticks_in_grid / Self::Mapping::GRID_WIDTH as u32) as i32;
 
         // Draw main grid
         events
@@ -284,18 +330,21 @@ pub trait APC {
         let channel = sequencer.channel(surface.channel_shown());
 
         // Draw main grid
-        let events = channel.timeline.events().iter();
-        let offset = Surface::TIMELINE_TICKS_PER_BUTTON * Self::CHANNEL_OFFSET as u32 + surface.timeline_offset();
-        self.draw_loopable_events(events, offset, 0, Surface::TIMELINE_TICKS_PER_BUTTON * 8, TIMELINE_HEAD_COLOR, TIMELINE_TAIL_COLOR);
+        let events = channel.timeline.events().iter()
+            .filter(|event| surface.bank_button(event.phrase).is_some());
+        let offset = Surface::TIMELINE_TICKS_PER_BUTTON * Self::Mapping::CHANNEL_OFFSET as u32 + surface.timeline_offset();
+        self.draw_loopable_events(events, offset, surface.bank_start(), Surface::TIMELINE_TICKS_PER_BUTTON * Self::Mapping::GRID_WIDTH as u32, TIMELINE_HEAD_COLOR, TIMELINE_TAIL_COLOR);
     }
 
     /*
      * Draw grid that we can use to select what phrases are playing
      */
-    fn draw_phrases(&mut self, phrases: &[Option<u8>; 16]) {
-        for (index, option) in phrases[Self::CHANNEL_OFFSET as usize .. (Self::CHANNEL_OFFSET + 8) as usize].iter().enumerate() {
-            if let Some(phrase) = option {
-                self.try_draw_to_grid(index as i32, *phrase, SEQUENCE_COLOR);
+    fn draw_phrases(&mut self, phrases: &[Option<u8>; 16], surface: &Surface) {
+        let columns = phrases.iter().skip(Self::Mapping::CHANNEL_OFFSET as usize).take(Self::Mapping::GRID_WIDTH as usize);
+
+        for (index, option) in columns.enumerate() {
+            if let Some(row) = option.and_then(|phrase| surface.bank_button(phrase)) {
+                self.try_draw_to_grid(index as i32, row, SEQUENCE_COLOR);
             }
         }
     }
@@ -304,20 +353,24 @@ pub trait APC {
         while let Some(x) = x_range.next() { self.try_draw_to_grid(x, y, color) }
     }
 
-    fn new(client: &jack::Client) -> Self;
+    // Controllers created without client are not connected to anything, see Emulator
+    fn new(client: Option<&jack::Client>) -> Self;
+
+    fn process_midi_input(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
+        let events = self.input_events(cycle);
+        self.process_input_events(events, cycle, sequencer, surface);
+    }
 
     /*
-     * Process incoming midi, handle generic midi here, pass controller specific input to
+     * Process incoming events, handle generic midi here, pass controller specific input to
      * controller via process_inputevent
      */ 
-    fn process_midi_input(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
-        for event in self.input_events(cycle.scope) {
+    fn process_input_events(&mut self, events: Vec<InputEvent>, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
+        for event in events {
             // Only process channel note messages
             match event.event_type {
                 InputEventType::InquiryResponse(local_id, device_id) => {
                     // Introduce ourselves to controller
-                    // 0x41 after 0x04 is ableton mode (only led rings are not controlled by host, but can be set.)
-                    // 0x42 is ableton alternate mode (all leds controlled from host)
                     self.set_device_id(device_id);
                     self.set_local_id(local_id);
                     // Make sure we stop inquiring
@@ -326,44 +379,51 @@ pub trait APC {
                     self.set_identified_cycles(1);
                 },
                 InputEventType::FaderMoved { value, fader_type: FaderType::Channel(index) } => {
-                    println!("fader {:?} adjusted to {:?}", index + Self::CHANNEL_OFFSET, value);
-                    //mixer.fader_adjusted(event.time, index + Self::CHANNEL_OFFSET, value);
+                    sequencer.fader_adjusted(event.time, (index + Self::Mapping::CHANNEL_OFFSET) as usize, value);
                 },
-                // TODO - Shift events in loopable to right/left when holding shift
-                InputEventType::KnobTurned { value, knob_type: KnobType::Control(index) } => {
-                    println!("knob {:?} adjusted to {:?}", index, value);
+                InputEventType::FaderMoved { value, fader_type: FaderType::Master } => {
+                    sequencer.master_adjusted(event.time, value);
                 },
                 // TODO - Shift events in loopable to right/left when holding shift
                 InputEventType::KnobTurned { value, knob_type: KnobType::Cue } => {
                     // Check if cueknob should respond immediately
-                    let usecs = cycle.time_at_frame(event.time) - LENGTH_INDICATOR_USECS;
+                    let usecs = cycle.time_at_frame(event.time).saturating_sub(LENGTH_INDICATOR_USECS);
                     let is_first_turn = surface.event_memory
-                        .last_occurred_controller_event_after(Self::CHANNEL_OFFSET, &[InputEvent::is_cue_knob], usecs)
+                        .last_occurred_controller_event_after(Self::Mapping::CHANNEL_OFFSET, &[InputEvent::is_cue_knob], usecs)
                         .is_none();
 
-                    let delta_buttons = self.cue_knob().process_turn(value, is_first_turn);
-
-                    match surface.view {
-                        View::Channel => {
-                            let delta_ticks = delta_buttons as i32 * self.loopable_ticks_per_button(surface) as i32;
-                            let new_offset = self.shown_loopable_offset(surface) as i32 + delta_ticks;
-                            let offset = if new_offset < 0 { 0 } else { new_offset as u32 };
+                    let delta_buttons = self.cue_knob().map(|cue_knob| cue_knob.process_turn(value, is_first_turn)).unwrap_or(0);
 
-                            self.set_shown_loopable_offset(sequencer, surface, offset);
-                        },
-                        View::Timeline => {
-                            let new_offset = surface.timeline_offset() as i32 + (delta_buttons as i32 * Surface::TIMELINE_TICKS_PER_BUTTON as i32);
-
-                            if new_offset >= 0 {
-                                surface.set_timeline_offset(sequencer, new_offset as u32);
-                            }
-                        },
-                        _ => (),
+                    // Fine tune tempo when holding shift
+                    if surface.button_memory.is_pressed(ButtonType::Shift) {
+                        let delta = CueKnob::delta(value) as f64 * FINE_TEMPO_STEP;
+                        let tick = cycle.tick_range.start;
+                        sequencer.set_beats_per_minute(tick, sequencer.beats_per_minute(tick) + delta);
+                    } else {
+                        match surface.view {
+                            View::Channel => {
+                                let delta_ticks = delta_buttons as i32 * self.loopable_ticks_per_button(surface) as i32;
+                                let new_offset = self.shown_loopable_offset(surface) as i32 + delta_ticks;
+                                let offset = if new_offset < 0 { 0 } else { new_offset as u32 };
+
+                                self.set_shown_loopable_offset(sequencer, surface, offset);
+                            },
+                            View::Timeline => {
+                                let new_offset = surface.timeline_offset() as i32 + (delta_buttons as i32 * Surface::TIMELINE_TICKS_PER_BUTTON as i32);
+
+                                if new_offset >= 0 {
+                                    surface.set_timeline_offset(sequencer, new_offset as u32);
+                                }
+                            },
+                            _ => (),
+                        }
                     }
                 },
                 InputEventType::ButtonPressed(button_type) => {
                     // Register press in memory to keep channel of modifing buttons
-                    surface.button_memory.press(Self::CHANNEL_OFFSET, button_type);
+                    surface.button_memory.press(Self::Mapping::CHANNEL_OFFSET, button_type);
+                    // Everything a button press changes can be undone at once
+                    sequencer.begin_edit();
                     let global_modifier = surface.button_memory.global_modifier(button_type);
 
                     // Do the right thing in the right visualization
@@ -381,16 +441,16 @@ pub trait APC {
                             }
                         },
                         View::Sequence => {
-                            let sequence = sequencer.get_sequence(surface.sequence_shown());
-
                             match button_type {
                                 ButtonType::Grid(x, row) => {
-                                    let channel = (x + Self::CHANNEL_OFFSET) as usize;
+                                    let sequence = sequencer.get_sequence(surface.sequence_shown());
+                                    let channel = (x + Self::Mapping::CHANNEL_OFFSET) as usize;
+                                    let phrase = surface.bank_index(row);
                                     
-                                    if let Some(true) = sequence.get_phrase(channel).and_then(|phrase| Some(phrase == row)) {
+                                    if let Some(true) = sequence.get_phrase(channel).and_then(|playing| Some(playing == phrase)) {
                                         sequence.unset_phrase(channel)
                                     } else {
-                                        sequence.set_phrase(channel, row);
+                                        sequence.set_phrase(channel, phrase);
                                     }
                                 },
                                 ButtonType::Side(index) => {
@@ -398,20 +458,20 @@ pub trait APC {
                                     let filters = vec![|event_type: &InputEventType| -> bool {
                                         *event_type == event.event_type
                                     }];
-                                    let usecs = cycle.time_stop - DOUBLE_CLICK_USECS;
-                                    let last_occurred_event = surface.event_memory.last_occurred_controller_event_after(Self::CHANNEL_OFFSET, &filters, usecs);
+                                    let usecs = cycle.time_stop.saturating_sub(DOUBLE_CLICK_USECS);
+                                    let last_occurred_event = surface.event_memory.last_occurred_controller_event_after(Self::Mapping::CHANNEL_OFFSET, &filters, usecs);
 
                                     if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
-                                        sequence.set_phrases(index);
+                                        sequencer.get_sequence(surface.sequence_shown()).set_phrases(surface.bank_index(index));
                                     } else if let Some(_) = last_occurred_event {
                                         // If we double clicked sequence button, queue it
-                                        sequencer.sequence_queued = Some(index as usize);
+                                        sequencer.sequence_queued = Some(surface.bank_index(index) as usize);
                                     } else {
-                                        surface.show_sequence(index);
+                                        surface.show_sequence(surface.bank_index(index));
                                     }
                                 },
                                 ButtonType::Activator(channel) => {
-                                    sequence.toggle_active((channel + Self::CHANNEL_OFFSET) as usize)
+                                    sequencer.get_sequence(surface.sequence_shown()).toggle_active((channel + Self::Mapping::CHANNEL_OFFSET) as usize)
                                 },
                                 _ => (),
                             }
@@ -419,15 +479,16 @@ pub trait APC {
                         View::Timeline => {
                             match button_type {
                                 ButtonType::Grid(x, y) => {
-                                    let channel = sequencer.channel_mut(surface.channel_shown());
+                                    let timeline = sequencer.channel_mut(surface.channel_shown()).timeline_mut();
+                                    let phrase = surface.bank_index(y);
 
                                     // Add channel offset to make it possible to draw across multiple controllers
-                                    let start = (Self::CHANNEL_OFFSET + x) as u32 * Surface::TIMELINE_TICKS_PER_BUTTON + surface.timeline_offset();
+                                    let start = (Self::Mapping::CHANNEL_OFFSET + x) as u32 * Surface::TIMELINE_TICKS_PER_BUTTON + surface.timeline_offset();
                                     let mut tick_range = TickRange::new(start, start + Surface::TIMELINE_TICKS_PER_BUTTON);
 
                                     // Should we delete the event we're clicking?
-                                    if let (None, true) = (global_modifier, channel.timeline.contains_events_starting_in(tick_range, y)) {
-                                        channel.timeline.remove_events_starting_in(tick_range, y);
+                                    if let (None, true) = (global_modifier, timeline.contains_events_starting_in(tick_range, phrase)) {
+                                        timeline.remove_events_starting_in(tick_range, phrase);
                                     } else {
                                         // Add event get x from modifier when its a grid button in the same row
                                         if let Some(ButtonPress { button_type: ButtonType::Grid(mod_x, mod_y), controller_channel_offset }) = global_modifier {
@@ -446,7 +507,7 @@ pub trait APC {
                                             tick_range.stop = start + Surface::TIMELINE_TICKS_PER_BUTTON;
                                         }
 
-                                        channel.timeline.add_complete_event(LoopablePhraseEvent::new(tick_range.start, tick_range.stop, y));
+                                        timeline.add_complete_event(LoopablePhraseEvent::new(tick_range.start, tick_range.stop, phrase));
                                     }
                                 },
                                 _ => (),
@@ -459,16 +520,16 @@ pub trait APC {
                         ButtonType::Channel(index) => {
                             match surface.view {
                                 View::Channel | View::Timeline => {
-                                    if surface.channel_shown() == (index + Self::CHANNEL_OFFSET) as usize {
+                                    if surface.channel_shown() == (index + Self::Mapping::CHANNEL_OFFSET) as usize {
                                         let view = if matches!(surface.view, View::Timeline) { View::Channel } else { View::Timeline };
                                         surface.switch_view(view);
                                     } else {
-                                        surface.show_channel(index + Self::CHANNEL_OFFSET);
+                                        surface.show_channel(index + Self::Mapping::CHANNEL_OFFSET);
                                     }
                                 },
                                 _ => {
                                     surface.switch_view(View::Timeline);
-                                    surface.show_channel(index + Self::CHANNEL_OFFSET);
+                                    surface.show_channel(index + Self::Mapping::CHANNEL_OFFSET);
                                 },
                             }
                         },
@@ -482,16 +543,18 @@ pub trait APC {
                         },
                         _ => self.process_inputevent(&event, cycle, sequencer, surface),
                     }
+
+                    sequencer.end_edit();
                 },
                 InputEventType::ButtonReleased(button_type) => {
-                    surface.button_memory.release(Self::CHANNEL_OFFSET, cycle.time_at_frame(event.time), button_type);
+                    surface.button_memory.release(Self::Mapping::CHANNEL_OFFSET, cycle.time_at_frame(event.time), button_type);
                 },
                 // This message is controller specific, handle it accordingly
                 _ => self.process_inputevent(&event, cycle, sequencer, surface),
             }
 
             // Keep channel of event so we can use it to calculate double presses etc.
-            surface.event_memory.register_event(Self::CHANNEL_OFFSET, cycle.time_at_frame(event.time), event.event_type);
+            surface.event_memory.register_event(Self::Mapping::CHANNEL_OFFSET, cycle.time_at_frame(event.time), event.event_type);
         }
     }
 
@@ -500,60 +563,70 @@ pub trait APC {
 
         // Identify when no controller found yet
         if self.identified_cycles() == 0 {
-            messages.push(TimedMessage::new(0, Message::Inquiry([0xF0, 0x7E, 0x00, 0x06, 0x01, 0xF7])));
+            match Self::Mapping::inquiry() {
+                Some(message) => messages.push(TimedMessage::new(0, message)),
+                None => self.set_identified_cycles(IDENTIFY_CYCLES),
+            }
         } else if self.identified_cycles() < IDENTIFY_CYCLES {
-            // Output introduction if APC just responded to inquiry
+            // Output introduction if controller just responded to inquiry
             if self.identified_cycles() == 1 {
-                let message = Message::Introduction([0xF0, 0x47, self.local_id(), self.device_id(), 0x60, 0x00, 0x04, 0x41, 0x00, 0x00, 0x00, 0xF7]);
-                messages.push(TimedMessage::new(0, message));
+                if let Some(message) = Self::Mapping::introduction(self.local_id(), self.device_id()) {
+                    messages.push(TimedMessage::new(0, message));
+                }
             }
 
             self.set_identified_cycles(self.identified_cycles() + 1);
         } else {
-            // APC 40 / 20 specific messages
+            // Hardware specific leds
             self.draw(sequencer, surface);
 
             // Always draw channel grid
             // This if statement is here to see if we can subtract CHANNEL_OFFSET
-            if surface.channel_shown() >= Self::CHANNEL_OFFSET as usize && ! matches!(surface.view, View::Sequence) {
-                let channel = surface.channel_shown() - Self::CHANNEL_OFFSET as usize;
-                self.channel().draw(channel as u8, 1);
+            if surface.channel_shown() >= Self::Mapping::CHANNEL_OFFSET as usize && ! matches!(surface.view, View::Sequence) {
+                let channel = surface.channel_shown() - Self::Mapping::CHANNEL_OFFSET as usize;
+                if let Some(row) = self.channel() { row.draw(channel as u8, 1); }
             }
-            messages.append(&mut self.channel().output_messages(0));
+            messages.append(&mut Self::optional_led_messages(0, self.channel()));
 
             match surface.view {
                 View::Channel => {
                     // Draw zoom grid
-                    for index in 0 .. self.loopable_zoom_level(surface) { self.solo().draw(index, 1); }
+                    let zoom_level = self.loopable_zoom_level(surface);
+                    if let Some(solo) = self.solo() {
+                        for index in 0 .. zoom_level { solo.draw(index, 1); }
+                    }
                 },
                 View::Timeline => {
                     self.draw_timeline(sequencer, surface);
                 },
                 View::Sequence => {
                     self.master().draw(1);
-                    let phrases = sequencer.get_sequence(surface.sequence_shown()).phrases();
-                    self.draw_phrases(phrases);
-                    self.side().draw(surface.sequence_shown() as u8, 1);
+                    let phrases = sequencer.sequences[surface.sequence_shown()].phrases();
+                    self.draw_phrases(phrases, surface);
                 },
             };
 
-            messages.append(&mut self.master().output_messages(0));
-            messages.append(&mut self.solo().output_messages(0));
-            messages.append(&mut self.grid().output_messages(0));
-            messages.append(&mut self.activator().output_messages(0));
+            messages.append(&mut Self::led_messages(0, self.master().output()));
+            messages.append(&mut Self::optional_led_messages(0, self.solo()));
+            messages.append(&mut Self::led_messages(0, self.grid().output()));
+            messages.append(&mut Self::optional_led_messages(0, self.activator()));
+            messages.append(&mut Self::optional_led_messages(0, self.arm()));
+            messages.append(&mut Self::optional_led_messages(0, self.knob_rings()));
             messages.append(&mut self.output_side(cycle, sequencer, surface));
             messages.append(&mut self.output_indicator(cycle, sequencer, surface));
         }
 
         // from this function
-        self.output().write_midi(cycle.scope, &mut messages);
+        self.output().write_midi(cycle, &mut messages);
     }
 
     fn output(&mut self) -> &mut MidiOut;
-    fn input(&self) -> &jack::Port<jack::MidiIn>;
+    fn input(&self) -> &MidiIn;
 
-    fn input_events(&self, scope: &jack::ProcessScope) -> Vec<InputEvent> {
-        self.input().iter(scope).map(|message| InputEvent::new(message.time, message.bytes)).collect()
+    fn input_events(&self, cycle: &ProcessCycle) -> Vec<InputEvent> {
+        self.input().messages(cycle).into_iter()
+            .map(|message| InputEvent { time: message.time, event_type: Self::Mapping::input_event_type(message.bytes) })
+            .collect()
     }
 
     fn process_inputevent(&mut self, event: &InputEvent, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface);
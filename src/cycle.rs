@@ -1,36 +1,119 @@
 
+use jack_sys as j;
 use super::TickRange;
+use super::TimebaseHandler;
+use super::tempo::TempoMap;
+use super::transport::{Transport, SyntheticTransport};
 
 pub struct ProcessCycle<'a> {
-    pub client: &'a jack::Client,
-    pub scope: &'a jack::ProcessScope,
+    pub transport: &'a dyn Transport,
+    // Cycles rendered offline don't have a jack process scope
+    pub scope: Option<&'a jack::ProcessScope>,
+    pub n_frames: u32,
     pub tick_range: TickRange,
+    pub beats_per_minute: f64,
     pub time_stop: u64,
     pub time_start: u64,
     pub is_rolling: bool,
 }
 
 impl<'a> ProcessCycle<'a> {
-    pub fn frame_to_tick(pos: jack::Position, frame: u32) -> f64 {
-        let second = frame as f64 / pos.frame_rate as f64;
-        second / 60.0 * pos.beats_per_minute * pos.ticks_per_beat
+    pub fn frame_to_tick(tempo_map: &TempoMap, pos: jack::Position, frame: u32) -> f64 {
+        tempo_map.tick_at_seconds(frame as f64 / pos.frame_rate as f64)
+    }
+
+    // Absolute tick of BBT position set by our timebase handler
+    pub fn bbt_to_tick(pos: jack::Position) -> f64 {
+        pos.bar_start_tick + (pos.beat - 1) as f64 * pos.ticks_per_beat + pos.tick as f64
+    }
+
+    // Absolute tick of BBT position set by other timebase master. These don't always set
+    // bar_start_tick & could use another resolution, so count bars & beats in our ticks
+    pub fn external_bbt_to_tick(pos: jack::Position) -> f64 {
+        let beats = (pos.bar - 1) as f64 * pos.beats_per_bar as f64 + (pos.beat - 1) as f64;
+        (beats + pos.tick as f64 / pos.ticks_per_beat) * TimebaseHandler::TICKS_PER_BEAT
     }
 
     // Save client as we pass this cycle thing everywhere
-    pub fn new(client: &'a jack::Client, scope: &'a jack::ProcessScope) -> Self {
+    pub fn new(
+        client: &'a jack::Client,
+        scope: &'a jack::ProcessScope,
+        previous: Option<TickRange>,
+        tempo_map: &TempoMap,
+        is_timebase_master: bool
+    ) -> Self {
         let cycle_times = scope.cycle_times().unwrap();
         let (state, pos) = client.transport_query();
+        let is_rolling = state == 1;
+        let has_bbt = pos.valid & j::JackPositionBBT != 0;
+        let cycle_seconds = scope.n_frames() as f64 / pos.frame_rate as f64;
+
+        let is_following = has_bbt && ! is_timebase_master;
+
+        let (start, stop, beats_per_minute) = if is_following {
+            // Follow tempo of external timebase master
+            let start = Self::external_bbt_to_tick(pos);
+            let stop = start + cycle_seconds / 60.0 * pos.beats_per_minute * TimebaseHandler::TICKS_PER_BEAT;
+            (start, stop, pos.beats_per_minute)
+        } else {
+            // Use BBT when our timebase handler provides it, that way tempo changes don't make us jump
+            let start = if has_bbt {
+                Self::bbt_to_tick(pos)
+            } else {
+                Self::frame_to_tick(tempo_map, pos, pos.frame)
+            };
+            // Tempo could change within cycle, follow tempo map from start tick
+            let stop = tempo_map.tick_at_seconds(tempo_map.seconds_at_tick(start) + cycle_seconds);
+            (start, stop, tempo_map.beats_per_minute_at(start as u32))
+        };
+        let mut tick_range = TickRange::new(start as u32, stop as u32);
+
+        // BBT ticks are rounded, continue where last cycle stopped so we don't skip or repeat ticks.
+        // External masters could count in larger ticks, allow for their rounding aswell
+        let tolerance = if is_following { (TimebaseHandler::TICKS_PER_BEAT / pos.ticks_per_beat).ceil().max(2.0) } else { 2.0 };
+        if let (Some(previous), true) = (previous, is_rolling) {
+            if (tick_range.start as f64 - previous.stop as f64).abs() <= tolerance {
+                tick_range.start = previous.stop;
+                tick_range.stop = tick_range.stop.max(tick_range.start);
+            }
+        }
 
         Self {
-            client,
-            scope,
+            transport: client,
+            scope: Some(scope),
+            n_frames: scope.n_frames(),
             time_start: cycle_times.current_usecs,
             time_stop: cycle_times.next_usecs,
-            tick_range: TickRange { 
-                start: Self::frame_to_tick(pos, pos.frame) as u32,
-                stop: Self::frame_to_tick(pos, pos.frame + scope.n_frames()) as u32,
-            },
-            is_rolling: state == 1,
+            tick_range,
+            beats_per_minute,
+            is_rolling,
+        }
+    }
+
+    // Cycle of synthetic transport, following our tempo map like we do when we're timebase master
+    pub fn synthetic(transport: &'a SyntheticTransport, previous: Option<TickRange>, tempo_map: &TempoMap) -> Self {
+        let seconds = |frame: u32| frame as f64 / transport.frame_rate as f64;
+        let start = transport.frame();
+        let stop = start + transport.buffer_size;
+
+        let mut tick_range = TickRange::new(tempo_map.tick_at_seconds(seconds(start)) as u32, tempo_map.tick_at_seconds(seconds(stop)) as u32);
+        // Ticks are rounded, continue where last cycle stopped
+        if let (Some(previous), true) = (previous, transport.is_rolling()) {
+            if (tick_range.start as f64 - previous.stop as f64).abs() <= 2.0 {
+                tick_range.start = previous.stop;
+                tick_range.stop = tick_range.stop.max(tick_range.start);
+            }
+        }
+
+        Self {
+            transport,
+            scope: None,
+            n_frames: transport.buffer_size,
+            time_start: transport.usecs(),
+            time_stop: transport.usecs() + transport.buffer_size as u64 * 1_000_000 / transport.frame_rate as u64,
+            beats_per_minute: tempo_map.beats_per_minute_at(tick_range.start),
+            tick_range,
+            is_rolling: transport.is_rolling(),
         }
     }
 
@@ -44,15 +127,20 @@ impl<'a> ProcessCycle<'a> {
 
     pub fn time_at_frame(&self, frame: u32) -> u64 {
         // TODO - When can this error?
-        let usecs_per_frame = self.usecs() as f32 / self.scope.n_frames() as f32;
+        let usecs_per_frame = self.usecs() as f32 / self.n_frames as f32;
         let usecs_since_period_start = frame as f32 * usecs_per_frame;
         self.time_start + usecs_since_period_start as u64
     }
 
+    pub fn tick_at_frame(&self, frame: u32) -> u32 {
+        let ticks_in_cycle = frame as f64 / self.n_frames as f64 * self.ticks() as f64;
+        self.tick_range.start + ticks_in_cycle as u32
+    }
+
     // TODO - This can panic, is that what we want?
     pub fn tick_to_frame(&self, tick: u32) -> u32 {
         let tick_in_cycle = tick - self.tick_range.start;
-        let frame_in_cycle = tick_in_cycle as f64 / self.ticks() as f64 * self.scope.n_frames() as f64;
+        let frame_in_cycle = tick_in_cycle as f64 / self.ticks() as f64 * self.n_frames as f64;
         frame_in_cycle as u32
     }
 }
@@ -199,6 +199,20 @@ impl LoopablePhraseEvent {
     }
 }
 
+// Automation of a controller, tick is relative to the phrase containing it
+#[derive(Debug, Clone, Copy)]
+pub struct ControlEvent {
+    pub tick: u32,
+    pub controller: u8,
+    pub value: u8,
+}
+
+impl ControlEvent {
+    pub fn new(tick: u32, controller: u8, value: u8) -> Self {
+        ControlEvent { tick, controller, value }
+    }
+}
+
 // We also keep start around so we can use this for different note visualizations aswell
 #[derive(Debug)]
 pub struct PlayingNoteEvent {
@@ -0,0 +1,105 @@
+
+use std::fmt;
+use super::TimebaseHandler;
+
+/*
+ * Timing & velocity offsets for notes on a grid of steps, the offsets repeat every steps.len()
+ * steps. Patterns stay on the grid, grooves are applied when notes are played
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Groove {
+    step_ticks: u32,
+    // Delay in ticks & velocity offset of every step
+    steps: Vec<(u32, i8)>,
+}
+
+impl Groove {
+    pub const SIXTEENTH_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32 / 4;
+    pub const MIN_SWING: u8 = 50;
+    pub const MAX_SWING: u8 = 75;
+
+    pub fn new(step_ticks: u32, steps: Vec<(u32, i8)>) -> Self {
+        Groove { step_ticks, steps }
+    }
+
+    // MPC style swing, percentage of 2 16th notes after which the second 16th is played
+    pub fn swing(percent: u8) -> Self {
+        let percent = percent.max(Self::MIN_SWING).min(Self::MAX_SWING) as u32;
+        let delay = (percent * 2 - 100) * Self::SIXTEENTH_TICKS / 100;
+
+        Self::new(Self::SIXTEENTH_TICKS, vec![(0, 0), (delay, 0)])
+    }
+
+    // Step ticks followed by steps written as <delay>:<velocity offset>, velocity offset is optional.
+    // Delays stay within their step, so notes keep their order
+    pub fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let step_ticks: u32 = words.next()?.parse().ok().filter(|ticks| *ticks > 0)?;
+        let steps = words
+            .map(|word| {
+                let mut values = word.split(':');
+                let delay = values.next()?.parse().ok().filter(|delay| *delay < step_ticks)?;
+                let velocity_offset = values.next().map(|value| value.parse().ok()).unwrap_or(Some(0))?;
+                Some((delay, velocity_offset))
+            })
+            .collect::<Option<Vec<(u32, i8)>>>()
+            .filter(|steps| ! steps.is_empty())?;
+
+        Some(Self::new(step_ticks, steps))
+    }
+
+    // Delay & velocity of note starting at tick, notes get offsets of step they're closest to
+    pub fn apply(&self, tick: u32, velocity: u8) -> (u32, u8) {
+        let step = (tick + self.step_ticks / 2) / self.step_ticks;
+        let (delay, velocity_offset) = self.steps[step as usize % self.steps.len()];
+        let velocity = (velocity as i32 + velocity_offset as i32).max(1).min(127);
+
+        (delay, velocity as u8)
+    }
+}
+
+impl fmt::Display for Groove {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.step_ticks)?;
+
+        for (delay, velocity_offset) in self.steps.iter() {
+            write!(f, " {}:{}", delay, velocity_offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swing() {
+        let groove = Groove::swing(66);
+        assert_eq!(groove.apply(0, 100), (0, 100));
+        assert_eq!(groove.apply(Groove::SIXTEENTH_TICKS, 100), (153, 100));
+        assert_eq!(groove.apply(Groove::SIXTEENTH_TICKS * 2 + 10, 100), (0, 100));
+
+        // Straight as it gets
+        assert_eq!(Groove::swing(10).apply(Groove::SIXTEENTH_TICKS, 100), (0, 100));
+    }
+
+    #[test]
+    fn parse() {
+        let groove = Groove::parse("240 0 20:-10".split_whitespace()).unwrap();
+        assert_eq!(groove, Groove::new(240, vec![(0, 0), (20, -10)]));
+        assert_eq!(Groove::parse(groove.to_string().split_whitespace()), Some(groove));
+
+        assert_eq!(Groove::parse("240".split_whitespace()), None);
+        assert_eq!(Groove::parse("240 a:1".split_whitespace()), None);
+        assert_eq!(Groove::parse("240 0 240".split_whitespace()), None);
+        assert_eq!(Groove::parse("240 4294967295".split_whitespace()), None);
+    }
+
+    #[test]
+    fn velocity() {
+        let groove = Groove::new(480, vec![(0, 20), (10, -120)]);
+        assert_eq!(groove.apply(0, 120), (0, 127));
+        assert_eq!(groove.apply(480, 100), (10, 1));
+    }
+}
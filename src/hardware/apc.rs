@@ -0,0 +1,166 @@
+
+use super::super::message::Message;
+use super::super::input::*;
+use super::super::mapping::*;
+
+/*
+ * Notes & controllers of the APC20 & APC40, the mkII & mini models number their buttons
+ * differently. Grid & side are turned upside down, as we draw lower notes lower on the grid while
+ * the APC's count their rows from the top
+ */
+pub struct APCMapping;
+
+// Both APC's speak the same midi, the APC40 sits right of the APC20
+pub struct APC20Mapping;
+pub struct APC40Mapping;
+
+impl APCMapping {
+    const GRID_WIDTH: u8 = 8;
+    const GRID_HEIGHT: u8 = 5;
+
+    fn button_type(channel: u8, note: u8) -> ButtonType {
+        match note {
+            0x5B => ButtonType::Play,
+            0x5C => ButtonType::Stop,
+            0x5D => ButtonType::Record,
+            0x63 => ButtonType::TapTempo,
+            0x64 => ButtonType::NudgeDown,
+            0x65 => ButtonType::NudgeUp,
+            0x33 => ButtonType::Channel(channel),
+            0x3F => ButtonType::Quantization,
+            // These used to be sequence buttons, but will now be more control groups for plugin parameters
+            //0x57 ..= 0x5A => ButtonType::Sequence(note - 0x57),
+            0x52 ..= 0x56 => ButtonType::Side(4 - (note - 0x52)),
+            0x51 => ButtonType::Shift,
+            0x50 => ButtonType::Master,
+            // Grid should add notes & add phrases
+            0x35 ..= 0x39 => ButtonType::Grid(channel, 4 - (note - 0x35)),
+            0x5E => ButtonType::Up,
+            0x5F => ButtonType::Down,
+            0x60 => ButtonType::Right,
+            0x61 => ButtonType::Left,
+            0x62 => ButtonType::Shift,
+            0x30 => ButtonType::Arm(channel),
+            0x31 => ButtonType::Solo(channel),
+            0x32 => ButtonType::Activator(channel),
+            _ => ButtonType::Unknown,
+        }
+    }
+
+    fn note(channel: u8, note: u8, value: u8) -> Message {
+        let status = if value > 0 { 0x90 } else { 0x80 };
+        Message::Note([status + channel, note, value])
+    }
+}
+
+impl APCMapping {
+    pub fn inquiry() -> Option<Message> {
+        Some(Message::Inquiry([0xF0, 0x7E, 0x00, 0x06, 0x01, 0xF7]))
+    }
+
+    // 0x41 after 0x04 is ableton mode (only led rings are not controlled by host, but can be set.)
+    // 0x42 is ableton alternate mode (all leds controlled from host)
+    pub fn introduction(local_id: u8, device_id: u8) -> Option<Message> {
+        Some(Message::Introduction([0xF0, 0x47, local_id, device_id, 0x60, 0x00, 0x04, 0x41, 0x00, 0x00, 0x00, 0xF7]))
+    }
+
+    pub fn input_event_type(bytes: &[u8]) -> InputEventType {
+        match bytes[0] {
+            0xF0 => {
+                // 0x06 = inquiry e, 0x02 = inquiry response 0x47 = akai manufacturer, 0x73 = APC40, 0x7b = APC20
+                if bytes.len() > 13 && bytes[3] == 0x06 && bytes[4] == 0x02 && bytes[5] == 0x47 && (bytes[6] == 0x73 || bytes[6] == 0x7b) {
+                    InputEventType::InquiryResponse(bytes[13], bytes[6])
+                } else {
+                    InputEventType::Unknown
+                }
+            },
+            0x90 ..= 0x9F => InputEventType::ButtonPressed(Self::button_type(bytes[0] - 0x90, bytes[1])),
+            0x80 ..= 0x8F => InputEventType::ButtonReleased(Self::button_type(bytes[0] - 0x80, bytes[1])),
+            0xB0 ..= 0xB8 => {
+                match bytes[1] {
+                    0x30 ..= 0x37 | 0x10 ..= 0x17 => {
+                        // APC effect knobs are ordered weird, reorder them from to 0..16
+                        let modifier = if (0x30 ..= 0x37).contains(&bytes[1]) { 48 } else { 8 };
+                        let index = bytes[1] - modifier;
+
+                        InputEventType::KnobTurned { value: bytes[2], knob_type: KnobType::Control(index) }
+                    },
+                    0x7 => InputEventType::FaderMoved { value: bytes[2], fader_type: FaderType::Channel(bytes[0] - 0xB0) },
+                    0xE => InputEventType::FaderMoved { value: bytes[2], fader_type: FaderType::Master },
+                    0xF => InputEventType::FaderMoved { value: bytes[2], fader_type: FaderType::CrossFade },
+                    0x2F => InputEventType::KnobTurned { value: bytes[2], knob_type: KnobType::Cue },
+                    _ => InputEventType::Unknown,
+                }
+            },
+            _ => InputEventType::Unknown,
+        }
+    }
+
+    pub fn led_message(led: Led, value: u8) -> Option<Message> {
+        let message = match led {
+            Led::Grid(x, y) => Self::note(x, 0x35 + 4 - y, value),
+            Led::Side(index) => Self::note(0, 0x52 + 4 - index, value),
+            Led::Indicator(index) => Self::note(index, 0x34, value),
+            Led::Channel(index) => Self::note(index, 0x33, value),
+            Led::Activator(index) => Self::note(index, 0x32, value),
+            Led::Solo(index) => Self::note(index, 0x31, value),
+            Led::Arm(index) => Self::note(index, 0x30, value),
+            Led::Master => Self::note(0, 0x50, value),
+            // Reverse of knob index in input events
+            Led::KnobRing(index) => {
+                let controller = if index < 8 { 0x30 + index } else { 0x10 + index - 8 };
+                Message::ControlChange([0xB0, controller, value])
+            },
+        };
+
+        Some(message)
+    }
+}
+
+impl Mapping for APC20Mapping {
+    const CHANNEL_OFFSET: u8 = 0;
+    const GRID_WIDTH: u8 = APCMapping::GRID_WIDTH;
+    const GRID_HEIGHT: u8 = APCMapping::GRID_HEIGHT;
+    const SIDE_BUTTONS: u8 = APCMapping::GRID_HEIGHT;
+
+    fn inquiry() -> Option<Message> { APCMapping::inquiry() }
+    fn introduction(local_id: u8, device_id: u8) -> Option<Message> { APCMapping::introduction(local_id, device_id) }
+    fn input_event_type(bytes: &[u8]) -> InputEventType { APCMapping::input_event_type(bytes) }
+    fn led_message(led: Led, value: u8) -> Option<Message> { APCMapping::led_message(led, value) }
+}
+
+impl Mapping for APC40Mapping {
+    const CHANNEL_OFFSET: u8 = APCMapping::GRID_WIDTH;
+    const GRID_WIDTH: u8 = APCMapping::GRID_WIDTH;
+    const GRID_HEIGHT: u8 = APCMapping::GRID_HEIGHT;
+    const SIDE_BUTTONS: u8 = APCMapping::GRID_HEIGHT;
+
+    fn inquiry() -> Option<Message> { APCMapping::inquiry() }
+    fn introduction(local_id: u8, device_id: u8) -> Option<Message> { APCMapping::introduction(local_id, device_id) }
+    fn input_event_type(bytes: &[u8]) -> InputEventType { APCMapping::input_event_type(bytes) }
+    fn led_message(led: Led, value: u8) -> Option<Message> { APCMapping::led_message(led, value) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_event_type() {
+        assert_eq!(APCMapping::input_event_type(&[0x92, 0x35, 0x7F]), InputEventType::ButtonPressed(ButtonType::Grid(2, 4)));
+        assert_eq!(APCMapping::input_event_type(&[0x80, 0x56, 0x00]), InputEventType::ButtonReleased(ButtonType::Side(0)));
+        assert_eq!(APCMapping::input_event_type(&[0xB0, 0x11, 0x40]), InputEventType::KnobTurned { value: 0x40, knob_type: KnobType::Control(9) });
+
+        let response = [0xF0, 0x7E, 0x00, 0x06, 0x02, 0x47, 0x73, 0x00, 0x19, 0x00, 0x00, 0x00, 0x00, 0x05, 0xF7];
+        assert_eq!(APCMapping::input_event_type(&response), InputEventType::InquiryResponse(0x05, 0x73));
+        assert_eq!(APCMapping::input_event_type(&[0xF0, 0x7E, 0xF7]), InputEventType::Unknown);
+    }
+
+    #[test]
+    fn led_message() {
+        // Leds are addressed like the buttons they belong to
+        assert_eq!(APCMapping::led_message(Led::Grid(2, 4), 3), Some(Message::Note([0x92, 0x35, 3])));
+        assert_eq!(APCMapping::led_message(Led::Side(0), 0), Some(Message::Note([0x80, 0x56, 0])));
+        assert_eq!(APCMapping::led_message(Led::KnobRing(9), 64), Some(Message::ControlChange([0xB0, 0x11, 64])));
+    }
+}
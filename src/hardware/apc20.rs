@@ -6,16 +6,18 @@ use super::super::cycle::ProcessCycle;
 use super::super::loopable::*;
 use super::super::sequencer::*;
 use super::super::surface::*;
-use super::super::port::MidiOut;
+use super::super::port::{MidiOut, MidiIn};
 use super::super::TimebaseHandler;
 use super::super::events::*;
 use super::super::input::*;
 use super::super::lights::*;
-use super::super::APC;
+use super::super::mapping::*;
+use super::super::Controller;
+use super::apc::APC20Mapping;
 
 pub struct APC20 {
     // Ports that connect to APC
-    input: jack::Port<jack::MidiIn>,
+    input: MidiIn,
     output: MidiOut,
 
     identified_cycles: u8,
@@ -32,13 +34,14 @@ pub struct APC20 {
     channel: WideRow,
     activator: WideRow,
     solo: WideRow,
-    //arm: WideRow,
+    arm: WideRow,
 }
 
-impl APC for APC20 {
+impl Controller for APC20 {
     type Loopable = Phrase;
 
-    const CHANNEL_OFFSET: u8 = 0;
+    type Mapping = APC20Mapping;
+
     const HEAD_COLOR: u8 = 3;
     const TAIL_COLOR: u8 = 5;
 
@@ -61,7 +64,7 @@ impl APC for APC20 {
     }
 
     fn output(&mut self) -> &mut MidiOut { &mut self.output }
-    fn input(&self) -> &jack::Port<jack::MidiIn> { &self.input }
+    fn input(&self) -> &MidiIn { &self.input }
 
     fn shown_loopable_index(&self, surface: &Surface) -> u8 { surface.phrase_shown(surface.channel_shown()) }
 
@@ -96,50 +99,48 @@ impl APC for APC20 {
             .collect()
     }
 
-    fn cue_knob(&mut self) -> &mut CueKnob { &mut self.cue_knob }
     fn master(&mut self) -> &mut Single { &mut self.master }
     fn grid(&mut self) -> &mut Grid { &mut self.grid }
-    fn side(&mut self) -> &mut Side { &mut self.side }
-    fn channel(&mut self) -> &mut WideRow { &mut self.channel }
-    fn activator(&mut self) -> &mut WideRow { &mut self.activator }
-    fn indicator(&mut self) -> &mut WideRow { &mut self.indicator }
-    fn solo(&mut self) -> &mut WideRow { &mut self.solo }
-
-    fn new(client: &jack::Client) -> Self {
-        let input = client.register_port("apc20_in", jack::MidiIn::default()).unwrap();
-        let output = client.register_port("apc20_out", jack::MidiOut::default()).unwrap();
-        
+    fn cue_knob(&mut self) -> Option<&mut CueKnob> { Some(&mut self.cue_knob) }
+    fn side(&mut self) -> Option<&mut Side> { Some(&mut self.side) }
+    fn channel(&mut self) -> Option<&mut WideRow> { Some(&mut self.channel) }
+    fn activator(&mut self) -> Option<&mut WideRow> { Some(&mut self.activator) }
+    fn indicator(&mut self) -> Option<&mut WideRow> { Some(&mut self.indicator) }
+    fn solo(&mut self) -> Option<&mut WideRow> { Some(&mut self.solo) }
+    fn arm(&mut self) -> Option<&mut WideRow> { Some(&mut self.arm) }
+
+    fn new(client: Option<&jack::Client>) -> Self {
         Self {
-            input,
-            output: MidiOut::new(output),
+            input: MidiIn::new(client, "apc20_in"),
+            output: MidiOut::new(client, "apc20_out"),
 
             identified_cycles: 0,
             local_id: 0,
             device_id: 0,
 
             cue_knob: CueKnob::new(),
-            master: Single::new(0x50),
-
-            grid: Grid::new(),
-            side: Side::new(),
-            indicator: WideRow::new(0x34),
-            channel: WideRow::new(0x33),
-            activator: WideRow::new(0x32),
-            solo: WideRow::new(0x31),
-            //arm: WideRow::new(0x30),
+            master: Single::new(Led::Master),
+
+            grid: Grid::new(Self::Mapping::GRID_WIDTH, Self::Mapping::GRID_HEIGHT),
+            side: Side::new(Self::Mapping::SIDE_BUTTONS),
+            indicator: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Indicator),
+            channel: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Channel),
+            activator: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Activator),
+            solo: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Solo),
+            arm: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Arm),
         }
     }
 
     fn process_inputevent(&mut self, event: &InputEvent, _cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
         let channel = sequencer.channel_mut(surface.channel_shown());
-        let phrase = channel.phrase_mut(surface.phrase_shown(surface.channel_shown()));
+        let phrase_index = surface.phrase_shown(surface.channel_shown());
 
         // Only process channel note messages
         match event.event_type {
             // TODO - Use indicator row as fast movement
             InputEventType::ButtonPressed(button_type) => {
                 // Get modifier (other currently pressed key)
-                let modifier = surface.button_memory.modifier(Self::CHANNEL_OFFSET, button_type);
+                let modifier = surface.button_memory.modifier(Self::Mapping::CHANNEL_OFFSET, button_type);
 
                 match surface.view {
                     View::Channel => {
@@ -149,9 +150,13 @@ impl APC for APC20 {
                                 // We draw grids from bottom to top
                                 let ticks_per_button = self.loopable_ticks_per_button(surface);
 
-                                if let Some(tick_range) = self.should_add_event(phrase, modifier, ticks_per_button, x, y, offset, y) {
-                                    phrase.try_add_starting_event(LoopablePatternEvent::new(tick_range.start, y));
-                                    let mut event = phrase.get_last_event_on_row(y);
+                                // Rows show patterns of shown bank
+                                let pattern = surface.bank_index(y);
+                                let phrase = channel.phrase_mut(phrase_index);
+
+                                if let Some(tick_range) = self.should_add_event(phrase, modifier, ticks_per_button, x, y, offset, pattern) {
+                                    phrase.try_add_starting_event(LoopablePatternEvent::new(tick_range.start, pattern));
+                                    let mut event = phrase.get_last_event_on_row(pattern);
                                     event.set_stop(tick_range.stop);
 
                                     phrase.add_complete_event(event);
@@ -161,15 +166,15 @@ impl APC for APC20 {
                                 let global_modifier = surface.button_memory.global_modifier(button_type);
 
                                 if let Some(ButtonType::Side(modifier_index)) = modifier {
-                                    channel.clone_phrase(modifier_index, index);
+                                    channel.clone_phrase(surface.bank_index(modifier_index), surface.bank_index(index));
                                 } else if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
-                                    channel.phrase_mut(index).clear_events();
+                                    channel.phrase_mut(surface.bank_index(index)).clear_events();
                                 } else {
-                                    surface.show_phrase(surface.channel_shown(), index);
+                                    surface.show_phrase(surface.channel_shown(), surface.bank_index(index));
                                 }
                             },
                             ButtonType::Activator(index) => {
-                                phrase.set_length(Phrase::default_length() * (index as u32 + 1));
+                                channel.phrase_mut(phrase_index).set_length(Phrase::default_length() * (index as u32 + 1));
                             },
                             _ => (),
                         }
@@ -188,8 +193,9 @@ impl APC for APC20 {
                 let loopable = self.shown_loopable(sequencer, surface);
 
                 // Draw main grid
-                let events = loopable.events().iter();
-                self.draw_loopable_events(events, surface.phrase_offset(surface.channel_shown()), 0, self.loopable_ticks_in_grid(surface), Self::HEAD_COLOR, Self::TAIL_COLOR);
+                let events = loopable.events().iter()
+                    .filter(|event| surface.bank_button(event.pattern).is_some());
+                self.draw_loopable_events(events, surface.phrase_offset(surface.channel_shown()), surface.bank_start(), self.loopable_ticks_in_grid(surface), Self::HEAD_COLOR, Self::TAIL_COLOR);
 
                 // Length selector
                 for index in 0 .. (loopable.length() / Self::Loopable::default_length()) {
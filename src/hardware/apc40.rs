@@ -5,17 +5,21 @@ use super::super::message::{TimedMessage, Message};
 use super::super::cycle::ProcessCycle;
 use super::super::loopable::*;
 use super::super::sequencer::*;
+use super::super::channel::Channel;
 use super::super::surface::*;
-use super::super::port::MidiOut;
+use super::super::port::{MidiOut, MidiIn};
 use super::super::TimebaseHandler;
 use super::super::events::*;
+use super::super::notice::Notice;
 use super::super::input::*;
 use super::super::lights::*;
-use super::super::APC;
+use super::super::mapping::*;
+use super::super::Controller;
+use super::apc::APC40Mapping;
 
 pub struct APC40 {
     // Ports that connect to APC
-    input: jack::Port<jack::MidiIn>,
+    input: MidiIn,
     output: MidiOut,
 
     identified_cycles: u8,
@@ -24,6 +28,7 @@ pub struct APC40 {
     //knob_offset: u8,
 
     cue_knob: CueKnob,
+    knob_takeover: KnobTakeover,
     master: Single,
 
     grid: Grid,
@@ -32,13 +37,26 @@ pub struct APC40 {
     channel: WideRow,
     activator: WideRow,
     solo: WideRow,
-    //arm: WideRow,
+    arm: WideRow,
+    knob_rings: KnobRings,
 }
 
-impl APC for APC40 {
+impl APC40 {
+    // Yellow for soft, green for medium & red for loud notes
+    fn velocity_color(velocity: u8) -> u8 {
+        match velocity {
+            0 ..= 42 => 5,
+            43 ..= 85 => 1,
+            _ => 3,
+        }
+    }
+}
+
+impl Controller for APC40 {
     type Loopable = Pattern;
 
-    const CHANNEL_OFFSET: u8 = 8;
+    type Mapping = APC40Mapping;
+
     const HEAD_COLOR: u8 = 1;
     const TAIL_COLOR: u8 = 5;
 
@@ -61,7 +79,7 @@ impl APC for APC40 {
     }
 
     fn output(&mut self) -> &mut MidiOut { &mut self.output }
-    fn input(&self) -> &jack::Port<jack::MidiIn> { &self.input }
+    fn input(&self) -> &MidiIn { &self.input }
 
     fn shown_loopable_index(&self, surface: &Surface) -> u8 { surface.pattern_shown(surface.channel_shown()) }
 
@@ -110,22 +128,21 @@ impl APC for APC40 {
             .collect()
     }
 
-    fn cue_knob(&mut self) -> &mut CueKnob { &mut self.cue_knob }
     fn master(&mut self) -> &mut Single { &mut self.master }
     fn grid(&mut self) -> &mut Grid { &mut self.grid }
-    fn side(&mut self) -> &mut Side { &mut self.side }
-    fn channel(&mut self) -> &mut WideRow { &mut self.channel }
-    fn indicator(&mut self) -> &mut WideRow { &mut self.indicator }
-    fn activator(&mut self) -> &mut WideRow { &mut self.activator }
-    fn solo(&mut self) -> &mut WideRow { &mut self.solo }
-
-    fn new(client: &jack::Client) -> Self {
-        let input = client.register_port("apc40_in", jack::MidiIn::default()).unwrap();
-        let output = client.register_port("apc40_out", jack::MidiOut::default()).unwrap();
-        
+    fn cue_knob(&mut self) -> Option<&mut CueKnob> { Some(&mut self.cue_knob) }
+    fn side(&mut self) -> Option<&mut Side> { Some(&mut self.side) }
+    fn channel(&mut self) -> Option<&mut WideRow> { Some(&mut self.channel) }
+    fn indicator(&mut self) -> Option<&mut WideRow> { Some(&mut self.indicator) }
+    fn activator(&mut self) -> Option<&mut WideRow> { Some(&mut self.activator) }
+    fn solo(&mut self) -> Option<&mut WideRow> { Some(&mut self.solo) }
+    fn arm(&mut self) -> Option<&mut WideRow> { Some(&mut self.arm) }
+    fn knob_rings(&mut self) -> Option<&mut KnobRings> { Some(&mut self.knob_rings) }
+
+    fn new(client: Option<&jack::Client>) -> Self {
         Self {
-            input,
-            output: MidiOut::new(output),
+            input: MidiIn::new(client, "apc40_in"),
+            output: MidiOut::new(client, "apc40_out"),
 
             identified_cycles: 0,
             local_id: 0,
@@ -134,30 +151,31 @@ impl APC for APC40 {
             //knob_offset: 0,
 
             cue_knob: CueKnob::new(),
-            master: Single::new(0x50),
-
-            grid: Grid::new(),
-            side: Side::new(),
-            indicator: WideRow::new(0x34),
-            channel: WideRow::new(0x33),
-            activator: WideRow::new(0x32),
-            solo: WideRow::new(0x31),
-            // TODO - Put length indicator here, get length from longest LoopablePatternEvent in phrases?
-            //arm: WideRow::new(0x30),
+            knob_takeover: KnobTakeover::new(),
+            master: Single::new(Led::Master),
+
+            grid: Grid::new(Self::Mapping::GRID_WIDTH, Self::Mapping::GRID_HEIGHT),
+            side: Side::new(Self::Mapping::SIDE_BUTTONS),
+            indicator: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Indicator),
+            channel: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Channel),
+            activator: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Activator),
+            solo: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Solo),
+            arm: WideRow::new(Self::Mapping::GRID_WIDTH, Led::Arm),
+            knob_rings: KnobRings::new(),
         }
     }
 
     /*
-     * Process APC40 specific midi input, shared input is handled by APC trait
+     * Process APC40 specific midi input, shared input is handled by Controller trait
      */
     fn process_inputevent(&mut self, event: &InputEvent, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
         // Only process channel note messages
         match event.event_type {
-            // TODO - As we are not using a software mixer anymore, we don't have a master fader
-            //InputEventType::FaderMoved { value, fader_type: FaderType::Master } => {
-                //mixer.master_adjusted(event.time, value);
-            //},
-            // We use the crossfader to traverse patterns & phrases horizontally
+            // Crossfader mixes channels assigned to it, when there are none we use it to traverse
+            // patterns & phrases horizontally
+            InputEventType::FaderMoved { value, fader_type: FaderType::CrossFade } if sequencer.mixer().has_crossfade_groups() => {
+                sequencer.crossfader_adjusted(event.time, value);
+            },
             InputEventType::FaderMoved { value, fader_type: FaderType::CrossFade } => {
                 let factor = value as f64 / 127.0;
                 //let max_offset = self.max_offset(self.shown_loopable(sequencer, surface).length());
@@ -169,19 +187,66 @@ impl APC for APC40 {
                 //self.set_offset(surface.channel_shown(), offset);
                 //mixer.master_adjusted(event.time, value);
             },
-            InputEventType::KnobTurned { value: _, knob_type: KnobType::Control(_index) } => {
-                // TODO 
-                //sequencer.knob_turned(event.time, index + self.knob_offset, value);
+            // Set velocity of notes under held grid buttons while editing velocity, otherwise
+            // knobs control the shown channel
+            InputEventType::KnobTurned { value, knob_type: KnobType::Control(index) } => {
+                if ! surface.is_editing_velocity {
+                    let channel_index = surface.channel_shown();
+
+                    // Shift + knob clears automation of knob from shown phrase
+                    if surface.button_memory.is_pressed(ButtonType::Shift) {
+                        let phrase_index = surface.phrase_shown(channel_index);
+                        sequencer.begin_edit();
+                        sequencer.channel_mut(channel_index).phrase_mut(phrase_index).clear_control_lane(Channel::KNOB_CONTROLLER_OFFSET + index);
+                        sequencer.end_edit();
+                    } else {
+                        let controller = Channel::KNOB_CONTROLLER_OFFSET + index;
+                        let parameter_value = sequencer.channel(channel_index).knob_value(controller);
+
+                        if self.knob_takeover.takes_over(index, value, parameter_value) {
+                            sequencer.knob_turned(cycle, event.time, channel_index, index, value);
+                        }
+                    }
+                } else {
+                    if let View::Channel = surface.view {
+                        let offset = surface.pattern_offset(surface.channel_shown());
+                        let base_note = surface.pattern_base_note(surface.channel_shown());
+                        let ticks_per_button = self.loopable_ticks_per_button(surface);
+                        let pressed: Vec<(u8, u8)> = surface.button_memory.pressed(Self::Mapping::CHANNEL_OFFSET).into_iter()
+                            .filter_map(|button_type| if let ButtonType::Grid(x, y) = button_type { Some((x, y)) } else { None })
+                            .collect();
+
+                        // Every turn of the knob can be undone, there's nothing to undo without selected notes
+                        if ! pressed.is_empty() {
+                            sequencer.begin_edit();
+                            let pattern = self.shown_loopable_mut(sequencer, surface);
+
+                            for (x, y) in pressed {
+                                let start = x as u32 * ticks_per_button + offset;
+                                // Note off velocity 0 is fine, note on velocity 0 would be a note off
+                                let velocity = value.max(1);
+
+                                pattern.set_velocity_starting_in(TickRange::new(start, start + ticks_per_button), base_note - 2 + y, velocity);
+                            }
+                            sequencer.end_edit();
+                        }
+                    }
+                }
             },
             InputEventType::ButtonPressed(button_type) => {
                 // Get modifier (other currently pressed key)
-                let modifier = surface.button_memory.modifier(Self::CHANNEL_OFFSET, button_type);
+                let modifier = surface.button_memory.modifier(Self::Mapping::CHANNEL_OFFSET, button_type);
 
                 match surface.view {
                     View::Channel => {
                         match button_type {
+                            // Grid buttons select notes to change velocity of while editing velocity
+                            ButtonType::Grid(_, _) if surface.is_editing_velocity => (),
                             ButtonType::Grid(x, y) => {
+                                let quantizer = if sequencer.keyboard.is_quantizing { Some(sequencer.keyboard.quantizer) } else { None };
                                 let channel = sequencer.channel_mut(surface.channel_shown());
+                                let velocity = surface.input_velocity(channel.default_velocity());
+                                let instrument = channel.instrument().cloned();
                                 let pattern = channel.pattern_mut(surface.pattern_shown(surface.channel_shown()));
 
                                 // We subtract y from 4 as we want lower notes to be lower on
@@ -190,34 +255,41 @@ impl APC for APC40 {
                                 // We put base note in center of grid
                                 let note = surface.pattern_base_note(surface.channel_shown()) - 2 + y;
                                 let ticks_per_button = self.loopable_ticks_per_button(surface);
+                                let mut note_name = None;
 
                                 if let Some(tick_range) = self.should_add_event(pattern, modifier, ticks_per_button, x, y, offset, note) {
-                                    pattern.try_add_starting_event(LoopableNoteEvent::new(tick_range.start, note, 127));
+                                    note_name = instrument.as_ref().and_then(|instrument| instrument.note_name(note)).map(String::from);
+
+                                    pattern.try_add_starting_event(LoopableNoteEvent::new(tick_range.start, note, velocity));
                                     let mut event = pattern.get_last_event_on_row(note);
                                     event.set_stop(tick_range.stop);
-                                    event.stop_velocity = Some(127);
+                                    event.stop_velocity = Some(velocity);
+
+                                    if let Some(quantizer) = quantizer {
+                                        quantizer.quantize_note(&mut event, pattern.length);
+                                    }
 
                                     pattern.add_complete_event(event);
                                 }
+
+                                // Tell what sound we're drawing when instrument has a drum map
+                                if let Some(name) = note_name {
+                                    sequencer.notify(Notice::NoteName(name));
+                                }
                             },
                             ButtonType::Side(index) => {
                                 let global_modifier = surface.button_memory.global_modifier(button_type);
 
-                                // TODO - double press logic && recording logic
-                                if false {
-                                    //channel.pattern_mut(index).switch_recording_state()
+                                if let Some(ButtonType::Side(modifier_index)) = modifier {
+                                    let channel = sequencer.channel_mut(surface.channel_shown());
+                                    channel.clone_pattern(surface.bank_index(modifier_index), surface.bank_index(index));
+                                } else if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
+                                    surface.set_pattern_offset(sequencer, surface.channel_shown(), 0);
+
+                                    let channel = sequencer.channel_mut(surface.channel_shown());
+                                    channel.pattern_mut(surface.bank_index(index)).clear_events();
                                 } else {
-                                    if let Some(ButtonType::Side(modifier_index)) = modifier {
-                                        let channel = sequencer.channel_mut(surface.channel_shown());
-                                        channel.clone_pattern(modifier_index, index);
-                                    } else if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
-                                        surface.set_pattern_offset(sequencer, surface.channel_shown(), 0);
-
-                                        let channel = sequencer.channel_mut(surface.channel_shown());
-                                        channel.pattern_mut(index).clear_events();
-                                    } else {
-                                        surface.show_pattern(surface.channel_shown(), index);
-                                    }
+                                    surface.show_pattern(surface.channel_shown(), surface.bank_index(index));
                                 }
                             },
                             ButtonType::Activator(index) => {
@@ -231,13 +303,35 @@ impl APC for APC40 {
                                     pattern.set_length(length);
                                 }
                             },
+                            ButtonType::Arm(index) => {
+                                let global_modifier = surface.button_memory.global_modifier(button_type);
+
+                                // Shift + arm switches velocity mode, arm selects velocity of new notes
+                                if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
+                                    surface.is_editing_velocity = ! surface.is_editing_velocity;
+                                } else if surface.is_editing_velocity {
+                                    surface.set_input_velocity_level(index);
+                                }
+                            },
+                            // Shift + arrows undo, redo & switch banks, handled independent of view
+                            ButtonType::Up | ButtonType::Down | ButtonType::Right | ButtonType::Left
+                                if surface.button_memory.is_pressed(ButtonType::Shift) => (),
+                            // Don't scroll grid past the notes instrument plays
                             ButtonType::Up => {
                                 let base_note = surface.pattern_base_note(surface.channel_shown());
-                                surface.set_pattern_base_note(surface.channel_shown(), base_note + 4);
+                                let (_, highest_note) = sequencer.channel(surface.channel_shown()).note_range();
+
+                                if base_note + 2 <= highest_note {
+                                    surface.set_pattern_base_note(surface.channel_shown(), base_note + 4);
+                                }
                             },
                             ButtonType::Down => {
                                 let base_note = surface.pattern_base_note(surface.channel_shown());
-                                surface.set_pattern_base_note(surface.channel_shown(), base_note - 4) 
+                                let (lowest_note, _) = sequencer.channel(surface.channel_shown()).note_range();
+
+                                if base_note >= lowest_note + 2 {
+                                    surface.set_pattern_base_note(surface.channel_shown(), base_note - 4);
+                                }
                             },
                             ButtonType::Right => {
                                 let ticks_per_button = self.loopable_ticks_per_button(surface);
@@ -253,9 +347,16 @@ impl APC for APC40 {
 
                                 surface.set_pattern_offset(sequencer, surface.channel_shown(), offset);
                             },
+                            // Switch quantizing of recorded & drawn notes, shift + quantization
+                            // quantizes shown pattern
                             ButtonType::Quantization => {
-                                // TODO - Move quantizing & quantize_level to "keyboard"
-                                //sequencer.switch_quantizing();
+                                if surface.button_memory.is_pressed(ButtonType::Shift) {
+                                    let quantizer = sequencer.keyboard.quantizer;
+                                    quantizer.quantize_pattern(self.shown_loopable_mut(sequencer, surface));
+                                } else {
+                                    sequencer.keyboard.is_quantizing = ! sequencer.keyboard.is_quantizing;
+                                    sequencer.notify(Notice::Quantizing(sequencer.keyboard.is_quantizing));
+                                }
                             },
                             _ => (),
                         }
@@ -264,24 +365,48 @@ impl APC for APC40 {
                 }
 
                 match button_type {
+                    ButtonType::Left if surface.button_memory.is_pressed(ButtonType::Shift) => sequencer.undo(),
+                    ButtonType::Right if surface.button_memory.is_pressed(ButtonType::Shift) => sequencer.redo(),
+                    ButtonType::Up if surface.button_memory.is_pressed(ButtonType::Shift) => surface.switch_bank(1),
+                    ButtonType::Down if surface.button_memory.is_pressed(ButtonType::Shift) => surface.switch_bank(-1),
+                    ButtonType::TapTempo => {
+                        if let Some(beats_per_minute) = surface.tap_tempo.tap(cycle.time_at_frame(event.time)) {
+                            sequencer.set_beats_per_minute(cycle.tick_range.start, beats_per_minute);
+                        }
+                    },
+                    ButtonType::NudgeDown => {
+                        let tick = cycle.tick_range.start;
+                        sequencer.set_beats_per_minute(tick, sequencer.beats_per_minute(tick) - 1.0);
+                    },
+                    ButtonType::NudgeUp => {
+                        let tick = cycle.tick_range.start;
+                        sequencer.set_beats_per_minute(tick, sequencer.beats_per_minute(tick) + 1.0);
+                    },
+                    // Record keyboard into shown pattern, shift + record switches overdub / replace
+                    ButtonType::Record => {
+                        if surface.button_memory.is_pressed(ButtonType::Shift) {
+                            sequencer.keyboard.switch_record_mode();
+                            sequencer.notify(Notice::RecordMode(sequencer.keyboard.record_mode()));
+                        } else {
+                            sequencer.keyboard.switch_recording();
+                        }
+                    },
                     ButtonType::Play => sequencer.start(cycle),
                     ButtonType::Stop => {
                         // Reset to 0 when we press stop button but we're already stopped
-                        let (state, pos) = cycle.client.transport_query();
-                        let is_transport_at_start = pos.bar == 1 && pos.beat == 1 && pos.tick == 0;
+                        let is_transport_at_start = cycle.tick_range.start == 0;
                         let global_modifier = surface.button_memory.global_modifier(button_type);
 
                         // Reset timeline when we shift press stop @ 0:0:0
                         if let (Some(ButtonPress { button_type: ButtonType::Shift, .. }), true) = (global_modifier, is_transport_at_start) {
                             sequencer.reset_timeline();
                         } else {
-                            match state {
-                                1 => sequencer.stop(cycle),
-                                _ => {
-                                    sequencer.reset(cycle);
-                                    surface.set_timeline_offset(sequencer, 0);
-                                },
-                            };
+                            if cycle.is_rolling {
+                                sequencer.stop(cycle);
+                            } else {
+                                sequencer.reset(cycle);
+                                surface.set_timeline_offset(sequencer, 0);
+                            }
                         }
                     },
                     _ => (),
@@ -292,16 +417,44 @@ impl APC for APC40 {
     }
 
     fn draw(&mut self, sequencer: &mut Sequencer, surface: &mut Surface) {
+        // Show knob values of shown channel, setting a ring also moves the knob to that value
+        let channel = sequencer.channel(surface.channel_shown());
+        for index in 0 .. self.knob_rings.width() {
+            let value = channel.knob_value(Channel::KNOB_CONTROLLER_OFFSET + index);
+
+            if self.knob_rings.value(index) != value {
+                self.knob_takeover.set_value(index, value);
+            }
+            self.knob_rings.draw(index, value);
+        }
+
         match surface.view {
             View::Channel => {
-                let loopable = self.shown_loopable_mut(sequencer, surface);
+                let loopable = self.shown_loopable(sequencer, surface);
 
                 // Get base note of channel, as we draw the grid with base note in vertical center
                 let base_note = surface.pattern_base_note(surface.channel_shown());
                 let events = loopable.events().iter()
                     .filter(|event| event.note >= base_note - 2 && event.note <= base_note + 2);
 
-                self.draw_loopable_events(events, surface.pattern_offset(surface.channel_shown()), base_note - 2, self.loopable_ticks_in_grid(surface), Self::HEAD_COLOR, Self::TAIL_COLOR);
+                let offset = surface.pattern_offset(surface.channel_shown());
+                let ticks_in_grid = self.loopable_ticks_in_grid(surface);
+                self.draw_loopable_events(events.clone(), offset, base_note - 2, ticks_in_grid, Self::HEAD_COLOR, Self::TAIL_COLOR);
+
+                // Color heads of notes by velocity & show input velocity level on arm row
+                if surface.is_editing_velocity {
+                    let ticks_per_button = ticks_in_grid / 8;
+
+                    for event in events.filter(|event| event.start() >= offset) {
+                        let x = (event.start() - offset) / ticks_per_button;
+                        self.grid.try_draw(x as i32, event.row(base_note - 2), Self::velocity_color(event.start_velocity));
+                    }
+
+                    let default_velocity = sequencer.channel(surface.channel_shown()).default_velocity();
+                    for index in 0 ..= surface.input_velocity_level(default_velocity) {
+                        self.arm.draw(index, 1);
+                    }
+                }
 
                 // pattern length selector
                 if loopable.has_explicit_length() {
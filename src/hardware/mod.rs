@@ -1,4 +1,5 @@
 
+pub mod apc;
 pub mod apc40;
 pub mod apc20;
 
@@ -0,0 +1,196 @@
+
+use std::collections::VecDeque;
+use super::project::ProjectChange;
+
+// Part of the project a change is about
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Part {
+    Pattern(usize, usize),
+    Phrase(usize, usize),
+    Timeline(usize),
+    Groove(usize),
+    Routing(usize),
+    Instrument(usize),
+    Sequence(usize),
+    TempoMap,
+    Mixer,
+}
+
+impl Part {
+    fn of(change: &ProjectChange) -> Self {
+        match change {
+            ProjectChange::Pattern(channel, index, _) => Part::Pattern(*channel, *index),
+            ProjectChange::Phrase(channel, index, _) => Part::Phrase(*channel, *index),
+            ProjectChange::Timeline(channel, _) => Part::Timeline(*channel),
+            ProjectChange::Groove(channel, _) => Part::Groove(*channel),
+            ProjectChange::Routing(channel, _) => Part::Routing(*channel),
+            ProjectChange::Instrument(channel, _) => Part::Instrument(*channel),
+            ProjectChange::Sequence(index, _) => Part::Sequence(*index),
+            ProjectChange::TempoMap(_) => Part::TempoMap,
+            ProjectChange::Mixer(_) => Part::Mixer,
+        }
+    }
+}
+
+/*
+ * Parts of the project as they were before an edit changed them. Only the first state of a part is
+ * kept, so parts are only copied the first time an edit touches them
+ */
+pub struct Edit {
+    changes: Vec<ProjectChange>,
+}
+
+impl Edit {
+    pub fn new() -> Self {
+        Edit { changes: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool { self.changes.is_empty() }
+
+    fn is_remembered(&self, part: Part) -> bool {
+        self.changes.iter().any(|change| Part::of(change) == part)
+    }
+
+    pub fn remember(&mut self, part: Part, state: impl FnOnce() -> ProjectChange) {
+        if ! self.is_remembered(part) {
+            self.changes.push(state());
+        }
+    }
+
+    // Take over parts other edit remembered that we did not
+    pub fn merge(&mut self, other: Edit) {
+        for change in other.changes {
+            if ! self.is_remembered(Part::of(&change)) {
+                self.changes.push(change);
+            }
+        }
+    }
+}
+
+/*
+ * Undo & redo stacks of edits. An edit holds the parts of the project it changed as they were
+ * before it changed them, oldest edits are forgotten when we remember too many
+ */
+pub struct History {
+    undo: VecDeque<Vec<ProjectChange>>,
+    redo: Vec<Vec<ProjectChange>>,
+    // Parts changed by the edit we're making right now
+    edit: Option<Edit>,
+}
+
+impl History {
+    pub const MAX_EDITS: usize = 100;
+
+    pub fn new() -> Self {
+        History { undo: VecDeque::new(), redo: vec![], edit: None }
+    }
+
+    pub fn begin_edit(&mut self) {
+        self.edit = Some(Edit::new());
+    }
+
+    // Remember part as it was before edit changed it
+    pub fn remember(&mut self, part: Part, state: impl FnOnce() -> ProjectChange) {
+        if let Some(edit) = &mut self.edit {
+            edit.remember(part, state);
+        }
+    }
+
+    // Parts other parts of the sequencer remembered while we were editing
+    pub fn merge(&mut self, other: Edit) {
+        if let Some(edit) = &mut self.edit {
+            edit.merge(other);
+        }
+    }
+
+    // Edits that changed something can be undone, they make redoing impossible
+    pub fn end_edit(&mut self) {
+        if let Some(edit) = self.edit.take().filter(|edit| ! edit.is_empty()) {
+            self.push_undo(edit.changes);
+            self.redo.clear();
+        }
+    }
+
+    pub fn push_undo(&mut self, edit: Vec<ProjectChange>) {
+        self.undo.push_back(edit);
+
+        if self.undo.len() > Self::MAX_EDITS {
+            self.undo.pop_front();
+        }
+    }
+
+    pub fn push_redo(&mut self, edit: Vec<ProjectChange>) {
+        self.redo.push(edit);
+    }
+
+    pub fn take_undo(&mut self) -> Option<Vec<ProjectChange>> { self.undo.pop_back() }
+    pub fn take_redo(&mut self) -> Option<Vec<ProjectChange>> { self.redo.pop() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sequence::Sequence;
+
+    fn edit(history: &mut History, changes: Vec<ProjectChange>) {
+        history.begin_edit();
+        changes.into_iter().for_each(|change| history.remember(Part::of(&change), || change));
+        history.end_edit();
+    }
+
+    #[test]
+    fn remember() {
+        let mut history = History::new();
+        history.remember(Part::Sequence(0), || ProjectChange::Sequence(0, Sequence::new(0)));
+        assert!(history.take_undo().is_none());
+
+        edit(&mut history, vec![
+            ProjectChange::Sequence(0, Sequence::new(1)),
+            ProjectChange::Sequence(0, Sequence::new(2)),
+            ProjectChange::Sequence(1, Sequence::new(3)),
+        ]);
+        edit(&mut history, vec![]);
+
+        let edit = history.take_undo().unwrap();
+        assert!(matches!(edit.as_slice(), [ProjectChange::Sequence(0, first), ProjectChange::Sequence(1, _)] if first.get_phrase(0) == Some(1)));
+        assert!(history.take_undo().is_none());
+    }
+
+    #[test]
+    fn merge() {
+        let mut copies = 0;
+        let mut edit = Edit::new();
+        for _ in 0 .. 3 {
+            edit.remember(Part::Sequence(0), || { copies += 1; ProjectChange::Sequence(0, Sequence::new(1)) });
+        }
+        assert_eq!(copies, 1);
+
+        let mut other = Edit::new();
+        other.remember(Part::Sequence(0), || ProjectChange::Sequence(0, Sequence::new(2)));
+        other.remember(Part::Sequence(1), || ProjectChange::Sequence(1, Sequence::new(3)));
+        edit.merge(other);
+
+        assert!(matches!(edit.changes.as_slice(), [ProjectChange::Sequence(0, first), ProjectChange::Sequence(1, _)] if first.get_phrase(0) == Some(1)));
+    }
+
+    #[test]
+    fn bounded() {
+        let mut history = History::new();
+        (0 .. History::MAX_EDITS + 10).for_each(|index| edit(&mut history, vec![ProjectChange::Sequence(index, Sequence::new(0))]));
+
+        assert!(matches!(history.take_undo().unwrap().as_slice(), [ProjectChange::Sequence(index, _)] if *index == History::MAX_EDITS + 9));
+        assert_eq!(history.undo.len(), History::MAX_EDITS - 1);
+    }
+
+    #[test]
+    fn redo() {
+        let mut history = History::new();
+        history.push_redo(vec![ProjectChange::Sequence(0, Sequence::new(0))]);
+        edit(&mut history, vec![]);
+        assert!(history.take_redo().is_some());
+
+        history.push_redo(vec![ProjectChange::Sequence(0, Sequence::new(0))]);
+        edit(&mut history, vec![ProjectChange::Sequence(0, Sequence::new(0))]);
+        assert!(history.take_redo().is_none());
+    }
+}
@@ -1,4 +1,132 @@
 
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use super::project::{invalid, parse};
+use super::sequencer::Sequencer;
+
+/*
+ * Synth or drum rack we play. Instruments are described once in an instruments file, channels of
+ * any project refer to them by name. Files are line based like project files:
+ *
+ * instrument <name>
+ * output <port> <midi channel>
+ * range <lowest note> <highest note>
+ * velocity <default velocity>
+ * program <program> [<bank msb> <bank lsb>]
+ * note <note> <name>
+ */
+#[derive(Debug, Clone, PartialEq)]
 pub struct Instrument {
+    pub name: String,
+    pub port: usize,
+    pub midi_channel: u8,
+    // Notes the instrument responds to
+    pub lowest_note: u8,
+    pub highest_note: u8,
+    // Velocity of notes we draw for this instrument
+    pub default_velocity: u8,
+    // Program & bank select sent when instrument is put on a channel
+    pub program: Option<u8>,
+    pub bank: Option<(u8, u8)>,
+    // Drum map, names of the sounds under notes
+    pub note_names: Vec<(u8, String)>,
+}
+
+impl Instrument {
+    pub fn new(name: &str, port: usize, midi_channel: u8) -> Self {
+        Instrument {
+            name: String::from(name),
+            port,
+            midi_channel,
+            lowest_note: 0,
+            highest_note: 127,
+            default_velocity: 127,
+            program: None,
+            bank: None,
+            note_names: vec![],
+        }
+    }
+
+    pub fn note_name(&self, note: u8) -> Option<&str> {
+        self.note_names.iter()
+            .find(|(named_note, _)| *named_note == note)
+            .map(|(_, name)| name.as_str())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Vec<Self>> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read(reader: impl BufRead) -> io::Result<Vec<Self>> {
+        let mut instruments: Vec<Instrument> = vec![];
+
+        for line in reader.lines() {
+            let line = line?;
+            let mut values = line.split_whitespace();
+            let keyword = values.next();
+
+            if keyword == Some("instrument") {
+                let name = values.next().ok_or_else(|| invalid("instrument without name"))?;
+                instruments.push(Instrument::new(name, 0, 0));
+                continue;
+            }
+
+            let instrument = match (keyword, instruments.last_mut()) {
+                (None, _) => continue,
+                (Some(_), Some(instrument)) => instrument,
+                (Some(_), None) => return Err(invalid("line outside of instrument")),
+            };
+
+            match keyword {
+                Some("output") => {
+                    instrument.port = parse(values.next())?;
+                    instrument.midi_channel = parse(values.next())?;
+
+                    if instrument.port >= Sequencer::OUTPUTS || instrument.midi_channel >= 16 {
+                        return Err(invalid(&format!("output {} {} does not exist", instrument.port, instrument.midi_channel)));
+                    }
+                },
+                Some("range") => {
+                    instrument.lowest_note = parse(values.next())?;
+                    instrument.highest_note = parse(values.next())?;
+                },
+                Some("velocity") => instrument.default_velocity = parse(values.next())?,
+                Some("program") => {
+                    instrument.program = Some(parse(values.next())?);
+                    instrument.bank = match values.next() {
+                        Some(msb) => Some((parse(Some(msb))?, parse(values.next())?)),
+                        None => None,
+                    };
+                },
+                Some("note") => {
+                    let note = parse(values.next())?;
+                    instrument.note_names.push((note, values.collect::<Vec<&str>>().join(" ")));
+                },
+                _ => (),
+            }
+        }
+
+        Ok(instruments)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read() {
+        let file = "instrument drums\noutput 2 9\nrange 36 51\nvelocity 100\nprogram 4 0 1\nnote 36 kick\nnote 38 snare drum\n\ninstrument bass\n";
+        let instruments = Instrument::read(io::Cursor::new(file)).unwrap();
+
+        let drums = &instruments[0];
+        assert_eq!((drums.port, drums.midi_channel, drums.lowest_note, drums.highest_note), (2, 9, 36, 51));
+        assert_eq!((drums.default_velocity, drums.program, drums.bank), (100, Some(4), Some((0, 1))));
+        assert_eq!((drums.note_name(38), drums.note_name(40)), (Some("snare drum"), None));
+        assert_eq!(instruments[1], Instrument::new("bass", 0, 0));
+
+        assert!(Instrument::read(io::Cursor::new("output 0 0\n")).is_err());
+        assert!(Instrument::read(io::Cursor::new("instrument synth\noutput 0 16\n")).is_err());
+    }
+}
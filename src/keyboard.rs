@@ -0,0 +1,145 @@
+
+use super::TickRange;
+use super::cycle::ProcessCycle;
+use super::loopable::*;
+use super::events::*;
+use super::quantize::Quantizer;
+use super::port::MidiIn;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordMode {
+    // Add recorded notes to the notes in pattern
+    Overdub,
+    // Remove notes we pass while recording
+    Replace,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyboardEvent {
+    NoteOn { frame: u32, note: u8, velocity: u8 },
+    NoteOff { frame: u32, note: u8, velocity: u8 },
+}
+
+/*
+ * Midi keyboards & drumpads share the keyboard_in port, their notes are played on & recorded into
+ * the shown channel
+ */
+pub struct Keyboard {
+    input: MidiIn,
+
+    is_recording: bool,
+    record_mode: RecordMode,
+    // Quantize recorded & drawn notes
+    pub is_quantizing: bool,
+    pub quantizer: Quantizer,
+
+    // Note, pattern tick & velocity of notes that are held down
+    held_notes: Vec<(u8, u32, u8)>,
+    // Pattern tick & note of notes recorded since recording started, replace mode keeps these
+    recorded_notes: Vec<(u32, u8)>,
+}
+
+impl Keyboard {
+    // Note off velocity when note off is sent as note on with velocity 0
+    const RELEASE_VELOCITY: u8 = 64;
+
+    pub fn new(client: Option<&jack::Client>) -> Self {
+        Keyboard {
+            input: MidiIn::new(client, "keyboard_in"),
+            is_recording: false,
+            record_mode: RecordMode::Overdub,
+            is_quantizing: false,
+            quantizer: Quantizer::new(16, false, 100),
+            held_notes: vec![],
+            recorded_notes: vec![],
+        }
+    }
+
+    pub fn is_recording(&self) -> bool { self.is_recording }
+    pub fn record_mode(&self) -> RecordMode { self.record_mode }
+
+    pub fn switch_recording(&mut self) {
+        self.is_recording = ! self.is_recording;
+        self.held_notes.clear();
+        self.recorded_notes.clear();
+    }
+
+    pub fn switch_record_mode(&mut self) {
+        self.record_mode = match self.record_mode {
+            RecordMode::Overdub => RecordMode::Replace,
+            RecordMode::Replace => RecordMode::Overdub,
+        };
+    }
+
+    // Notes of all midi channels, note on with velocity 0 is a note off
+    pub fn event(frame: u32, bytes: &[u8]) -> Option<KeyboardEvent> {
+        match *bytes {
+            [0x90 ..= 0x9F, note, 0] => Some(KeyboardEvent::NoteOff { frame, note, velocity: Self::RELEASE_VELOCITY }),
+            [0x90 ..= 0x9F, note, velocity] => Some(KeyboardEvent::NoteOn { frame, note, velocity }),
+            [0x80 ..= 0x8F, note, velocity] => Some(KeyboardEvent::NoteOff { frame, note, velocity }),
+            _ => None,
+        }
+    }
+
+    pub fn events(&self, cycle: &ProcessCycle) -> Vec<KeyboardEvent> {
+        self.input.messages(cycle).into_iter()
+            .filter_map(|message| Self::event(message.time, message.bytes))
+            .collect()
+    }
+
+    pub fn is_held(&self, note: u8) -> bool {
+        self.held_notes.iter().any(|(held_note, _, _)| *held_note == note)
+    }
+
+    pub fn note_on(&mut self, tick: u32, note: u8, velocity: u8) {
+        self.held_notes.retain(|(held_note, _, _)| *held_note != note);
+        self.held_notes.push((note, tick, velocity));
+    }
+
+    // Add released note to pattern
+    pub fn note_off(&mut self, pattern: &mut Pattern, tick: u32, note: u8, velocity: u8) {
+        if let Some(index) = self.held_notes.iter().position(|(held_note, _, _)| *held_note == note) {
+            let (_, start, start_velocity) = self.held_notes.remove(index);
+
+            // Stop before start is a note looping around pattern end, a note can't be 0 ticks long
+            let mut event = LoopableNoteEvent::new(start, note, start_velocity);
+            event.set_stop(if tick == start { tick + 1 } else { tick });
+            event.stop_velocity = Some(velocity);
+
+            if self.is_quantizing {
+                self.quantizer.quantize_note(&mut event, pattern.length);
+            }
+
+            pattern.add_complete_event(event);
+            self.recorded_notes.push((event.start, note));
+        }
+    }
+
+    // Notes starting in range of pattern we're passing are replaced, except the ones we're recording
+    fn is_replaced(&self, event: &LoopableNoteEvent, range: &TickRange) -> bool {
+        range.contains(event.start)
+            && ! self.recorded_notes.contains(&(event.start, event.note))
+            && ! self.held_notes.iter().any(|(note, start, _)| (*start, *note) == (event.start, event.note))
+    }
+
+    pub fn replaces(&self, pattern: &Pattern, range: &TickRange) -> bool {
+        pattern.note_events.iter().any(|event| self.is_replaced(event, range))
+    }
+
+    pub fn replace(&self, pattern: &mut Pattern, range: &TickRange) {
+        pattern.note_events.retain(|event| ! self.is_replaced(event, range));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event() {
+        assert_eq!(Keyboard::event(1, &[0x91, 60, 100]), Some(KeyboardEvent::NoteOn { frame: 1, note: 60, velocity: 100 }));
+        assert_eq!(Keyboard::event(2, &[0x91, 60, 0]), Some(KeyboardEvent::NoteOff { frame: 2, note: 60, velocity: 64 }));
+        assert_eq!(Keyboard::event(3, &[0x81, 60, 10]), Some(KeyboardEvent::NoteOff { frame: 3, note: 60, velocity: 10 }));
+        assert_eq!(Keyboard::event(4, &[0xB0, 7, 10]), None);
+    }
+}
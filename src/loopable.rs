@@ -2,6 +2,7 @@
 use super::TickRange;
 use super::events::*;
 use super::TimebaseHandler;
+use super::groove::Groove;
 
 pub trait Loopable {
     type Event: LoopableEvent;
@@ -133,6 +134,8 @@ pub struct Phrase {
     // Length in ticks
     length: u32,
     pub pattern_events: Vec<LoopablePatternEvent>,
+    // Recorded knob movements, sorted by tick
+    pub control_events: Vec<ControlEvent>,
 }
 
 impl Loopable for Phrase {
@@ -145,7 +148,7 @@ impl Loopable for Phrase {
 
 impl Phrase {
     pub fn new() -> Self {
-        Phrase { length: Self::default_length(), pattern_events: vec![] }
+        Phrase { length: Self::default_length(), pattern_events: vec![], control_events: vec![] }
     }
 
     // Default phrase length = 4 bars
@@ -153,10 +156,11 @@ impl Phrase {
     pub fn set_length(&mut self, length: u32) { 
         self.length = length; 
 
-        // Remove pattern events that start outside of length
+        // Remove pattern & control events that start outside of length
         self.pattern_events.retain(|event| {
             event.start() < length
         });
+        self.control_events.retain(|event| event.tick < length);
 
         // Cut pattern events short that start within length but stop after 
         self.pattern_events.iter_mut().for_each(|mut event| {
@@ -167,12 +171,37 @@ impl Phrase {
             }
         });
     }
+
+    // Overdub value of controller at tick, replacing value recorded at same tick
+    pub fn record_control(&mut self, tick: u32, controller: u8, value: u8) {
+        self.control_events.retain(|event| event.tick != tick || event.controller != controller);
+
+        let index = self.control_events.iter().position(|event| event.tick > tick).unwrap_or(self.control_events.len());
+        self.control_events.insert(index, ControlEvent::new(tick, controller, value));
+    }
+
+    pub fn clear_control_lane(&mut self, controller: u8) {
+        self.control_events.retain(|event| event.controller != controller);
+    }
+
+    // Control events in range relative to start of phrase, offset by tick phrase starts at
+    pub fn control_events(&self, range: &TickRange, phrase_start: u32) -> Vec<ControlEvent> {
+        self.looping_ranges(range).into_iter()
+            .flat_map(|(range, offset)| {
+                self.control_events.iter()
+                    .filter(move |event| range.contains(event.tick))
+                    .map(move |event| ControlEvent::new(phrase_start + offset + event.tick, event.controller, event.value))
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone)]
 pub struct Pattern {
     pub note_events: Vec<LoopableNoteEvent>,
     pub length: Option<u32>,
+    // Overrides groove of channel
+    pub groove: Option<Groove>,
 }
 
 impl Loopable for Pattern {
@@ -203,7 +232,7 @@ impl Pattern {
     pub fn minimum_length() -> u32 { TimebaseHandler::TICKS_PER_BEAT as u32 * 4 }
 
     pub fn new() -> Self {
-        Pattern { note_events: vec![], length: None }
+        Pattern { note_events: vec![], length: None, groove: None }
     }
 
     pub fn has_explicit_length(&self) -> bool {
@@ -218,6 +247,24 @@ impl Pattern {
         self.length = Some(length);
     }
 
+    // Remove notes starting in range, add note spanning range when there are none
+    pub fn toggle_note(&mut self, range: TickRange, note: u8, velocity: u8) {
+        if self.contains_events_starting_in(range, note) {
+            self.remove_events_starting_in(range, note);
+        } else {
+            let mut event = LoopableNoteEvent::new(range.start, note, velocity);
+            event.set_stop(range.stop);
+            event.stop_velocity = Some(velocity);
+            self.add_complete_event(event);
+        }
+    }
+
+    pub fn set_velocity_starting_in(&mut self, range: TickRange, note: u8, velocity: u8) {
+        self.note_events.iter_mut()
+            .filter(|event| event.is_on_row(note) && range.contains(event.start()))
+            .for_each(|event| event.start_velocity = velocity);
+    }
+
     pub fn starting_notes(&self, absolute_start: u32, relative_range: TickRange, pattern_event_length: u32) 
         -> Vec<PlayingNoteEvent> 
     {
@@ -277,4 +324,42 @@ mod tests {
         pattern.add_complete_event(event);
         assert_eq!(pattern.length(), length * 4);
     }
+    #[test]
+    fn set_velocity_starting_in() {
+        let mut pattern = Pattern::new();
+
+        for start in &[0, 100] {
+            let mut event = LoopableNoteEvent::new(*start, 1, 127);
+            event.set_stop(start + 10);
+            pattern.add_complete_event(event);
+        }
+
+        pattern.set_velocity_starting_in(TickRange::new(0, 50), 1, 64);
+        pattern.set_velocity_starting_in(TickRange::new(100, 150), 2, 32);
+
+        assert_eq!(pattern.note_events[0].start_velocity, 64);
+        assert_eq!(pattern.note_events[1].start_velocity, 127);
+    }
+    #[test]
+    fn control_events() {
+        let mut phrase = Phrase::new();
+        let length = phrase.length();
+
+        phrase.record_control(100, 20, 1);
+        phrase.record_control(10, 20, 2);
+        phrase.record_control(100, 20, 3);
+        phrase.record_control(50, 21, 4);
+
+        let ticks: Vec<u32> = phrase.control_events.iter().map(|event| event.tick).collect();
+        assert_eq!(ticks, vec![10, 50, 100]);
+        assert_eq!(phrase.control_events[2].value, 3);
+
+        // Looping range gets events of phrase end & start
+        let events = phrase.control_events(&TickRange::new(length - 10, length + 60), 1000);
+        let ticks: Vec<u32> = events.iter().map(|event| event.tick).collect();
+        assert_eq!(ticks, vec![1000 + length + 10, 1000 + length + 50]);
+
+        phrase.clear_control_lane(20);
+        assert_eq!(phrase.control_events.len(), 1);
+    }
 }
@@ -21,38 +21,67 @@ pub mod events;
 pub mod instrument;
 pub mod router;
 pub mod tickrange;
-
-// TODO - Save & load state on restart
-//use std::io;
+pub mod project;
+pub mod storage;
+pub mod command;
+pub mod action;
+pub mod smf;
+pub mod tempo;
+pub mod options;
+pub mod clock;
+pub mod keyboard;
+pub mod quantize;
+pub mod groove;
+pub mod history;
+pub mod transport;
+pub mod render;
+pub mod mirror;
+pub mod osc;
+pub mod tui;
+pub mod notice;
+
+use std::env;
+use std::thread;
+use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Sender, Receiver};
 use sequencer::Sequencer;
 use controller::*;
 use hardware::*;
-use surface::Surface;
+use surface::{Surface, View};
 use cycle::*;
 use router::*;
 use tickrange::*;
+use project::Project;
+use storage::*;
+use command::*;
+use action::Action;
+use tempo::TempoMap;
+use options::{Options, SyncSource};
+use instrument::Instrument;
+use mirror::Mirror;
+use osc::OscServer;
+use tui::Tui;
+use notice::Notice;
 
 pub struct TimebaseHandler {
-    beats_per_minute: f64,
-    beats_per_bar: f32,
-    beat_type: f32,
-    is_up_to_date: bool,
+    tempo_map: TempoMap,
+    // Seconds the tempo map is shifted by, this changes when the tempo map changes while we're
+    // playing, so that changing tempo does not make the transport jump
+    seconds_offset: f64,
 
-    //receiver: Receiver<f64>,
+    receiver: Receiver<TempoMap>,
 }
 
 impl TimebaseHandler {
     pub const TICKS_PER_BEAT: f64 = 1920.0;
+    pub const DEFAULT_BEATS_PER_MINUTE: f64 = 138.0;
 
-    pub fn new(_: Receiver<f64>) -> Self {
+    pub fn new(receiver: Receiver<TempoMap>) -> Self {
         TimebaseHandler {
-            beats_per_minute: 138.0,
-            is_up_to_date: false,
-            beats_per_bar: 4.0,
-            beat_type: 4.0,
-            //receiver,
+            tempo_map: TempoMap::new(Self::DEFAULT_BEATS_PER_MINUTE, 4, 4),
+            seconds_offset: 0.0,
+            receiver,
         }
     }
 }
@@ -60,32 +89,36 @@ impl TimebaseHandler {
 impl jack::TimebaseHandler for TimebaseHandler {
     fn timebase(&mut self, _: &jack::Client, _state: jack::TransportState, _n_frames: jack::Frames, pos: *mut jack::Position, is_new_pos: bool) {
         unsafe {
+            let seconds = (*pos).frame as f64 / (*pos).frame_rate as f64;
+
             // Set position type
             (*pos).valid = j::JackPositionBBT;
 
-            // BPM changed?
-            //if ! is_new_pos && (*pos).beats_per_minute != self.beats_per_minute {
-                //println!("{:?}", (*pos).beats_per_minute);
-            //}
-
-            // Only update timebase when we are asked for it, or when our state changed
-            if is_new_pos || ! self.is_up_to_date {
-                (*pos).beats_per_bar = self.beats_per_bar;
-                (*pos).ticks_per_beat = Self::TICKS_PER_BEAT;
-                (*pos).beat_type = self.beat_type;
-                (*pos).beats_per_minute = self.beats_per_minute;
+            // Relocating puts us on the tempo map again
+            if is_new_pos {
+                self.seconds_offset = 0.0;
+            }
 
-                self.is_up_to_date = true;
+            // Tempo map changed? Continue from the tick we're at using the new map
+            while let Ok(tempo_map) = self.receiver.try_recv() {
+                let tick = self.tempo_map.tick_at_seconds(seconds - self.seconds_offset);
+                self.seconds_offset = seconds - tempo_map.seconds_at_tick(tick);
+                self.tempo_map = tempo_map;
             }
 
-            let abs_tick = ProcessCycle::frame_to_tick(*pos, (*pos).frame);
-            let abs_beat = abs_tick / (*pos).ticks_per_beat;
+            let abs_tick = self.tempo_map.tick_at_seconds(seconds - self.seconds_offset).max(0.0) as u32;
+            let position = self.tempo_map.bar_beat_tick(abs_tick);
+
+            (*pos).ticks_per_beat = Self::TICKS_PER_BEAT;
+            (*pos).beats_per_bar = position.beats_per_bar as f32;
+            (*pos).beat_type = position.beat_type as f32;
+            (*pos).beats_per_minute = self.tempo_map.beats_per_minute_at(abs_tick);
 
             // Plus 1 as humans tend not to count from 0
-            (*pos).bar = (abs_beat / (*pos).beats_per_bar as f64) as i32 + 1;
-            (*pos).beat = (abs_beat % (*pos).beats_per_bar as f64) as i32 + 1;
-            (*pos).bar_start_tick = (abs_beat as i32 * (*pos).ticks_per_beat as i32) as f64;
-            (*pos).tick = abs_tick as i32 - (*pos).bar_start_tick as i32;
+            (*pos).bar = position.bar as i32 + 1;
+            (*pos).beat = position.beat as i32 + 1;
+            (*pos).bar_start_tick = position.bar_start_tick as f64;
+            (*pos).tick = position.tick as i32;
         }
     }
 }
@@ -95,28 +128,152 @@ pub struct ProcessHandler {
     // Controllers
     apc20: APC20,
     apc40: APC40,
+    // Controllers shown in terminal
+    mirror: Option<Mirror>,
 
-    //mixer: Mixer,
     sequencer: Sequencer,
     surface: Surface,
 
     introduction_receiver: Receiver<(jack::Port<jack::Unowned>, bool)>,
+    action_receiver: Receiver<Action>,
+    storage_sender: Sender<StorageRequest>,
+    notice_sender: Sender<Notice>,
+    project_path: PathBuf,
+    last_autosave: u64,
+    // Tick range of last rolling cycle, used to continue exactly where we left off
+    tick_range: Option<TickRange>,
 }
 
 impl ProcessHandler {
+    const AUTOSAVE_USECS: u64 = 30000000;
+
     pub fn new(
         introduction_receiver: Receiver<(jack::Port<jack::Unowned>, bool)>,
-        _timebase_sender: Sender<f64>,
+        timebase_sender: Sender<TempoMap>,
+        action_receiver: Receiver<Action>,
+        storage_sender: Sender<StorageRequest>,
+        notice_sender: Sender<Notice>,
+        project_path: PathBuf,
+        project: Option<Project>,
+        instruments: Vec<Instrument>,
+        sync_source: SyncSource,
+        mirror: Option<Mirror>,
         client: &jack::Client
     ) -> Self {
+        let mut sequencer = Sequencer::new(Some(client), timebase_sender, sync_source);
+        sequencer.set_instruments(instruments);
+
+        if let Some(project) = project {
+            sequencer.load_project(project);
+        }
+
         ProcessHandler {
-            apc20: APC20::new(client),
-            apc40: APC40::new(client),
+            apc20: APC20::new(Some(client)),
+            apc40: APC40::new(Some(client)),
+            mirror,
 
-            //mixer: Mixer::new(client),
-            sequencer: Sequencer::new(client),
+            sequencer,
             surface: Surface::new(),
             introduction_receiver,
+            action_receiver,
+            storage_sender,
+            notice_sender,
+            project_path,
+            last_autosave: 0,
+            tick_range: None,
+        }
+    }
+
+    // Hand changed state to storage thread every once in a while
+    fn autosave(&mut self, cycle: &ProcessCycle) {
+        if self.last_autosave == 0 {
+            self.last_autosave = cycle.time_start;
+        } else if cycle.time_start - self.last_autosave >= Self::AUTOSAVE_USECS {
+            self.last_autosave = cycle.time_start;
+            let changes = self.sequencer.take_changes();
+            if ! changes.is_empty() {
+                // Changes are dropped when storage thread crashed, they'd never be written anyway
+                let _ = self.storage_sender.send(StorageRequest::Autosave(changes));
+            }
+        }
+    }
+
+    fn process_action(&mut self, cycle: &ProcessCycle, action: Action) {
+        match action {
+            // Hand a copy of our state to storage thread, so we don't block this thread with file I/O
+            Action::Save(path) => {
+                let path = path.unwrap_or_else(|| self.project_path.clone());
+                // Storage thread is only gone when it crashed, there's nobody left to save then
+                let _ = self.storage_sender.send(StorageRequest::Save(path, self.sequencer.project()));
+                // Saved copy replaces the autosave copy, so our changes are in it already
+                self.sequencer.clear_changes();
+            },
+            // Storage thread renders the copy on a sequencer of it's own
+            Action::Export(path) => {
+                let _ = self.storage_sender.send(StorageRequest::Export(path, self.sequencer.project()));
+            },
+            // Import checked patterns & phrase fit in channel
+            Action::LoadPatterns { channel, pattern, patterns, phrase } => {
+                let channel = self.sequencer.channel_mut(channel);
+
+                for (index, loaded) in patterns.into_iter().enumerate() {
+                    *channel.pattern_mut(pattern + index as u8) = loaded;
+                }
+
+                if let Some((index, phrase)) = phrase {
+                    *channel.phrase_mut(index) = phrase;
+                }
+            },
+            Action::Tempo { bar, beats_per_minute, is_ramp } => self.sequencer.set_tempo(bar, beats_per_minute, is_ramp),
+            Action::Meter { bar, meter } => self.sequencer.set_meter(bar, meter),
+            Action::Crossfade { channel, group } => self.sequencer.set_crossfade_group(channel, group),
+            Action::Groove { channel, pattern, groove } => {
+                let channel = self.sequencer.channel_mut(channel);
+
+                match pattern {
+                    Some(index) if (index as usize) < channel.patterns.len() => channel.pattern_mut(index).groove = groove,
+                    Some(index) => self.sequencer.notify(Notice::PatternMissing(index)),
+                    None => channel.set_groove(groove),
+                }
+            },
+            Action::Output { channel, routing } => self.sequencer.set_routing(channel, routing),
+            Action::Instrument { channel, name } => self.sequencer.set_instrument(channel, name.as_deref()),
+            Action::Quantize(quantizer) => {
+                let keyboard = &mut self.sequencer.keyboard;
+                keyboard.is_quantizing = quantizer.is_some();
+                keyboard.quantizer = quantizer.unwrap_or(keyboard.quantizer);
+            },
+            Action::Play => self.sequencer.start(cycle),
+            Action::Stop => self.sequencer.stop(cycle),
+            Action::Reset => {
+                self.sequencer.reset(cycle);
+                self.surface.set_timeline_offset(&self.sequencer, 0);
+            },
+            Action::ShowChannel(index) => {
+                self.surface.show_channel(index);
+                self.surface.switch_view(View::Channel);
+            },
+            Action::ShowPattern(index) => {
+                self.surface.show_pattern(self.surface.channel_shown(), index);
+                self.surface.switch_view(View::Channel);
+            },
+            Action::ShowPhrase(index) => {
+                self.surface.show_phrase(self.surface.channel_shown(), index);
+                self.surface.switch_view(View::Channel);
+            },
+            Action::ShowSequence(index) => {
+                self.surface.show_sequence(index);
+                self.surface.switch_view(View::Sequence);
+            },
+            Action::Note { channel, pattern, tick_range, note } => {
+                let channel = self.sequencer.channel_mut(channel);
+                let velocity = self.surface.input_velocity(channel.default_velocity());
+                channel.pattern_mut(pattern).toggle_note(tick_range, note, velocity);
+            },
+            Action::PatternLength { channel, pattern, length } => self.sequencer.channel_mut(channel).pattern_mut(pattern).set_length(length),
+            Action::PhraseLength { channel, phrase, length } => self.sequencer.channel_mut(channel).phrase_mut(phrase).set_length(length),
+            Action::QueueSequence(index) => self.sequencer.sequence_queued = Some(index),
+            Action::BeatsPerMinute(beats_per_minute) => self.sequencer.set_beats_per_minute(cycle.tick_range.start, beats_per_minute),
         }
     }
 }
@@ -124,7 +281,8 @@ impl ProcessHandler {
 impl jack::ProcessHandler for ProcessHandler {
     fn process(&mut self, client: &jack::Client, scope: &jack::ProcessScope) -> jack::Control {
         // Get something representing this process cycle
-        let cycle = ProcessCycle::new(client, scope);
+        let cycle = ProcessCycle::new(client, scope, self.tick_range, self.sequencer.tempo_map(), self.sequencer.is_timebase_master());
+        self.tick_range = if cycle.is_rolling { Some(cycle.tick_range) } else { None };
 
         while let Ok((port, _is_registered)) = self.introduction_receiver.try_recv() {
             // TODO - Use is_registered to create & destroy controller structs
@@ -143,8 +301,23 @@ impl jack::ProcessHandler for ProcessHandler {
             }
         }
 
+        // Every action of console & OSC clients can be undone by itself
+        while let Ok(action) = self.action_receiver.try_recv() {
+            self.sequencer.begin_edit();
+            self.process_action(&cycle, action);
+            self.sequencer.end_edit();
+        }
+
+        self.sequencer.process_clock_input(&cycle);
+
         self.apc20.process_midi_input(&cycle, &mut self.sequencer, &mut self.surface);
         self.apc40.process_midi_input(&cycle, &mut self.sequencer, &mut self.surface);
+        if let Some(mirror) = &mut self.mirror {
+            mirror.process_midi_input(&cycle, &mut self.sequencer, &mut self.surface);
+        }
+
+        let channel_shown = self.surface.channel_shown();
+        self.sequencer.process_keyboard_input(&cycle, channel_shown, self.surface.pattern_shown(channel_shown));
 
         if cycle.is_rolling {
             self.sequencer.autoqueue_next_sequence(&cycle);
@@ -152,10 +325,19 @@ impl jack::ProcessHandler for ProcessHandler {
 
         // Sequencer first at it will cache playing notes, these we can use for sequence visualization
         self.sequencer.output_midi(&cycle);
-        //self.mixer.output_midi(&cycle);
 
         self.apc20.output_midi(&cycle, &mut self.sequencer, &mut self.surface);
         self.apc40.output_midi(&cycle, &mut self.sequencer, &mut self.surface);
+        if let Some(mirror) = &mut self.mirror {
+            mirror.output_midi(&cycle, &mut self.sequencer, &mut self.surface);
+        }
+
+        self.autosave(&cycle);
+
+        // Printing thread is only gone when we're shutting down
+        for notice in self.sequencer.take_notices() {
+            let _ = self.notice_sender.send(notice);
+        }
 
         jack::Control::Continue
     }
@@ -188,6 +370,15 @@ impl jack::NotificationHandler for NotificationHandler {
 }
 
 fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("Error: {}", e);
+            println!("Usage: octothorpe [--slave | --clock] [--instruments <path>] [--tui] [--osc <port>] [project]");
+            std::process::exit(1);
+        },
+    };
+
     // Setup client
     let (client, _status) =
         jack::Client::new("octothorpe", jack::ClientOptions::NO_START_SERVER).unwrap();
@@ -195,18 +386,103 @@ fn main() {
     let (timebase_sender, timebase_receiver) = channel();
     let (introduction_send, introduction_receive) = channel();
     let (connection_send, connection_receive) = channel();
+    let (action_send, action_receive) = channel();
+    let (storage_send, storage_receive) = channel();
+    let (notice_send, notice_receive) = channel();
+
+    // Load project we saved before, when there is one
+    let project_path = options.project_path;
+
+    // Offer to restore autosave snapshot when the last session ended with unsaved changes
+    let snapshot_path = Autosave::latest_snapshot(&project_path)
+        .filter(|(modified, path)| {
+            let age = modified.elapsed().map(|age| age.as_secs() / 60).unwrap_or(0);
+            Console::confirm(&format!("Restore autosave snapshot {:?} from {} minutes ago?", path, age))
+        })
+        .map(|(_, path)| path);
+
+    let load_path = snapshot_path.as_ref().unwrap_or(&project_path);
+    let project = if load_path.exists() {
+        match Project::load(load_path) {
+            Ok(project) => Some(project),
+            Err(e) => { println!("Error: could not load project {:?}: {}", load_path, e); None },
+        }
+    } else {
+        None
+    };
+
+    // Instruments are shared between projects
+    let instruments_path = options.instruments_path;
+    let instruments = if instruments_path.exists() {
+        Instrument::load(&instruments_path).unwrap_or_else(|e| {
+            println!("Error: could not load instruments {:?}: {}", instruments_path, e);
+            vec![]
+        })
+    } else {
+        vec![]
+    };
+
+    let autosave = Autosave::new(Autosave::directory(&project_path), project.clone().unwrap_or_else(Project::new));
 
     let mut router = Router::new(connection_receive, introduction_send);
+    let mut storage = Storage::new(storage_receive, autosave);
+    let mut console = Console::new(action_send.clone());
+
+    // Terminal UI & OSC clients show controllers that are mirrored in process thread
+    let (mirror_input_send, mirror_input_receive) = channel();
+    let mut mirror_listeners = vec![];
+    let tui_snapshot_receive = if options.is_tui {
+        let (snapshot_send, snapshot_receive) = channel();
+        mirror_listeners.push(snapshot_send);
+        Some(snapshot_receive)
+    } else {
+        None
+    };
+    // We run without OSC when the port is taken
+    let osc_server = options.osc_port.and_then(|port| {
+        OscServer::bind(port, action_send, mirror_input_send.clone())
+            .map_err(|error| println!("Error: could not listen for OSC on port {}: {}", port, error))
+            .ok()
+    });
+    let osc = osc_server.map(|server| {
+        let (snapshot_send, snapshot_receive) = channel();
+        mirror_listeners.push(snapshot_send);
+        (server, snapshot_receive)
+    });
+    let mirror = if mirror_listeners.is_empty() { None } else { Some(Mirror::new(mirror_input_receive, mirror_listeners)) };
 
     let notificationhandler = NotificationHandler::new(connection_send);
     let timebasehandler = TimebaseHandler::new(timebase_receiver);
-    let processhandler = ProcessHandler::new(introduction_receive, timebase_sender, &client);
+    let processhandler = ProcessHandler::new(introduction_receive, timebase_sender, action_receive, storage_send, notice_send, project_path, project, instruments, options.sync_source, mirror, &client);
 
     // Activate client
     let async_client = client
         .activate_async(notificationhandler, processhandler, timebasehandler)
         .unwrap();
 
+    // Activating registers us as timebase master, give that up again when following another client
+    if options.sync_source == SyncSource::Timebase {
+        unsafe { j::jack_release_timebase(async_client.as_client().raw()); }
+    }
+
+    // Write files & read commands outside of process thread
+    thread::spawn(move || storage.start());
+    thread::spawn(move || {
+        while let Ok(notice) = notice_receive.recv() {
+            println!("{}", notice);
+        }
+    });
+    if let Some((server, snapshot_receive)) = osc {
+        thread::spawn(move || server.start(snapshot_receive));
+    }
+    // Terminal UI reads keys instead of lines, commands are typed in it
+    if let Some(snapshot_receive) = tui_snapshot_receive {
+        let mut tui = Tui::new(snapshot_receive, mirror_input_send, console);
+        thread::spawn(move || tui.start());
+    } else {
+        thread::spawn(move || console.start());
+    }
+
     // Start router that will listen for new ports & handle connections
     router.start(async_client.as_client());
 }
@@ -6,6 +6,11 @@ pub enum Message {
     Introduction([u8; 12]),
     Inquiry([u8; 6]),
     Note([u8; 3]),
+    ControlChange([u8; 3]),
+    ProgramChange([u8; 2]),
+    // Clock, start, stop & continue
+    Realtime([u8; 1]),
+    SongPosition([u8; 3]),
 }
 
 #[derive(Debug, Eq)]
@@ -27,6 +32,14 @@ impl TimedMessage {
                 jack::RawMidi{ time: self.time, bytes: bytes},
             Message::Note(bytes) =>                                                    
                 jack::RawMidi{ time: self.time, bytes: bytes},
+            Message::ControlChange(bytes) =>
+                jack::RawMidi{ time: self.time, bytes: bytes},
+            Message::ProgramChange(bytes) =>
+                jack::RawMidi{ time: self.time, bytes: bytes},
+            Message::Realtime(bytes) =>
+                jack::RawMidi{ time: self.time, bytes: bytes},
+            Message::SongPosition(bytes) =>
+                jack::RawMidi{ time: self.time, bytes: bytes},
         }
     }
 }
@@ -0,0 +1,116 @@
+
+use std::sync::mpsc::{Sender, Receiver};
+use super::controller::Controller;
+use super::controller::input::*;
+use super::controller::emulator::Emulator;
+use super::hardware::{APC20, APC40};
+use super::cycle::ProcessCycle;
+use super::sequencer::Sequencer;
+use super::surface::Surface;
+
+// Notes of the rows of buttons under the grid, from top to bottom
+pub const ROWS: [(u8, &str); 5] = [(0x34, "indicator"), (0x33, "channel"), (0x32, "activator"), (0x31, "solo"), (0x30, "arm")];
+
+/*
+ * Lights of both controllers next to each other, APC20 on the left & APC40 on the right like they
+ * are on the desk
+ */
+#[derive(Clone, PartialEq)]
+pub struct Lights {
+    // Rows are numbered from the bottom like grid buttons are
+    pub grid: [[u8; 16]; 5],
+    pub side: [[u8; 5]; 2],
+    pub rows: [[u8; 16]; 5],
+    pub master: u8,
+}
+
+impl Lights {
+    pub fn new<A: Controller, B: Controller>(left: &Emulator<A>, right: &Emulator<B>) -> Self {
+        let mut lights = Lights { grid: [[0; 16]; 5], side: [left.side(), right.side()], rows: [[0; 16]; 5], master: right.led(0, 0x50) };
+
+        for (y, (left_row, right_row)) in left.grid().iter().zip(right.grid().iter()).enumerate() {
+            lights.grid[y][.. 8].copy_from_slice(left_row);
+            lights.grid[y][8 ..].copy_from_slice(right_row);
+        }
+        for (index, (note, _)) in ROWS.iter().enumerate() {
+            lights.rows[index][.. 8].copy_from_slice(&left.row(*note));
+            lights.rows[index][8 ..].copy_from_slice(&right.row(*note));
+        }
+
+        lights
+    }
+}
+
+/*
+ * What the controllers show & what the sequencer is doing, handed to terminal & OSC clients
+ */
+#[derive(Clone, PartialEq)]
+pub struct Snapshot {
+    pub lights: Lights,
+    pub is_rolling: bool,
+    pub beats_per_minute: f64,
+    pub channel_shown: u8,
+    pub pattern_shown: u8,
+    pub phrase_shown: u8,
+    pub sequence_playing: usize,
+    pub sequence_queued: Option<usize>,
+}
+
+/*
+ * Virtual APC20 & APC40 in the process thread, they play along with the hardware on the same
+ * surface. Button presses of terminal & OSC clients are injected, snapshots are handed to them
+ * when something changed
+ */
+pub struct Mirror {
+    apc20: Emulator<APC20>,
+    apc40: Emulator<APC40>,
+    input_receiver: Receiver<(usize, InputEventType)>,
+    listeners: Vec<Sender<Snapshot>>,
+    snapshot: Option<Snapshot>,
+}
+
+impl Mirror {
+    pub fn new(input_receiver: Receiver<(usize, InputEventType)>, listeners: Vec<Sender<Snapshot>>) -> Self {
+        let mut apc20 = Emulator::new();
+        let mut apc40 = Emulator::new();
+        apc20.identify(0x7b);
+        apc40.identify(0x73);
+
+        Mirror { apc20, apc40, input_receiver, listeners, snapshot: None }
+    }
+
+    pub fn process_midi_input(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
+        while let Ok((controller, event_type)) = self.input_receiver.try_recv() {
+            match controller {
+                0 => self.apc20.inject(event_type),
+                _ => self.apc40.inject(event_type),
+            }
+        }
+
+        self.apc20.process_input(cycle, sequencer, surface);
+        self.apc40.process_input(cycle, sequencer, surface);
+    }
+
+    pub fn output_midi(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
+        self.apc20.output(cycle, sequencer, surface);
+        self.apc40.output(cycle, sequencer, surface);
+
+        let channel_shown = surface.channel_shown();
+        let snapshot = Snapshot {
+            lights: Lights::new(&self.apc20, &self.apc40),
+            is_rolling: cycle.is_rolling,
+            beats_per_minute: cycle.beats_per_minute,
+            channel_shown: channel_shown as u8,
+            pattern_shown: surface.pattern_shown(channel_shown),
+            phrase_shown: surface.phrase_shown(channel_shown),
+            sequence_playing: sequencer.sequence_playing,
+            sequence_queued: sequencer.sequence_queued,
+        };
+
+        if self.snapshot.as_ref() != Some(&snapshot) {
+            // Listeners could be gone, we'll keep playing
+            self.listeners.iter().for_each(|listener| { let _ = listener.send(snapshot.clone()); });
+            self.snapshot = Some(snapshot);
+        }
+    }
+}
@@ -1,36 +1,117 @@
 
-use super::port::*;
-use super::message::*;
-use super::cycle::*;
+use std::fmt;
+use std::str::FromStr;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrossfadeGroup {
+    A,
+    B,
+}
+
+// Written as a & b in project files & commands
+impl FromStr for CrossfadeGroup {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "a" | "A" => Ok(CrossfadeGroup::A),
+            "b" | "B" => Ok(CrossfadeGroup::B),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for CrossfadeGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrossfadeGroup::A => write!(f, "a"),
+            CrossfadeGroup::B => write!(f, "b"),
+        }
+    }
+}
+
+/*
+ * Fader positions, channel volumes are sent as CC7 to the channel outputs. Mixer does not own a
+ * port so we can hand copies of it to the storage thread
+ */
+#[derive(Debug, Clone)]
 pub struct Mixer {
-    output: MidiOut,
-    buffer: Vec<TimedMessage>,
+    faders: [u8; 16],
+    master: u8,
+    crossfader: u8,
+    // Side of crossfader channel is on, channels without group are not affected by crossfader
+    crossfade_groups: [Option<CrossfadeGroup>; 16],
 }
 
 impl Mixer {
-    pub fn new(client: &jack::Client) -> Self {
-        let output = client.register_port("Mixer", jack::MidiOut::default()).unwrap();
+    pub const VOLUME_CONTROLLER: u8 = 7;
+    // Default volume of general midi
+    const DEFAULT_FADER: u8 = 100;
 
-        Self { 
-            output: MidiOut::new(output),
-            buffer: vec![],
+    pub fn new() -> Self {
+        Mixer {
+            faders: [Self::DEFAULT_FADER; 16],
+            master: 127,
+            crossfader: 64,
+            crossfade_groups: [None; 16],
         }
     }
 
-    pub fn fader_adjusted(&mut self, time: u32, fader: u8, value: u8) {
-        // TODO - Output this to corresponding port
-        self.buffer.push(TimedMessage::new(time, Message::Note([0xB0, fader, value])));
+    pub fn fader(&self, channel: usize) -> u8 { self.faders[channel] }
+    pub fn set_fader(&mut self, channel: usize, value: u8) { self.faders[channel] = value }
+    pub fn master(&self) -> u8 { self.master }
+    pub fn set_master(&mut self, value: u8) { self.master = value }
+    pub fn crossfader(&self) -> u8 { self.crossfader }
+    pub fn set_crossfader(&mut self, value: u8) { self.crossfader = value }
+
+    pub fn crossfade_group(&self, channel: usize) -> Option<CrossfadeGroup> { self.crossfade_groups[channel] }
+    pub fn set_crossfade_group(&mut self, channel: usize, group: Option<CrossfadeGroup>) {
+        self.crossfade_groups[channel] = group;
+    }
+
+    // Crossfader only mixes when there's channels assigned to it
+    pub fn has_crossfade_groups(&self) -> bool {
+        self.crossfade_groups.iter().any(|group| group.is_some())
+    }
+
+    // Both sides are at full volume when crossfader is centered
+    fn crossfade_gain(&self, group: Option<CrossfadeGroup>) -> f64 {
+        let position = match group {
+            Some(CrossfadeGroup::A) => 127 - self.crossfader,
+            Some(CrossfadeGroup::B) => self.crossfader,
+            None => return 1.0,
+        };
+
+        (position as f64 / 63.5).min(1.0)
     }
 
-    pub fn master_adjusted(&mut self, time: u32, value: u8) {
-        // TODO - Output this to corresponding port
-        //vec![TimedMessage::new(time, Message::Note([0xB0 + 15, 127, value]))]
-        self.buffer.push(TimedMessage::new(time, Message::Note([0xB0, 127, value])));
+    // Volume of channel, scaled by master fader & crossfader
+    pub fn volume(&self, channel: usize) -> u8 {
+        let gain = self.master as f64 / 127.0 * self.crossfade_gain(self.crossfade_groups[channel]);
+        (self.faders[channel] as f64 * gain).round() as u8
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume() {
+        let mut mixer = Mixer::new();
+        mixer.set_fader(0, 127);
+        mixer.set_fader(1, 127);
+        mixer.set_fader(2, 100);
+        mixer.set_crossfade_group(0, Some(CrossfadeGroup::A));
+        mixer.set_crossfade_group(1, Some(CrossfadeGroup::B));
+
+        mixer.set_crossfader(0);
+        assert_eq!((mixer.volume(0), mixer.volume(1), mixer.volume(2)), (127, 0, 100));
+
+        mixer.set_crossfader(127);
+        assert_eq!((mixer.volume(0), mixer.volume(1), mixer.volume(2)), (0, 127, 100));
 
-    pub fn output_midi(&mut self, cycle: &ProcessCycle) {
-        // This wil drain buffer as write_midi uses vec.drain
-        self.output.write_midi(cycle.scope, &mut self.buffer);
+        mixer.set_master(0);
+        assert_eq!((mixer.volume(0), mixer.volume(1), mixer.volume(2)), (0, 0, 0));
     }
 }
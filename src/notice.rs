@@ -0,0 +1,34 @@
+
+use std::fmt;
+use super::options::SyncSource;
+use super::keyboard::RecordMode;
+
+/*
+ * Things the user should know about that happen in the process thread. We can't print there, so
+ * the process handler hands these to a thread that prints them
+ */
+#[derive(Debug, PartialEq)]
+pub enum Notice {
+    // Tempo was changed while it's not ours to control
+    TempoFollowed(SyncSource),
+    RecordMode(RecordMode),
+    Quantizing(bool),
+    // Name of drawn note in drum map of instrument
+    NoteName(String),
+    InstrumentMissing(String),
+    PatternMissing(u8),
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notice::TempoFollowed(SyncSource::MidiClock) => write!(f, "Tempo is controlled by midi clock"),
+            Notice::TempoFollowed(_) => write!(f, "Tempo is controlled by timebase master"),
+            Notice::RecordMode(mode) => write!(f, "Record mode: {:?}", mode),
+            Notice::Quantizing(is_quantizing) => write!(f, "Quantizing: {}", is_quantizing),
+            Notice::NoteName(name) => write!(f, "{}", name),
+            Notice::InstrumentMissing(name) => write!(f, "Error: instrument {} does not exist", name),
+            Notice::PatternMissing(index) => write!(f, "Error: pattern {} does not exist", index),
+        }
+    }
+}
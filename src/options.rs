@@ -0,0 +1,100 @@
+
+use std::path::PathBuf;
+
+// Where our transport gets it's tempo & position from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncSource {
+    // We're jack timebase master
+    Internal,
+    // Follow timebase of other jack client
+    Timebase,
+    // Follow midi clock coming in on clock input port
+    MidiClock,
+}
+
+/*
+ * Command line options
+ */
+pub struct Options {
+    pub project_path: PathBuf,
+    pub instruments_path: PathBuf,
+    pub sync_source: SyncSource,
+    // Show controllers in terminal
+    pub is_tui: bool,
+    // Local UDP port of OSC server
+    pub osc_port: Option<u16>,
+}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut project_path = None;
+        let mut instruments_path = None;
+        let mut sync_source = SyncSource::Internal;
+        let mut is_tui = false;
+        let mut osc_port = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--slave" | "--clock" if sync_source != SyncSource::Internal => {
+                    return Err(String::from("--slave and --clock can't be combined"))
+                },
+                "--slave" => sync_source = SyncSource::Timebase,
+                "--clock" => sync_source = SyncSource::MidiClock,
+                "--tui" => is_tui = true,
+                "--osc" => {
+                    let port = args.next().and_then(|port| port.parse().ok()).ok_or_else(|| String::from("--osc needs a port"))?;
+                    osc_port = Some(port);
+                },
+                "--instruments" => {
+                    let path = args.next().ok_or_else(|| String::from("--instruments needs a path"))?;
+                    instruments_path = Some(PathBuf::from(path));
+                },
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ if project_path.is_none() => project_path = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+
+        Ok(Options {
+            project_path: project_path.unwrap_or_else(|| PathBuf::from("octothorpe.project")),
+            instruments_path: instruments_path.unwrap_or_else(|| PathBuf::from("octothorpe.instruments")),
+            sync_source,
+            is_tui,
+            osc_port,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.project_path, PathBuf::from("octothorpe.project"));
+        assert_eq!(options.sync_source, SyncSource::Internal);
+        assert_eq!(options.instruments_path, PathBuf::from("octothorpe.instruments"));
+        assert!(! options.is_tui);
+
+        let options = parse(&["--slave", "set.project"]).unwrap();
+        assert_eq!(options.project_path, PathBuf::from("set.project"));
+        assert_eq!(options.sync_source, SyncSource::Timebase);
+
+        assert_eq!(parse(&["--clock"]).unwrap().sync_source, SyncSource::MidiClock);
+        assert_eq!(parse(&["--instruments", "studio.instruments"]).unwrap().instruments_path, PathBuf::from("studio.instruments"));
+        assert!(parse(&["--instruments"]).is_err());
+        assert!(parse(&["--tui"]).unwrap().is_tui);
+        assert_eq!(options.osc_port, None);
+        assert_eq!(parse(&["--osc", "9000"]).unwrap().osc_port, Some(9000));
+        assert!(parse(&["--osc", "port"]).is_err());
+
+        assert!(parse(&["--slave", "--clock"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+        assert!(parse(&["one.project", "two.project"]).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, Receiver};
+use std::thread;
+use super::TickRange;
+use super::channel::Channel;
+use super::action::Action;
+use super::controller::input::*;
+use super::groove::Groove;
+use super::loopable::Pattern;
+use super::mirror::{Snapshot, ROWS};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+/*
+ * OSC message as it's sent in a single UDP packet. We only know about int, float & string
+ * arguments, that's what control surface apps send
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> Self {
+        OscMessage { address: String::from(address), args }
+    }
+
+    // Strings are null terminated & padded to 4 bytes
+    fn encode_string(bytes: &mut Vec<u8>, string: &str) {
+        bytes.extend_from_slice(string.as_bytes());
+        let length = bytes.len() + 4 - string.len() % 4;
+        bytes.resize(length, 0);
+    }
+
+    fn decode_string(bytes: &[u8], offset: &mut usize) -> Option<String> {
+        let length = bytes.get(*offset ..)?.iter().position(|byte| *byte == 0)?;
+        let string = String::from_utf8(bytes[*offset .. *offset + length].to_vec()).ok()?;
+        *offset += (length / 4 + 1) * 4;
+        Some(string)
+    }
+
+    fn decode_word(bytes: &[u8], offset: &mut usize) -> Option<[u8; 4]> {
+        let word = bytes.get(*offset .. *offset + 4)?;
+        *offset += 4;
+        Some([word[0], word[1], word[2], word[3]])
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        Self::encode_string(&mut bytes, &self.address);
+
+        let tags: String = self.args.iter()
+            .map(|arg| match arg { OscArg::Int(_) => 'i', OscArg::Float(_) => 'f', OscArg::String(_) => 's' })
+            .collect();
+        Self::encode_string(&mut bytes, &format!(",{}", tags));
+
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Float(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                OscArg::String(value) => Self::encode_string(&mut bytes, value),
+            }
+        }
+
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut offset = 0;
+        let address = Self::decode_string(bytes, &mut offset)?;
+        if ! address.starts_with('/') {
+            return None;
+        }
+
+        // Old clients leave out the type tags when there's no arguments
+        let tags = if offset < bytes.len() { Self::decode_string(bytes, &mut offset)? } else { String::from(",") };
+        let tags = tags.strip_prefix(',')?;
+
+        let args = tags.chars()
+            .map(|tag| match tag {
+                'i' => Self::decode_word(bytes, &mut offset).map(|word| OscArg::Int(i32::from_be_bytes(word))),
+                'f' => Self::decode_word(bytes, &mut offset).map(|word| OscArg::Float(f32::from_be_bytes(word))),
+                's' => Self::decode_string(bytes, &mut offset).map(OscArg::String),
+                _ => None,
+            })
+            .collect::<Option<Vec<OscArg>>>()?;
+
+        Some(OscMessage { address, args })
+    }
+
+    // Sliders of control surface apps send floats where we expect ints
+    fn int(&self, index: usize) -> Option<i32> {
+        match self.args.get(index)? {
+            OscArg::Int(value) => Some(*value),
+            OscArg::Float(value) => Some(*value as i32),
+            OscArg::String(_) => None,
+        }
+    }
+
+    fn float(&self, index: usize) -> Option<f32> {
+        match self.args.get(index)? {
+            OscArg::Int(value) => Some(*value as f32),
+            OscArg::Float(value) => Some(*value),
+            OscArg::String(_) => None,
+        }
+    }
+
+    // Int argument that lies in 0 .. limit
+    fn index(&self, index: usize, limit: usize) -> Option<usize> {
+        self.int(index).filter(|value| *value >= 0 && (*value as usize) < limit).map(|value| value as usize)
+    }
+
+    // Length in bars as ticks, lengths too long to count in ticks are no length
+    fn bars(&self, index: usize) -> Option<u32> {
+        (self.int(index).filter(|bars| *bars > 0)? as u32).checked_mul(Pattern::minimum_length())
+    }
+}
+
+// What a message of a client asks us to do
+pub enum Request {
+    Action(Action),
+    // Button presses on the mirrored controllers
+    Input(Vec<(usize, InputEventType)>),
+    Subscribe,
+    Unsubscribe,
+}
+
+/*
+ * Sequencer state & actions on a local UDP port. Actions are passed to the process thread like
+ * console commands are, subscribed clients get led & state changes of the mirrored controllers
+ */
+pub struct OscServer {
+    socket: UdpSocket,
+    subscribers: Arc<Mutex<Subscribers>>,
+    action_sender: Sender<Action>,
+    input_sender: Sender<(usize, InputEventType)>,
+}
+
+// Clients that want feedback & the state they last got
+struct Subscribers {
+    addresses: Vec<SocketAddr>,
+    snapshot: Option<Snapshot>,
+}
+
+impl OscServer {
+    pub fn bind(port: u16, action_sender: Sender<Action>, input_sender: Sender<(usize, InputEventType)>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("127.0.0.1", port))?;
+        let subscribers = Arc::new(Mutex::new(Subscribers { addresses: vec![], snapshot: None }));
+
+        Ok(OscServer { socket, subscribers, action_sender, input_sender })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.socket.local_addr() }
+
+    pub fn request(message: &OscMessage) -> Option<Request> {
+        let loopables = Channel::LOOPABLES;
+        let action = |action| Some(Request::Action(action));
+
+        match message.address.as_str() {
+            "/transport/play" => action(Action::Play),
+            "/transport/stop" => action(Action::Stop),
+            "/transport/reset" => action(Action::Reset),
+            "/show/channel" => action(Action::ShowChannel(message.index(0, 16)? as u8)),
+            "/show/pattern" => action(Action::ShowPattern(message.index(0, loopables)? as u8)),
+            "/show/phrase" => action(Action::ShowPhrase(message.index(0, loopables)? as u8)),
+            "/show/sequence" => action(Action::ShowSequence(message.index(0, loopables)? as u8)),
+            // Note starts at 16th step & lasts a step unless told otherwise
+            "/note" => {
+                let start = (message.int(2).filter(|step| *step >= 0)? as u32).checked_mul(Groove::SIXTEENTH_TICKS)?;
+                let steps = message.int(4).unwrap_or(1).max(1) as u32;
+
+                action(Action::Note {
+                    channel: message.index(0, 16)?,
+                    pattern: message.index(1, loopables)? as u8,
+                    tick_range: TickRange::new(start, start.checked_add(steps.checked_mul(Groove::SIXTEENTH_TICKS)?)?),
+                    note: message.index(3, 128)? as u8,
+                })
+            },
+            // Lengths are in bars
+            "/pattern/length" => action(Action::PatternLength {
+                channel: message.index(0, 16)?,
+                pattern: message.index(1, loopables)? as u8,
+                length: message.bars(2)?,
+            }),
+            "/phrase/length" => action(Action::PhraseLength {
+                channel: message.index(0, 16)?,
+                phrase: message.index(1, loopables)? as u8,
+                length: message.bars(2)?,
+            }),
+            "/sequence/queue" => action(Action::QueueSequence(message.index(0, loopables)?)),
+            "/tempo" => action(Action::BeatsPerMinute(message.float(0).filter(|bpm| *bpm > 0.0)? as f64)),
+            // Grid columns 0 - 7 are the APC20, 8 - 15 the APC40
+            "/grid" => {
+                let x = message.index(0, 16)? as u8;
+                let y = message.index(1, 5)? as u8;
+                let button_type = ButtonType::Grid(x % 8, y);
+                let controller = x as usize / 8;
+
+                Some(Request::Input(vec![
+                    (controller, InputEventType::ButtonPressed(button_type)),
+                    (controller, InputEventType::ButtonReleased(button_type)),
+                ]))
+            },
+            "/subscribe" => Some(Request::Subscribe),
+            "/unsubscribe" => Some(Request::Unsubscribe),
+            _ => None,
+        }
+    }
+
+    // Messages telling a client that knows about previous snapshot what changed
+    pub fn feedback(previous: Option<&Snapshot>, snapshot: &Snapshot) -> Vec<OscMessage> {
+        let mut messages = vec![];
+        let mut int = |address: &str, args: &[i32]| {
+            messages.push(OscMessage::new(address, args.iter().map(|arg| OscArg::Int(*arg)).collect()));
+        };
+
+        let lights = &snapshot.lights;
+        let previous_lights = previous.map(|previous| &previous.lights);
+
+        for (y, row) in lights.grid.iter().enumerate() {
+            for (x, value) in row.iter().enumerate() {
+                if previous_lights.map(|lights| lights.grid[y][x]) != Some(*value) {
+                    int("/led/grid", &[x as i32, y as i32, *value as i32]);
+                }
+            }
+        }
+        for (controller, side) in lights.side.iter().enumerate() {
+            for (index, value) in side.iter().enumerate() {
+                if previous_lights.map(|lights| lights.side[controller][index]) != Some(*value) {
+                    int("/led/side", &[controller as i32, index as i32, *value as i32]);
+                }
+            }
+        }
+        for (index, (_, name)) in ROWS.iter().enumerate() {
+            for (x, value) in lights.rows[index].iter().enumerate() {
+                if previous_lights.map(|lights| lights.rows[index][x]) != Some(*value) {
+                    int(&format!("/led/{}", name), &[x as i32, *value as i32]);
+                }
+            }
+        }
+        if previous_lights.map(|lights| lights.master) != Some(lights.master) {
+            int("/led/master", &[lights.master as i32]);
+        }
+
+        if previous.map(|previous| previous.is_rolling) != Some(snapshot.is_rolling) {
+            int("/transport/playing", &[snapshot.is_rolling as i32]);
+        }
+        if previous.map(|previous| (previous.channel_shown, previous.pattern_shown, previous.phrase_shown))
+            != Some((snapshot.channel_shown, snapshot.pattern_shown, snapshot.phrase_shown))
+        {
+            int("/show/channel", &[snapshot.channel_shown as i32]);
+            int("/show/pattern", &[snapshot.pattern_shown as i32]);
+            int("/show/phrase", &[snapshot.phrase_shown as i32]);
+        }
+        if previous.map(|previous| previous.sequence_playing) != Some(snapshot.sequence_playing) {
+            int("/sequence/playing", &[snapshot.sequence_playing as i32]);
+        }
+        if previous.map(|previous| previous.sequence_queued) != Some(snapshot.sequence_queued) {
+            int("/sequence/queued", &[snapshot.sequence_queued.map(|index| index as i32).unwrap_or(-1)]);
+        }
+        if previous.map(|previous| previous.beats_per_minute) != Some(snapshot.beats_per_minute) {
+            messages.push(OscMessage::new("/tempo", vec![OscArg::Float(snapshot.beats_per_minute as f32)]));
+        }
+
+        messages
+    }
+
+    fn send(socket: &UdpSocket, address: SocketAddr, messages: &[OscMessage]) {
+        for message in messages {
+            // Clients come & go, we don't care whether they got it
+            let _ = socket.send_to(&message.encode(), address);
+        }
+    }
+
+    // Push snapshots to subscribers & handle messages of clients, this function halts
+    pub fn start(self, snapshot_receiver: Receiver<Snapshot>) {
+        let socket = self.socket.try_clone().unwrap();
+        let subscribers = Arc::clone(&self.subscribers);
+
+        thread::spawn(move || {
+            for snapshot in snapshot_receiver {
+                let mut subscribers = subscribers.lock().unwrap();
+                let messages = Self::feedback(subscribers.snapshot.as_ref(), &snapshot);
+
+                subscribers.addresses.iter().for_each(|address| Self::send(&socket, *address, &messages));
+                subscribers.snapshot = Some(snapshot);
+            }
+        });
+
+        let mut buffer = [0; 1536];
+
+        while let Ok((size, address)) = self.socket.recv_from(&mut buffer) {
+            let message = OscMessage::decode(&buffer[.. size]);
+
+            match message.as_ref().and_then(Self::request) {
+                // Requests are dropped when process thread is gone, there's nobody to carry them out
+                Some(Request::Action(action)) => { let _ = self.action_sender.send(action); },
+                Some(Request::Input(inputs)) => inputs.into_iter().for_each(|input| { let _ = self.input_sender.send(input); }),
+                Some(Request::Subscribe) => {
+                    let mut subscribers = self.subscribers.lock().unwrap();
+                    if ! subscribers.addresses.contains(&address) {
+                        subscribers.addresses.push(address);
+                    }
+                    // New subscriber gets the whole state
+                    if let Some(snapshot) = &subscribers.snapshot {
+                        Self::send(&self.socket, address, &Self::feedback(None, snapshot));
+                    }
+                },
+                Some(Request::Unsubscribe) => self.subscribers.lock().unwrap().addresses.retain(|subscriber| *subscriber != address),
+                None => println!("Unknown OSC message {:?}", message.map(|message| message.address)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::super::controller::emulator::Emulator;
+    use super::super::hardware::{APC20, APC40};
+    use super::super::mirror::Lights;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            lights: Lights::new(&Emulator::<APC20>::new(), &Emulator::<APC40>::new()),
+            is_rolling: false,
+            beats_per_minute: 138.0,
+            channel_shown: 0,
+            pattern_shown: 0,
+            phrase_shown: 0,
+            sequence_playing: 0,
+            sequence_queued: None,
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let message = OscMessage::new("/note", vec![OscArg::Int(-2), OscArg::Float(0.5), OscArg::String(String::from("kick"))]);
+        let bytes = message.encode();
+        // Address & type tags are padded to 4 bytes, even when that takes an extra word
+        assert_eq!(&bytes[.. 12], b"/note\0\0\0,ifs");
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(OscMessage::decode(&bytes), Some(message));
+
+        assert_eq!(OscMessage::decode(b"/transport/play\0"), Some(OscMessage::new("/transport/play", vec![])));
+        // Arguments missing from the packet
+        assert_eq!(OscMessage::decode(b"/note\0\0\0,i\0\0"), None);
+        assert_eq!(OscMessage::decode(b"note\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn request() {
+        let note = OscMessage::new("/note", [1, 2, 4, 60, 2].iter().map(|arg| OscArg::Int(*arg)).collect());
+        let sixteenth = Groove::SIXTEENTH_TICKS;
+        assert!(matches!(OscServer::request(&note), Some(Request::Action(Action::Note { channel: 1, pattern: 2, tick_range, note: 60 }))
+            if (tick_range.start, tick_range.stop) == (sixteenth * 4, sixteenth * 6)));
+
+        let length = OscMessage::new("/pattern/length", vec![OscArg::Int(0), OscArg::Int(0), OscArg::Float(2.0)]);
+        assert!(matches!(OscServer::request(&length), Some(Request::Action(Action::PatternLength { length, .. })) if length == Pattern::minimum_length() * 2));
+
+        // Channels & notes that don't exist
+        assert!(OscServer::request(&OscMessage::new("/show/channel", vec![OscArg::Int(16)])).is_none());
+        assert!(OscServer::request(&OscMessage::new("/note", [0, 0, 0, 128].iter().map(|arg| OscArg::Int(*arg)).collect())).is_none());
+        assert!(OscServer::request(&OscMessage::new("/show/channel", vec![])).is_none());
+
+        // Lengths & positions that don't fit in ticks
+        assert!(OscServer::request(&OscMessage::new("/pattern/length", vec![OscArg::Int(0), OscArg::Int(0), OscArg::Int(i32::MAX)])).is_none());
+        assert!(OscServer::request(&OscMessage::new("/note", [0, 0, i32::MAX, 60].iter().map(|arg| OscArg::Int(*arg)).collect())).is_none());
+    }
+
+    #[test]
+    fn feedback() {
+        let previous = snapshot();
+        assert_eq!(OscServer::feedback(None, &previous).len(), 80 + 10 + 80 + 1 + 7);
+
+        let mut snapshot = previous.clone();
+        snapshot.lights.grid[1][3] = 1;
+        snapshot.sequence_queued = Some(2);
+        assert_eq!(OscServer::feedback(Some(&previous), &snapshot), vec![
+            OscMessage::new("/led/grid", vec![OscArg::Int(3), OscArg::Int(1), OscArg::Int(1)]),
+            OscMessage::new("/sequence/queued", vec![OscArg::Int(2)]),
+        ]);
+    }
+
+    #[test]
+    fn server() {
+        let (action_sender, action_receiver) = mpsc::channel();
+        let (input_sender, input_receiver) = mpsc::channel();
+        let (snapshot_sender, snapshot_receiver) = mpsc::channel();
+
+        let server = OscServer::bind(0, action_sender, input_sender).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.start(snapshot_receiver));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let send = |message: OscMessage| { client.send_to(&message.encode(), address).unwrap(); };
+        let timeout = Duration::from_secs(5);
+
+        send(OscMessage::new("/tempo", vec![OscArg::Float(120.0)]));
+        assert!(matches!(action_receiver.recv_timeout(timeout), Ok(Action::BeatsPerMinute(bpm)) if bpm == 120.0));
+
+        send(OscMessage::new("/grid", vec![OscArg::Int(9), OscArg::Int(4)]));
+        assert_eq!(input_receiver.recv_timeout(timeout), Ok((1, InputEventType::ButtonPressed(ButtonType::Grid(1, 4)))));
+
+        // Subscriber gets what changed after subscribing
+        send(OscMessage::new("/subscribe", vec![]));
+        send(OscMessage::new("/transport/play", vec![]));
+        assert!(matches!(action_receiver.recv_timeout(timeout), Ok(Action::Play)));
+
+        let mut snapshot = snapshot();
+        snapshot.beats_per_minute = 120.0;
+        snapshot_sender.send(snapshot).unwrap();
+
+        let mut buffer = [0; 1536];
+        let tempo = loop {
+            let (size, _) = client.recv_from(&mut buffer).unwrap();
+            let message = OscMessage::decode(&buffer[.. size]).unwrap();
+            if message.address == "/tempo" {
+                break message;
+            }
+        };
+        assert_eq!(tempo.args, vec![OscArg::Float(120.0)]);
+    }
+}
@@ -1,27 +1,41 @@
-
+use super::cycle::ProcessCycle;
 use super::message::TimedMessage;
 
+/*
+ * Ports are registered with jack when we have a client. Without one, like when rendering offline,
+ * output ports collect the messages written to them & input ports stay silent
+ */
 pub struct MidiOut {
-    pub port: jack::Port<jack::MidiOut>,
+    pub port: Option<jack::Port<jack::MidiOut>>,
+    // Messages written while there's no jack to write them to
+    rendered: Vec<TimedMessage>,
 }
 
 // We use a wrapper so we can sort the messages before outputting them to jack, as out off order
 // messages produce runtime errors
 impl MidiOut {
-    pub fn new(port: jack::Port<jack::MidiOut>) -> Self {
-        MidiOut { port }
+    pub fn new(client: Option<&jack::Client>, name: &str) -> Self {
+        let port = client.map(|client| client.register_port(name, jack::MidiOut::default()).unwrap());
+        MidiOut { port, rendered: vec![] }
     }
 
     /*
      * Output to jack
      */
-    pub fn write_midi(&mut self, process_scope: &jack::ProcessScope, messages: &mut Vec<TimedMessage>) {
-        let mut writer = self.port.writer(process_scope);
-
+    pub fn write_midi(&mut self, cycle: &ProcessCycle, messages: &mut Vec<TimedMessage>) {
         // Sort messages based on time in timed message as jack will complain about unordered
         // messages
         messages.sort();
-        messages.drain(0..).for_each(|message| { 
+
+        let mut writer = match (&mut self.port, cycle.scope) {
+            (Some(port), Some(scope)) => port.writer(scope),
+            _ => {
+                self.rendered.append(messages);
+                return;
+            },
+        };
+
+        messages.drain(0..).for_each(|message| {
             match writer.write(&message.to_raw_midi()) {
                 Err(e) => {
                     println!("Error: {}", e);
@@ -31,4 +45,28 @@ impl MidiOut {
             }
         });
     }
+
+    // Messages that were written without jack, frames are relative to the cycle they were written in
+    pub fn take_rendered(&mut self) -> Vec<TimedMessage> {
+        self.rendered.drain(0..).collect()
+    }
+}
+
+pub struct MidiIn {
+    pub port: Option<jack::Port<jack::MidiIn>>,
+}
+
+impl MidiIn {
+    pub fn new(client: Option<&jack::Client>, name: &str) -> Self {
+        let port = client.map(|client| client.register_port(name, jack::MidiIn::default()).unwrap());
+        MidiIn { port }
+    }
+
+    // Frame & bytes of messages that came in this cycle
+    pub fn messages<'a>(&'a self, cycle: &ProcessCycle<'a>) -> Vec<jack::RawMidi<'a>> {
+        match (&self.port, cycle.scope) {
+            (Some(port), Some(scope)) => port.iter(scope).collect(),
+            _ => vec![],
+        }
+    }
 }
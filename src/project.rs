@@ -0,0 +1,533 @@
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use super::loopable::*;
+use super::events::*;
+use super::sequence::Sequence;
+use super::channel::{Channel, Routing};
+use super::sequencer::Sequencer;
+use super::tempo::TempoMap;
+use super::mixer::Mixer;
+use super::groove::Groove;
+use super::TimebaseHandler;
+
+/*
+ * Loopables of a channel, without the jack port that comes with a channel so we can move this
+ * between threads
+ */
+#[derive(Clone)]
+pub struct ChannelState {
+    pub patterns: Vec<Pattern>,
+    pub phrases: Vec<Phrase>,
+    pub timeline: Timeline,
+    pub groove: Option<Groove>,
+    pub routing: Routing,
+    // Name of instrument in instruments file
+    pub instrument: Option<String>,
+}
+
+impl ChannelState {
+    pub fn new(index: usize) -> Self {
+        ChannelState {
+            patterns: (0 .. Channel::LOOPABLES).map(|_| Pattern::new()).collect(),
+            phrases: (0 .. Channel::LOOPABLES).map(|_| Phrase::new()).collect(),
+            timeline: Timeline::new(),
+            groove: None,
+            routing: Routing::new(index, index as u8, 0),
+            instrument: None,
+        }
+    }
+
+    fn pattern_mut(&mut self, index: usize) -> &mut Pattern {
+        if index >= self.patterns.len() {
+            self.patterns.resize_with(index + 1, Pattern::new);
+        }
+        &mut self.patterns[index]
+    }
+
+    fn phrase_mut(&mut self, index: usize) -> &mut Phrase {
+        if index >= self.phrases.len() {
+            self.phrases.resize_with(index + 1, Phrase::new);
+        }
+        &mut self.phrases[index]
+    }
+}
+
+/*
+ * Changed parts of the sequencer state, the process thread hands these to the storage thread so
+ * it can keep an up to date copy of the project around for autosaving
+ */
+pub enum ProjectChange {
+    // Channel index, pattern index, pattern
+    Pattern(usize, usize, Pattern),
+    // Channel index, phrase index, phrase
+    Phrase(usize, usize, Phrase),
+    Timeline(usize, Timeline),
+    Groove(usize, Option<Groove>),
+    Routing(usize, Routing),
+    Instrument(usize, Option<String>),
+    Sequence(usize, Sequence),
+    TempoMap(TempoMap),
+    Mixer(Mixer),
+}
+
+/*
+ * Full sequencer state as it is saved to disk. Files are line based, every line starts with a
+ * keyword followed by it's values. Lines with unknown keywords are skipped, that way we can add
+ * things to the format without breaking older project files
+ */
+#[derive(Clone)]
+pub struct Project {
+    pub channels: Vec<ChannelState>,
+    pub sequences: Vec<Sequence>,
+    pub tempo_map: TempoMap,
+    pub mixer: Mixer,
+}
+
+impl Project {
+    // Raised every time the format gains lines, older builds would silently skip those
+    // 2: control, groove, output, instrument, meter & crossfade lines
+    pub const VERSION: u32 = 2;
+    const HEADER: &'static str = "octothorpe";
+
+    pub fn new() -> Self {
+        Project {
+            channels: (0 .. 16).map(ChannelState::new).collect(),
+            sequences: (0 .. Channel::LOOPABLES as u8).map(Sequence::new).collect(),
+            tempo_map: TempoMap::new(TimebaseHandler::DEFAULT_BEATS_PER_MINUTE, 4, 4),
+            mixer: Mixer::new(),
+        }
+    }
+
+    pub fn apply(&mut self, change: ProjectChange) {
+        match change {
+            ProjectChange::Pattern(channel, index, pattern) => *self.channels[channel].pattern_mut(index) = pattern,
+            ProjectChange::Phrase(channel, index, phrase) => *self.channels[channel].phrase_mut(index) = phrase,
+            ProjectChange::Timeline(channel, timeline) => self.channels[channel].timeline = timeline,
+            ProjectChange::Groove(channel, groove) => self.channels[channel].groove = groove,
+            ProjectChange::Routing(channel, routing) => self.channels[channel].routing = routing,
+            ProjectChange::Instrument(channel, instrument) => self.channels[channel].instrument = instrument,
+            ProjectChange::Sequence(index, sequence) => {
+                if index >= self.sequences.len() {
+                    self.sequences.resize_with(index + 1, || Sequence::new(0));
+                }
+                self.sequences[index] = sequence;
+            },
+            ProjectChange::TempoMap(tempo_map) => self.tempo_map = tempo_map,
+            ProjectChange::Mixer(mixer) => self.mixer = mixer,
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    // Write to temporary file first, that way we don't end up with half a project when we crash
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temporary_path)?);
+            self.write(&mut writer)?;
+            writer.flush()?;
+        }
+        std::fs::rename(temporary_path, path)
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "{} {}", Self::HEADER, Self::VERSION)?;
+
+        for change in self.tempo_map.tempo_changes() {
+            writeln!(writer, "tempo {} {} {}", change.tick, change.beats_per_minute, change.is_ramp as u8)?;
+        }
+
+        for change in self.tempo_map.meter_changes() {
+            writeln!(writer, "meter {} {} {}", change.bar, change.beats_per_bar, change.beat_type)?;
+        }
+
+        writeln!(writer, "master {}", self.mixer.master())?;
+        writeln!(writer, "crossfader {}", self.mixer.crossfader())?;
+
+        for (channel_index, channel) in self.channels.iter().enumerate() {
+            writeln!(writer, "channel {}", channel_index)?;
+            writeln!(writer, "fader {} {}", self.mixer.fader(channel_index), format_option(self.mixer.crossfade_group(channel_index)))?;
+            writeln!(writer, "output {} {} {}", channel.routing.port, channel.routing.midi_channel, channel.routing.transpose)?;
+
+            if let Some(instrument) = &channel.instrument {
+                writeln!(writer, "instrument {}", instrument)?;
+            }
+
+            if let Some(groove) = &channel.groove {
+                writeln!(writer, "groove {}", groove)?;
+            }
+
+            for (index, pattern) in channel.patterns.iter().enumerate() {
+                writeln!(writer, "pattern {} {}", index, format_option(pattern.length))?;
+
+                if let Some(groove) = &pattern.groove {
+                    writeln!(writer, "pattern_groove {}", groove)?;
+                }
+
+                // Only complete events are saved, events without stop are still being drawn
+                for event in pattern.note_events.iter().filter(|event| event.stop.is_some()) {
+                    writeln!(writer, "note {} {} {} {} {}", event.note, event.start, event.start_velocity,
+                        event.stop.unwrap(), event.stop_velocity.unwrap_or(event.start_velocity))?;
+                }
+            }
+
+            for (index, phrase) in channel.phrases.iter().enumerate() {
+                writeln!(writer, "phrase {} {}", index, phrase.length())?;
+
+                for event in phrase.pattern_events.iter().filter(|event| event.stop.is_some()) {
+                    writeln!(writer, "pattern_event {} {} {}", event.pattern, event.start, event.stop.unwrap())?;
+                }
+
+                for event in phrase.control_events.iter() {
+                    writeln!(writer, "control {} {} {}", event.controller, event.tick, event.value)?;
+                }
+            }
+
+            for event in channel.timeline.phrase_events.iter().filter(|event| event.stop.is_some()) {
+                writeln!(writer, "phrase_event {} {} {}", event.phrase, event.start, event.stop.unwrap())?;
+            }
+        }
+
+        for (index, sequence) in self.sequences.iter().enumerate() {
+            writeln!(writer, "sequence {}", index)?;
+
+            for (channel_index, phrase) in sequence.phrases().iter().enumerate() {
+                writeln!(writer, "sequence_phrase {} {} {}", channel_index, format_option(*phrase), sequence.is_active(channel_index) as u8)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read(reader: impl BufRead) -> io::Result<Self> {
+        let mut lines = reader.lines();
+
+        // Check if this is a project file we understand
+        let header = lines.next().unwrap_or_else(|| Ok(String::new()))?;
+        let mut values = header.split_whitespace();
+        if values.next() != Some(Self::HEADER) {
+            return Err(invalid("not an octothorpe project"));
+        }
+        let version: u32 = parse(values.next())?;
+        if version > Self::VERSION {
+            return Err(invalid(&format!("project version {} is newer than supported version {}", version, Self::VERSION)));
+        }
+
+        let mut project = Project::new();
+        let mut channel: Option<usize> = None;
+        let mut pattern: Option<usize> = None;
+        let mut phrase: Option<usize> = None;
+        let mut sequence: Option<usize> = None;
+
+        for line in lines {
+            let line = line?;
+            let mut values = line.split_whitespace();
+
+            match values.next() {
+                Some("tempo") => {
+                    let tick = parse(values.next())?;
+                    let beats_per_minute: f64 = parse(values.next())?;
+                    let is_ramp: u8 = parse(values.next())?;
+
+                    // Tempo is used to divide by, keep it to what can be set while playing
+                    if ! (Sequencer::MIN_BEATS_PER_MINUTE ..= Sequencer::MAX_BEATS_PER_MINUTE).contains(&beats_per_minute) {
+                        return Err(invalid(&format!("invalid tempo {}", beats_per_minute)));
+                    }
+                    project.tempo_map.set_tempo(tick, beats_per_minute, is_ramp == 1);
+                },
+                Some("meter") => {
+                    let bar = parse(values.next())?;
+                    let beats_per_bar: u8 = parse(values.next())?;
+                    let beat_type: u8 = parse(values.next())?;
+
+                    if beats_per_bar == 0 || ! beat_type.is_power_of_two() {
+                        return Err(invalid(&format!("invalid meter {}/{}", beats_per_bar, beat_type)));
+                    }
+                    project.tempo_map.set_meter(bar, beats_per_bar, beat_type);
+                },
+                Some("master") => project.mixer.set_master(parse_midi(values.next())?),
+                Some("crossfader") => project.mixer.set_crossfader(parse_midi(values.next())?),
+                Some("fader") => {
+                    let index = channel.ok_or_else(|| invalid("fader outside of channel"))?;
+                    project.mixer.set_fader(index, parse_midi(values.next())?);
+                    project.mixer.set_crossfade_group(index, parse_option(values.next())?);
+                },
+                Some("output") => {
+                    let port = parse(values.next())?;
+                    let midi_channel = parse(values.next())?;
+                    let transpose = parse(values.next())?;
+
+                    if port >= Sequencer::OUTPUTS || midi_channel >= 16 {
+                        return Err(invalid(&format!("output {} {} does not exist", port, midi_channel)));
+                    }
+                    project.channel_mut(channel)?.routing = Routing::new(port, midi_channel, transpose);
+                },
+                Some("instrument") => {
+                    let name = values.next().ok_or_else(|| invalid("instrument without name"))?;
+                    project.channel_mut(channel)?.instrument = Some(String::from(name));
+                },
+                Some("channel") => {
+                    let index: usize = parse(values.next())?;
+                    if index >= project.channels.len() {
+                        return Err(invalid(&format!("channel {} does not exist", index)));
+                    }
+                    channel = Some(index);
+                    pattern = None;
+                    phrase = None;
+                },
+                Some("pattern") => {
+                    let index = parse_index(values.next(), "pattern")?;
+                    let length = parse_option(values.next())?;
+
+                    if length == Some(0) {
+                        return Err(invalid(&format!("pattern {} has no length", index)));
+                    }
+                    project.channel_mut(channel)?.pattern_mut(index).length = length;
+                    pattern = Some(index);
+                },
+                Some("groove") => {
+                    let groove = Groove::parse(values).ok_or_else(|| invalid("could not parse groove"))?;
+                    project.channel_mut(channel)?.groove = Some(groove);
+                },
+                Some("pattern_groove") => {
+                    let groove = Groove::parse(values).ok_or_else(|| invalid("could not parse groove"))?;
+                    let index = pattern.ok_or_else(|| invalid("groove outside of pattern"))?;
+                    project.channel_mut(channel)?.pattern_mut(index).groove = Some(groove);
+                },
+                Some("note") => {
+                    let note = parse_midi(values.next())?;
+                    let mut event = LoopableNoteEvent::new(parse(values.next())?, note, parse_midi(values.next())?);
+                    event.stop = Some(parse(values.next())?);
+                    event.stop_velocity = Some(parse_midi(values.next())?);
+
+                    let index = pattern.ok_or_else(|| invalid("note outside of pattern"))?;
+                    project.channel_mut(channel)?.pattern_mut(index).note_events.push(event);
+                },
+                Some("phrase") => {
+                    let index = parse_index(values.next(), "phrase")?;
+                    let length = parse(values.next())?;
+
+                    if length == 0 {
+                        return Err(invalid(&format!("phrase {} has no length", index)));
+                    }
+                    project.channel_mut(channel)?.phrase_mut(index).set_length(length);
+                    phrase = Some(index);
+                },
+                Some("pattern_event") => {
+                    let mut event = LoopablePatternEvent::new(0, parse_index(values.next(), "pattern")? as u8);
+                    event.start = parse(values.next())?;
+                    event.stop = Some(parse(values.next())?);
+
+                    let index = phrase.ok_or_else(|| invalid("pattern event outside of phrase"))?;
+                    project.channel_mut(channel)?.phrase_mut(index).pattern_events.push(event);
+                },
+                Some("control") => {
+                    let controller = parse_midi(values.next())?;
+                    let tick = parse(values.next())?;
+                    let value = parse_midi(values.next())?;
+
+                    let index = phrase.ok_or_else(|| invalid("control event outside of phrase"))?;
+                    project.channel_mut(channel)?.phrase_mut(index).record_control(tick, controller, value);
+                },
+                Some("phrase_event") => {
+                    let phrase_index = parse_index(values.next(), "phrase")? as u8;
+                    let event = LoopablePhraseEvent::new(parse(values.next())?, parse(values.next())?, phrase_index);
+                    project.channel_mut(channel)?.timeline.phrase_events.push(event);
+                },
+                Some("sequence") => {
+                    sequence = Some(parse_index(values.next(), "sequence")?);
+                },
+                Some("sequence_phrase") => {
+                    let channel_index: usize = parse(values.next())?;
+                    let phrase: Option<u8> = parse_option(values.next())?;
+                    let is_active: u8 = parse(values.next())?;
+
+                    if channel_index >= 16 {
+                        return Err(invalid(&format!("channel {} does not exist", channel_index)));
+                    }
+                    if let Some(phrase) = phrase.filter(|phrase| *phrase as usize >= Channel::LOOPABLES) {
+                        return Err(invalid(&format!("phrase {} does not exist", phrase)));
+                    }
+
+                    let index = sequence.ok_or_else(|| invalid("sequence phrase outside of sequence"))?;
+                    let sequence = &mut project.sequences[index];
+                    match phrase {
+                        Some(phrase) => sequence.set_phrase(channel_index, phrase),
+                        None => sequence.unset_phrase(channel_index),
+                    }
+                    sequence.set_active(channel_index, is_active == 1);
+                },
+                // Empty lines, lines newer versions would write are refused by the version check
+                _ => (),
+            }
+        }
+
+        Ok(project)
+    }
+
+    fn channel_mut(&mut self, index: Option<usize>) -> io::Result<&mut ChannelState> {
+        let index = index.ok_or_else(|| invalid("event outside of channel"))?;
+        Ok(&mut self.channels[index])
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn parse<T: FromStr>(value: Option<&str>) -> io::Result<T> {
+    value.and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid(&format!("could not parse {:?}", value)))
+}
+
+// Channels have a fixed number of patterns & phrases, there's as many sequences
+fn parse_index(value: Option<&str>, name: &str) -> io::Result<usize> {
+    let index: usize = parse(value)?;
+
+    if index >= Channel::LOOPABLES {
+        return Err(invalid(&format!("{} {} does not exist", name, index)));
+    }
+    Ok(index)
+}
+
+// Notes, velocities & controller values go out as midi data bytes
+fn parse_midi(value: Option<&str>) -> io::Result<u8> {
+    let byte: u8 = parse(value)?;
+
+    if byte > 127 {
+        return Err(invalid(&format!("midi value {} is out of range", byte)));
+    }
+    Ok(byte)
+}
+
+// Options are written as "-" when they're not set
+fn parse_option<T: FromStr>(value: Option<&str>) -> io::Result<Option<T>> {
+    match value {
+        Some("-") => Ok(None),
+        _ => parse(value).map(Some),
+    }
+}
+
+fn format_option<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_else(|| String::from("-"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mixer::CrossfadeGroup;
+
+    #[test]
+    fn write_read() {
+        let mut project = Project::new();
+
+        let mut note = LoopableNoteEvent::new(10, 60, 100);
+        note.stop = Some(20);
+        note.stop_velocity = Some(64);
+        project.channels[3].patterns[1].note_events.push(note);
+        project.channels[3].patterns[1].set_length(Pattern::minimum_length());
+        project.channels[3].patterns[1].groove = Some(Groove::new(240, vec![(0, 10), (30, -10)]));
+        project.channels[3].groove = Some(Groove::swing(60));
+        project.channels[3].routing = Routing::new(0, 9, -12);
+        project.channels[3].instrument = Some(String::from("drums"));
+
+        let mut pattern_event = LoopablePatternEvent::new(30, 1);
+        pattern_event.stop = Some(10);
+        project.channels[3].phrases[2].pattern_events.push(pattern_event);
+        project.channels[3].phrases[2].set_length(Phrase::default_length() * 2);
+        project.channels[3].phrases[2].record_control(50, 21, 99);
+        project.channels[3].timeline.phrase_events.push(LoopablePhraseEvent::new(0, 40, 2));
+
+        project.tempo_map.set_tempo(0, 120.5, true);
+        project.tempo_map.set_tempo(7680, 90.0, false);
+        project.tempo_map.set_meter(4, 7, 8);
+
+        project.mixer.set_master(90);
+        project.mixer.set_crossfader(10);
+        project.mixer.set_fader(3, 80);
+        project.mixer.set_crossfade_group(3, Some(CrossfadeGroup::B));
+
+        project.sequences[4].unset_phrase(3);
+        project.sequences[4].set_active(5, false);
+
+        let mut buffer = vec![];
+        project.write(&mut buffer).unwrap();
+        let read = Project::read(io::Cursor::new(buffer)).unwrap();
+
+        let pattern = &read.channels[3].patterns[1];
+        assert_eq!(pattern.length, Some(Pattern::minimum_length()));
+        assert_eq!(pattern.note_events.len(), 1);
+        assert_eq!((pattern.note_events[0].note, pattern.note_events[0].start, pattern.note_events[0].stop), (60, 10, Some(20)));
+        assert_eq!((pattern.note_events[0].start_velocity, pattern.note_events[0].stop_velocity), (100, Some(64)));
+        assert_eq!(read.channels[3].patterns[0].length, None);
+        assert_eq!(pattern.groove, project.channels[3].patterns[1].groove);
+        assert_eq!(read.channels[3].groove, Some(Groove::swing(60)));
+        assert_eq!(read.channels[2].groove, None);
+        assert_eq!(read.channels[3].routing, Routing::new(0, 9, -12));
+        assert_eq!(read.channels[2].routing, Routing::new(2, 2, 0));
+        assert_eq!((read.channels[3].instrument.as_deref(), read.channels[2].instrument.as_deref()), (Some("drums"), None));
+
+        let phrase = &read.channels[3].phrases[2];
+        assert_eq!(phrase.length(), Phrase::default_length() * 2);
+        assert_eq!((phrase.pattern_events[0].pattern, phrase.pattern_events[0].start, phrase.pattern_events[0].stop), (1, 30, Some(10)));
+        assert_eq!((phrase.control_events[0].controller, phrase.control_events[0].tick, phrase.control_events[0].value), (21, 50, 99));
+
+        let phrase_event = &read.channels[3].timeline.phrase_events[0];
+        assert_eq!((phrase_event.phrase, phrase_event.start, phrase_event.stop), (2, 0, Some(40)));
+
+        assert_eq!(read.tempo_map.tempo_changes(), project.tempo_map.tempo_changes());
+        assert_eq!(read.tempo_map.meter_changes(), project.tempo_map.meter_changes());
+
+        assert_eq!((read.mixer.master(), read.mixer.crossfader()), (90, 10));
+        assert_eq!((read.mixer.fader(3), read.mixer.crossfade_group(3)), (80, Some(CrossfadeGroup::B)));
+        assert_eq!((read.mixer.fader(2), read.mixer.crossfade_group(2)), (100, None));
+
+        assert_eq!(read.sequences[4].get_phrase(3), None);
+        assert_eq!(read.sequences[4].get_phrase(2), Some(4));
+        assert_eq!(read.sequences[4].is_active(5), false);
+        assert_eq!(read.sequences[4].is_active(4), true);
+    }
+
+    #[test]
+    fn read_invalid() {
+        let read = |lines: &str| Project::read(io::Cursor::new(format!("octothorpe 1\nchannel 0\n{}\n", lines)));
+        assert!(read("pattern 1 -\nnote 60 0 100 10 64\nphrase 0 7680\npattern_event 1 0 10").is_ok());
+
+        // Indexes past the loopables we have would grow or index out of bounds
+        assert!(read("pattern 4000000000 -").is_err());
+        assert!(read("phrase 40 7680").is_err());
+        assert!(read("sequence 40").is_err());
+        assert!(read("phrase 0 7680\npattern_event 40 0 10").is_err());
+        assert!(read("phrase_event 255 0 10").is_err());
+        assert!(read("sequence 0\nsequence_phrase 0 40 1").is_err());
+
+        // Lengths are used to divide by
+        assert!(read("pattern 0 0").is_err());
+        assert!(read("phrase 0 0").is_err());
+
+        assert!(read("pattern 0 -\nnote 128 0 100 10 64").is_err());
+        assert!(read("pattern 0 -\nnote 60 0 200 10 64").is_err());
+        assert!(read("phrase 0 7680\ncontrol 21 0 128").is_err());
+        assert!(read("master 255").is_err());
+
+        assert!(read("tempo 0 120 0").is_ok());
+        assert!(read("tempo 0 0 0").is_err());
+        assert!(read("tempo 0 NaN 0").is_err());
+        assert!(read("tempo 0 inf 0").is_err());
+        assert!(read("tempo 0 1000 0").is_err());
+    }
+
+    #[test]
+    fn read_newer_version() {
+        let file = format!("octothorpe {}\n", Project::VERSION + 1);
+        assert!(Project::read(io::Cursor::new(file)).is_err());
+
+        // Files of older versions are read, they contain a subset of what we write
+        assert!(Project::read(io::Cursor::new("octothorpe 1\n")).is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+
+use super::TimebaseHandler;
+use super::loopable::*;
+use super::events::*;
+
+/*
+ * Moves ticks towards a grid of notes, strength is how far ticks are moved in percent
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantizer {
+    // 4 for quarter notes up to 32 for 32nd notes
+    division: u8,
+    is_triplet: bool,
+    strength: u8,
+}
+
+impl Quantizer {
+    pub const DIVISIONS: [u8; 4] = [4, 8, 16, 32];
+
+    pub fn new(division: u8, is_triplet: bool, strength: u8) -> Self {
+        Quantizer { division, is_triplet, strength: strength.min(100) }
+    }
+
+    pub fn division(&self) -> u8 { self.division }
+    pub fn is_triplet(&self) -> bool { self.is_triplet }
+    pub fn strength(&self) -> u8 { self.strength }
+
+    // Ticks between grid lines, triplets fit 3 notes in the space of 2
+    pub fn grid_ticks(&self) -> u32 {
+        let ticks = TimebaseHandler::TICKS_PER_BEAT as u32 * 4 / self.division as u32;
+        if self.is_triplet { ticks * 2 / 3 } else { ticks }
+    }
+
+    pub fn quantize(&self, tick: u32) -> u32 {
+        let grid_ticks = self.grid_ticks();
+        let nearest = (tick + grid_ticks / 2) / grid_ticks * grid_ticks;
+        let delta = (nearest as i64 - tick as i64) * self.strength as i64 / 100;
+
+        (tick as i64 + delta) as u32
+    }
+
+    // Quantize start & stop of note, looping around at length of pattern when it has one
+    pub fn quantize_note(&self, event: &mut LoopableNoteEvent, length: Option<u32>) {
+        let wrap = |tick: u32| length.map(|length| tick % length).unwrap_or(tick);
+        let start = wrap(self.quantize(event.start));
+
+        if let Some(stop) = event.stop {
+            let mut stop = wrap(self.quantize(stop));
+
+            // Notes that were not looping should not become looping by being quantized
+            if stop == start {
+                stop = wrap(start + self.grid_ticks());
+            }
+
+            event.stop = Some(stop);
+        }
+
+        event.start = start;
+    }
+
+    pub fn quantize_pattern(&self, pattern: &mut Pattern) {
+        let length = pattern.length;
+
+        for event in pattern.note_events.iter_mut() {
+            self.quantize_note(event, length);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantize() {
+        let quantizer = Quantizer::new(16, false, 100);
+        assert_eq!(quantizer.grid_ticks(), 480);
+        assert_eq!((quantizer.quantize(200), quantizer.quantize(250), quantizer.quantize(960)), (0, 480, 960));
+
+        let quantizer = Quantizer::new(8, true, 50);
+        assert_eq!(quantizer.grid_ticks(), 640);
+        assert_eq!(quantizer.quantize(600), 620);
+        assert_eq!(quantizer.quantize(700), 670);
+    }
+
+    #[test]
+    fn quantize_pattern() {
+        let mut pattern = Pattern::new();
+        pattern.set_length(Pattern::minimum_length());
+
+        let mut event = LoopableNoteEvent::new(Pattern::minimum_length() - 10, 60, 100);
+        event.set_stop(Pattern::minimum_length() - 5);
+        pattern.note_events.push(event);
+
+        Quantizer::new(16, false, 100).quantize_pattern(&mut pattern);
+        assert_eq!((pattern.note_events[0].start, pattern.note_events[0].stop), (0, Some(480)));
+    }
+}
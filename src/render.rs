@@ -0,0 +1,168 @@
+
+use super::TickRange;
+use super::cycle::ProcessCycle;
+use super::message::Message;
+use super::sequencer::Sequencer;
+use super::transport::SyntheticTransport;
+
+// Message played by sequencer, at frame counted from start of transport
+#[derive(Debug, PartialEq)]
+pub struct RenderedEvent {
+    pub frame: u32,
+    pub port: usize,
+    pub message: Message,
+}
+
+/*
+ * Plays the sequencer faster than realtime. Cycles of a synthetic transport go through the same
+ * playback path as jack cycles do, what the sequencer outputs is collected instead of sent
+ */
+pub struct Renderer {
+    transport: SyntheticTransport,
+    tick_range: Option<TickRange>,
+}
+
+impl Renderer {
+    pub fn new(frame_rate: u32, buffer_size: u32) -> Self {
+        Renderer { transport: SyntheticTransport::new(frame_rate, buffer_size), tick_range: None }
+    }
+
+    pub fn transport(&self) -> &SyntheticTransport { &self.transport }
+
+    fn next_cycle(&self, sequencer: &Sequencer) -> ProcessCycle<'_> {
+        ProcessCycle::synthetic(&self.transport, self.tick_range, sequencer.tempo_map())
+    }
+
+    pub fn start(&mut self, sequencer: &mut Sequencer) {
+        let cycle = self.next_cycle(sequencer);
+        sequencer.start(&cycle);
+    }
+
+    pub fn stop(&mut self, sequencer: &mut Sequencer) {
+        let cycle = self.next_cycle(sequencer);
+        sequencer.stop(&cycle);
+    }
+
+    // Run one cycle like the process handler does & move transport to the next one
+    pub fn cycle(&mut self, sequencer: &mut Sequencer) -> Vec<RenderedEvent> {
+        let cycle = self.next_cycle(sequencer);
+
+        if cycle.is_rolling {
+            sequencer.autoqueue_next_sequence(&cycle);
+        }
+
+        sequencer.output_midi(&cycle);
+
+        let frame = self.transport.frame();
+        self.tick_range = Some(cycle.tick_range);
+        self.transport.advance();
+
+        sequencer.take_rendered().into_iter()
+            .map(|(port, message)| RenderedEvent { frame: frame + message.time, port, message: message.message })
+            .collect()
+    }
+
+    // Play from where transport is until it passes tick, stopping there
+    pub fn render(&mut self, sequencer: &mut Sequencer, tick: u32) -> Vec<RenderedEvent> {
+        let mut events = vec![];
+
+        self.start(sequencer);
+        while self.tick_range.map(|range| range.stop < tick).unwrap_or(true) {
+            events.append(&mut self.cycle(sequencer));
+        }
+        self.stop(sequencer);
+
+        // Note offs of notes that were still playing go out in the cycle after stopping
+        events.append(&mut self.cycle(sequencer));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use super::super::TimebaseHandler;
+    use super::super::options::SyncSource;
+    use super::super::loopable::Loopable;
+    use super::super::events::*;
+    use super::super::channel::Channel;
+
+    #[test]
+    fn render() {
+        let (sender, _receiver) = mpsc::channel();
+        let mut sequencer = Sequencer::new(None, sender, SyncSource::Internal);
+        let beat = TimebaseHandler::TICKS_PER_BEAT as u32;
+
+        // Quarter note on every beat of the first bar of channel 0, phrase 0 plays pattern 0
+        let channel = sequencer.channel_mut(0);
+        channel.pattern_mut(0).set_length(beat * 4);
+        for start in (0 .. 4).map(|index| index * beat) {
+            let mut note = LoopableNoteEvent::new(start, 60, 100);
+            note.set_stop(start + beat / 2);
+            note.stop_velocity = Some(64);
+            channel.pattern_mut(0).add_complete_event(note);
+        }
+        let mut pattern = LoopablePatternEvent::new(0, 0);
+        pattern.set_stop(beat * 4);
+        channel.phrase_mut(0).add_complete_event(pattern);
+
+        // Half a second per beat
+        sequencer.set_beats_per_minute(0, 120.0);
+        let mut renderer = Renderer::new(48000, 256);
+        let events = renderer.render(&mut sequencer, beat * 7 / 4);
+
+        let notes: Vec<&RenderedEvent> = events.iter().filter(|event| event.port == 0).collect();
+        let expected = vec![
+            (0, Message::Note([0x90, 60, 100])),
+            (12000, Message::Note([0x80, 60, 64])),
+            (24000, Message::Note([0x90, 60, 100])),
+            (36000, Message::Note([0x80, 60, 64])),
+        ];
+        assert_eq!(notes.len(), expected.len());
+        // Ticks are 12.5 frames at this tempo, frames are exact up to a tick
+        for (event, (frame, message)) in notes.iter().zip(expected) {
+            assert_eq!(event.message, message);
+            assert!((event.frame as i64 - frame as i64).abs() <= 13);
+        }
+
+        assert!(! renderer.transport().is_rolling());
+        assert!(renderer.transport().frame() >= 42000);
+    }
+
+    #[test]
+    fn knob_recording() {
+        let (sender, _receiver) = mpsc::channel();
+        let mut sequencer = Sequencer::new(None, sender, SyncSource::Internal);
+        let beat = TimebaseHandler::TICKS_PER_BEAT as u32;
+        let controller = Channel::KNOB_CONTROLLER_OFFSET;
+
+        // Lane of channel 0 sets the first knob on every beat
+        for tick in (0 .. 4).map(|index| index * beat) {
+            sequencer.channel_mut(0).phrase_mut(0).record_control(tick, controller, 10);
+        }
+
+        sequencer.set_beats_per_minute(0, 120.0);
+        let mut renderer = Renderer::new(48000, 256);
+        renderer.start(&mut sequencer);
+        let mut events = renderer.cycle(&mut sequencer);
+
+        // Knob is turned while phrase plays, before cycle is played like the process handler does
+        let cycle = ProcessCycle::synthetic(renderer.transport(), renderer.tick_range, sequencer.tempo_map());
+        sequencer.knob_turned(&cycle, 0, 0, 0, 100);
+
+        while renderer.transport().frame() < 60000 {
+            events.append(&mut renderer.cycle(&mut sequencer));
+        }
+
+        // Live value is not doubled by what was recorded from it, lane plays again a beat after knob rests
+        let values: Vec<u8> = events.iter()
+            .filter_map(|event| match event.message {
+                Message::ControlChange([0xB0, number, value]) if number == controller => Some(value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, vec![10, 100, 10]);
+        assert!(sequencer.channel(0).phrase(0).control_events.iter().any(|event| event.value == 100));
+    }
+}
@@ -2,6 +2,7 @@
 use super::channel::Channel;
 use super::loopable::*;
 
+#[derive(Clone)]
 pub struct Sequence {
     // Phrase that's playing for channel, array index = channel
     phrases: [Option<u8>; 16],
@@ -40,6 +41,14 @@ impl Sequence {
         self.phrases[channel].and_then(|phrase| if self.active[channel] { Some(phrase) } else { None })
     }
 
+    pub fn is_active(&self, channel: usize) -> bool {
+        self.active[channel]
+    }
+
+    pub fn set_active(&mut self, channel: usize, is_active: bool) {
+        self.active[channel] = is_active;
+    }
+
     pub fn toggle_active(&mut self, channel: usize) {
         self.active[channel as usize] = ! self.active[channel as usize];
     }
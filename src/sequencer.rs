@@ -1,58 +1,124 @@
 
+use std::mem;
+use std::sync::mpsc::Sender;
 use super::TickRange;
+use super::TimebaseHandler;
 use super::cycle::*;
-use super::channel::Channel;
+use super::channel::{Channel, Routing};
+use super::port::MidiOut;
+use super::message::TimedMessage;
+use super::clock::{Clock, ClockInput, ClockEvent};
+use super::options::SyncSource;
 use super::sequence::Sequence;
+use super::mixer::{Mixer, CrossfadeGroup};
+use super::keyboard::{Keyboard, KeyboardEvent, RecordMode};
 use super::loopable::*;
 use super::events::*;
+use super::project::{Project, ProjectChange};
+use super::tempo::TempoMap;
+use super::history::{History, Part};
+use super::instrument::Instrument;
+use super::notice::Notice;
 
 pub struct Sequencer {
     pub channels: [Channel; 16],
-    pub sequences: [Sequence; 5],
+    pub sequences: Vec<Sequence>,
+    // Channels write to one of these, several channels can share an output
+    outputs: Vec<MidiOut>,
 
     pub sequence_playing: usize,
     pub sequence_queued: Option<usize>,
     pub last_sequence_started: u32,
+
+    mixer: Mixer,
+    // Remember what was touched since last autosave, like channels do for their loopables
+    changed_sequences: Vec<bool>,
+    changed_tempo_map: bool,
+    changed_mixer: bool,
+    pub keyboard: Keyboard,
+    history: History,
+    // Instruments channels can refer to by name
+    instruments: Vec<Instrument>,
+
+    clock: Clock,
+    clock_input: Option<ClockInput>,
+
+    // Tempo map changes are handed to timebase handler, when we're following another jack client
+    // or midi clock tempo is not ours to control
+    sync_source: SyncSource,
+    tempo_map: TempoMap,
+    timebase_sender: Sender<TempoMap>,
+
+    // Automation lanes knobs are writing to, by channel & controller. Playback of a lane is held
+    // back over these ticks, the knob itself is what the instrument should hear
+    written_lanes: Vec<(usize, u8, TickRange)>,
+
+    // Printed outside of process thread
+    notices: Vec<Notice>,
 }
 
 impl Sequencer {
-    pub fn new(client: &jack::Client) -> Self {
+    pub const MIN_BEATS_PER_MINUTE: f64 = 20.0;
+    pub const MAX_BEATS_PER_MINUTE: f64 = 300.0;
+    pub const OUTPUTS: usize = 16;
+    // Lane is no longer written to when its knob was not turned for a beat
+    const LANE_RELEASE_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32;
+
+    pub fn new(client: Option<&jack::Client>, timebase_sender: Sender<TempoMap>, sync_source: SyncSource) -> Self {
         // Build channels array, shame there's no way to do this elegantly without a macro as far as i can tell
         let channels = [
-            Channel::new(client, 0),
-            Channel::new(client, 1),
-            Channel::new(client, 2),
-            Channel::new(client, 3),
-            Channel::new(client, 4),
-            Channel::new(client, 5),
-            Channel::new(client, 6),
-            Channel::new(client, 7),
-            Channel::new(client, 8),
-            Channel::new(client, 9),
-            Channel::new(client, 10),
-            Channel::new(client, 11),
-            Channel::new(client, 12),
-            Channel::new(client, 13),
-            Channel::new(client, 14),
-            Channel::new(client, 15),
+            Channel::new(0),
+            Channel::new(1),
+            Channel::new(2),
+            Channel::new(3),
+            Channel::new(4),
+            Channel::new(5),
+            Channel::new(6),
+            Channel::new(7),
+            Channel::new(8),
+            Channel::new(9),
+            Channel::new(10),
+            Channel::new(11),
+            Channel::new(12),
+            Channel::new(13),
+            Channel::new(14),
+            Channel::new(15),
         ];
 
-        // Build sequence we can trigger
-        let sequences = [
-            Sequence::new(0),
-            Sequence::new(1),
-            Sequence::new(2),
-            Sequence::new(3),
-            Sequence::new(4),
-        ];
+        // Build sequences we can trigger, one for every phrase
+        let sequences = (0 .. Channel::LOOPABLES).map(|index| Sequence::new(index as u8)).collect();
+
+        let outputs = (0 .. Self::OUTPUTS)
+            .map(|index| MidiOut::new(client, format!("channel_{}", index).as_str()))
+            .collect();
 
         Sequencer {
             channels,
             sequences,
+            outputs,
 
             sequence_playing: 0,
             sequence_queued: None,
             last_sequence_started: 0,
+
+            mixer: Mixer::new(),
+            changed_sequences: vec![false; Channel::LOOPABLES],
+            changed_tempo_map: false,
+            changed_mixer: false,
+            keyboard: Keyboard::new(client),
+            history: History::new(),
+            instruments: vec![],
+
+            clock: Clock::new(client),
+            clock_input: if sync_source == SyncSource::MidiClock { Some(ClockInput::new(client)) } else { None },
+
+            sync_source,
+            tempo_map: TempoMap::new(TimebaseHandler::DEFAULT_BEATS_PER_MINUTE, 4, 4),
+            timebase_sender,
+
+            written_lanes: vec![],
+
+            notices: vec![],
         }
     }
 
@@ -65,30 +131,323 @@ impl Sequencer {
     }
 
     pub fn get_sequence(&mut self, index: usize) -> &mut Sequence {
+        let sequences = &self.sequences;
+        self.history.remember(Part::Sequence(index), || ProjectChange::Sequence(index, sequences[index].clone()));
+        self.changed_sequences[index] = true;
         &mut self.sequences[index]
     }
 
+    // Loopables & sequences changed between beginning & ending an edit can be undone
+    pub fn begin_edit(&mut self) {
+        self.history.begin_edit();
+        self.channels.iter_mut().for_each(|channel| channel.begin_edit());
+    }
+
+    pub fn end_edit(&mut self) {
+        for edit in self.channels.iter_mut().filter_map(|channel| channel.end_edit()) {
+            self.history.merge(edit);
+        }
+        self.history.end_edit();
+    }
+
+    pub fn undo(&mut self) {
+        if let Some(edit) = self.history.take_undo() {
+            let redo = self.apply_changes(edit);
+            self.history.push_redo(redo);
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some(edit) = self.history.take_redo() {
+            let undo = self.apply_changes(edit);
+            self.history.push_undo(undo);
+        }
+    }
+
+    // Put back remembered parts, returns the parts they replaced so we can go back again
+    fn apply_changes(&mut self, changes: Vec<ProjectChange>) -> Vec<ProjectChange> {
+        changes.into_iter()
+            .map(|change| match change {
+                ProjectChange::Pattern(channel, index, pattern) => {
+                    ProjectChange::Pattern(channel, index, self.channels[channel].replace_pattern(index as u8, pattern))
+                },
+                ProjectChange::Phrase(channel, index, phrase) => {
+                    ProjectChange::Phrase(channel, index, self.channels[channel].replace_phrase(index as u8, phrase))
+                },
+                ProjectChange::Timeline(channel, timeline) => {
+                    ProjectChange::Timeline(channel, self.channels[channel].replace_timeline(timeline))
+                },
+                ProjectChange::Groove(channel, groove) => {
+                    ProjectChange::Groove(channel, self.channels[channel].replace_groove(groove))
+                },
+                ProjectChange::Routing(channel, routing) => {
+                    ProjectChange::Routing(channel, self.channels[channel].replace_routing(routing))
+                },
+                ProjectChange::Instrument(channel, name) => {
+                    let previous = self.channels[channel].instrument().map(|instrument| instrument.name.clone());
+                    let instrument = name.as_deref().and_then(|name| self.instrument(name));
+                    self.channels[channel].replace_instrument(instrument);
+                    ProjectChange::Instrument(channel, previous)
+                },
+                ProjectChange::Sequence(index, sequence) => {
+                    self.changed_sequences[index] = true;
+                    ProjectChange::Sequence(index, std::mem::replace(&mut self.sequences[index], sequence))
+                },
+                ProjectChange::TempoMap(tempo_map) => {
+                    ProjectChange::TempoMap(self.replace_tempo_map(tempo_map))
+                },
+                ProjectChange::Mixer(mixer) => {
+                    let previous = std::mem::replace(&mut self.mixer, mixer);
+                    self.changed_mixer = true;
+                    self.output_volumes(0);
+                    ProjectChange::Mixer(previous)
+                },
+            })
+            .collect()
+    }
+
+    // Copy of everything we need to restore the sequencer later on
+    pub fn project(&self) -> Project {
+        Project {
+            channels: self.channels.iter().map(|channel| channel.state()).collect(),
+            sequences: self.sequences.clone(),
+            tempo_map: self.tempo_map.clone(),
+            mixer: self.mixer.clone(),
+        }
+    }
+
+    // Changes since last time we asked, used for autosaving. Empty when nothing changed
+    pub fn take_changes(&mut self) -> Vec<ProjectChange> {
+        let mut changes: Vec<ProjectChange> = self.channels.iter_mut()
+            .flat_map(|channel| channel.take_changes())
+            .collect();
+
+        changes.extend(self.sequences.iter().enumerate()
+            .filter(|(index, _)| self.changed_sequences[*index])
+            .map(|(index, sequence)| ProjectChange::Sequence(index, sequence.clone())));
+        if self.changed_tempo_map {
+            changes.push(ProjectChange::TempoMap(self.tempo_map.clone()));
+        }
+        if self.changed_mixer {
+            changes.push(ProjectChange::Mixer(self.mixer.clone()));
+        }
+
+        self.clear_changes();
+        changes
+    }
+
+    // Forget what changed, used after loading or saving the whole project
+    pub fn clear_changes(&mut self) {
+        self.channels.iter_mut().for_each(|channel| channel.clear_changes());
+        self.changed_sequences.iter_mut().for_each(|changed| *changed = false);
+        self.changed_tempo_map = false;
+        self.changed_mixer = false;
+    }
+
+    pub fn load_project(&mut self, project: Project) {
+        for (index, state) in project.channels.into_iter().enumerate().take(self.channels.len()) {
+            let instrument = state.instrument.as_deref().and_then(|name| self.instrument(name));
+            self.channels[index].load_state(state);
+            self.channels[index].replace_instrument(instrument);
+        }
+        // Sequence buttons reach as many sequences as we start out with
+        for (index, loaded) in project.sequences.into_iter().enumerate().take(self.sequences.len()) {
+            self.sequences[index] = loaded;
+        }
+        self.replace_tempo_map(project.tempo_map);
+        self.mixer = project.mixer;
+        self.output_volumes(0);
+        self.clear_changes();
+    }
+
+    pub fn set_instruments(&mut self, instruments: Vec<Instrument>) {
+        self.instruments = instruments;
+    }
+
+    fn instrument(&mut self, name: &str) -> Option<Instrument> {
+        let instrument = self.instruments.iter().find(|instrument| instrument.name == name).cloned();
+
+        if instrument.is_none() {
+            self.notify(Notice::InstrumentMissing(name.to_string()));
+        }
+        instrument
+    }
+
+    // Channel keeps routing when instrument is removed or does not exist
+    pub fn set_instrument(&mut self, channel: usize, name: Option<&str>) {
+        let instrument = name.and_then(|name| self.instrument(name));
+        self.channels[channel].set_instrument(instrument);
+    }
+
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    fn mixer_mut(&mut self) -> &mut Mixer {
+        let mixer = &self.mixer;
+        self.history.remember(Part::Mixer, || ProjectChange::Mixer(mixer.clone()));
+        self.changed_mixer = true;
+        &mut self.mixer
+    }
+
+    pub fn fader_adjusted(&mut self, frame: u32, channel_index: usize, value: u8) {
+        self.mixer_mut().set_fader(channel_index, value);
+        self.output_volumes(frame);
+    }
+
+    pub fn master_adjusted(&mut self, frame: u32, value: u8) {
+        self.mixer_mut().set_master(value);
+        self.output_volumes(frame);
+    }
+
+    pub fn crossfader_adjusted(&mut self, frame: u32, value: u8) {
+        self.mixer_mut().set_crossfader(value);
+        self.output_volumes(frame);
+    }
+
+    pub fn set_crossfade_group(&mut self, channel_index: usize, group: Option<CrossfadeGroup>) {
+        self.mixer_mut().set_crossfade_group(channel_index, group);
+        self.output_volumes(0);
+    }
+
+    // Send volume to channels where it changed
+    fn output_volumes(&mut self, frame: u32) {
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            let volume = self.mixer.volume(index);
+
+            if channel.knob_value(Mixer::VOLUME_CONTROLLER) != volume {
+                channel.control_change(frame, Mixer::VOLUME_CONTROLLER, volume);
+            }
+        }
+    }
+
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    pub fn set_tempo_map(&mut self, tempo_map: TempoMap) {
+        let previous = &self.tempo_map;
+        self.history.remember(Part::TempoMap, || ProjectChange::TempoMap(previous.clone()));
+        self.replace_tempo_map(tempo_map);
+    }
+
+    // Swap tempo map without remembering it, used to undo & redo edits
+    fn replace_tempo_map(&mut self, tempo_map: TempoMap) -> TempoMap {
+        let previous = mem::replace(&mut self.tempo_map, tempo_map);
+        self.changed_tempo_map = true;
+
+        // Timebase handler is only called when we're master, nobody would receive the map otherwise
+        if self.is_timebase_master() {
+            let _ = self.timebase_sender.send(self.tempo_map.clone());
+        }
+        previous
+    }
+
+    fn clamp_beats_per_minute(beats_per_minute: f64) -> f64 {
+        // Round to 2 decimals, otherwise fine adjustments add up rounding errors
+        let beats_per_minute = (beats_per_minute * 100.0).round() / 100.0;
+        beats_per_minute.max(Self::MIN_BEATS_PER_MINUTE).min(Self::MAX_BEATS_PER_MINUTE)
+    }
+
+    pub fn beats_per_minute(&self, tick: u32) -> f64 {
+        self.tempo_map.beats_per_minute_at(tick)
+    }
+
+    pub fn is_timebase_master(&self) -> bool {
+        self.sync_source != SyncSource::Timebase
+    }
+
+    // Change tempo of the tempo map segment we're playing
+    pub fn set_beats_per_minute(&mut self, tick: u32, beats_per_minute: f64) {
+        match self.sync_source {
+            SyncSource::Internal => self.change_beats_per_minute(tick, beats_per_minute),
+            _ => self.notify(Notice::TempoFollowed(self.sync_source)),
+        }
+    }
+
+    fn change_beats_per_minute(&mut self, tick: u32, beats_per_minute: f64) {
+        let beats_per_minute = Self::clamp_beats_per_minute(beats_per_minute);
+
+        // Don't bother timebase handler when nothing changed
+        if beats_per_minute != self.tempo_map.beats_per_minute_at(tick) {
+            let mut tempo_map = self.tempo_map.clone();
+            tempo_map.set_beats_per_minute_at(tick, beats_per_minute);
+            self.set_tempo_map(tempo_map);
+        }
+    }
+
+    // Follow transport & tempo of incoming midi clock
+    pub fn process_clock_input(&mut self, cycle: &ProcessCycle) {
+        let events = match &mut self.clock_input {
+            Some(clock_input) => clock_input.process(cycle),
+            None => return,
+        };
+
+        for event in events {
+            match event {
+                ClockEvent::Start => {
+                    self.reset(cycle);
+                    self.start(cycle);
+                },
+                ClockEvent::Continue => self.start(cycle),
+                ClockEvent::Stop => self.stop(cycle),
+                ClockEvent::SongPosition(tick) => self.reposition(cycle, tick),
+                ClockEvent::BeatsPerMinute(beats_per_minute) => self.change_beats_per_minute(cycle.tick_range.start, beats_per_minute),
+            }
+        }
+    }
+
+    // Add tempo change at start of bar, or remove it when no tempo is given
+    pub fn set_tempo(&mut self, bar: u32, beats_per_minute: Option<f64>, is_ramp: bool) {
+        let mut tempo_map = self.tempo_map.clone();
+        let tick = tempo_map.bar_to_tick(bar);
+
+        match beats_per_minute {
+            Some(beats_per_minute) => tempo_map.set_tempo(tick, Self::clamp_beats_per_minute(beats_per_minute), is_ramp),
+            None => tempo_map.remove_tempo(tick),
+        }
+
+        self.set_tempo_map(tempo_map);
+    }
+
+    // Add meter change (beats per bar & beat type) at bar, or remove it when no meter is given
+    pub fn set_meter(&mut self, bar: u32, meter: Option<(u8, u8)>) {
+        let mut tempo_map = self.tempo_map.clone();
+
+        match meter {
+            Some((beats_per_bar, beat_type)) => tempo_map.set_meter(bar, beats_per_bar, beat_type),
+            None => tempo_map.remove_meter(bar),
+        }
+
+        self.set_tempo_map(tempo_map);
+    }
+
     pub fn start(&mut self, cycle: &ProcessCycle) {
         // Start playing notes, as it could be we halted mid channel
         self.channels.iter_mut().for_each(|channel| {
-            channel.start_playing_notes(cycle);
+            channel.start_playing_notes();
         });
 
-        cycle.client.transport_start();
+        cycle.transport.start();
     }
 
     pub fn stop(&mut self, cycle: &ProcessCycle) {
-        cycle.client.transport_stop();
+        cycle.transport.stop();
 
         // Output start of playing notes, as it could be we're starting mid channel
         self.channels.iter_mut().for_each(|channel| {
-            channel.stop_playing_notes(cycle);
+            channel.stop_playing_notes();
         });
     }
 
     pub fn reset(&mut self, cycle: &ProcessCycle) {
-        // Reset position back to 0
-        cycle.client.transport_reposition(jack::Position::default());
+        self.reposition(cycle, 0);
+    }
+
+    // Move transport to tick
+    pub fn reposition(&mut self, cycle: &ProcessCycle, tick: u32) {
+        let frame = self.tempo_map.seconds_at_tick(tick as f64) * cycle.transport.frame_rate() as f64;
+        cycle.transport.reposition(frame as u32);
 
         // Clear playing notes
         self.channels.iter_mut().for_each(|channel| {
@@ -98,7 +457,7 @@ impl Sequencer {
 
     pub fn reset_timeline(&mut self) {
         self.channels.iter_mut().for_each(|channel| {
-            channel.timeline.clear_events();
+            channel.timeline_mut().clear_events();
         });
     }
 
@@ -124,7 +483,7 @@ impl Sequencer {
                 let phrase_stop = if phrase_start + phrase_length > stop { stop } else { phrase_start + phrase_length };
 
                 let event = LoopablePhraseEvent::new(phrase_start, phrase_stop, phrase_index);
-                self.channel_mut(channel_index).timeline.add_complete_event(event);
+                self.channel_mut(channel_index).add_timeline_event(event);
 
                 phrase_start += phrase_length;
             }
@@ -214,37 +573,252 @@ impl Sequencer {
             .collect()
     }
 
+    // Get notes starting in tick_range for channel, based on timeline, phrases & patterns
+    pub fn starting_notes(&self, channel_index: usize, tick_range: &TickRange) -> Vec<PlayingNoteEvent> {
+        let playing_phrases = self.playing_phrases(channel_index, tick_range);
+
+        playing_phrases.into_iter()
+            .flat_map(|(tick_range, sequence_start, phrase_index)| {
+                // TODO - Make the switch to first getting pattern events, then converting
+                // those to notes
+                self.playing_patterns(&tick_range, channel_index, phrase_index, sequence_start).into_iter()
+                    .flat_map(move |(pattern_index, absolute_start, relative_range, pattern_event_length, absolute_offset)| {
+                        let channel = &self.channels[channel_index];
+                        let pattern = channel.pattern(pattern_index);
+                        let groove = pattern.groove.as_ref().or(channel.groove.as_ref());
+
+                        // Get pattern based starting notes, and add offset based on phrase
+                        // iteration & sequence start
+                        pattern.starting_notes(absolute_start, relative_range, pattern_event_length).into_iter()
+                            .map(move |mut playing_note| {
+                                playing_note.start += absolute_offset;
+                                playing_note.stop += absolute_offset;
+
+                                // Patterns stay on the grid, groove moves notes when they're played
+                                if let Some(groove) = groove {
+                                    let (delay, velocity) = groove.apply(playing_note.start, playing_note.start_velocity);
+                                    playing_note.start = playing_note.start.saturating_add(delay);
+                                    playing_note.stop = playing_note.stop.saturating_add(delay);
+                                    playing_note.start_velocity = velocity;
+                                }
+
+                                playing_note
+                            })
+                    })
+            })
+            .collect()
+    }
+
+    // Tick in pattern at absolute tick, follows the arrangement when pattern is playing
+    pub fn pattern_tick(&self, channel_index: usize, pattern_index: u8, tick: u32) -> u32 {
+        let pattern = self.channel(channel_index).pattern(pattern_index);
+        let tick_range = TickRange::new(tick, tick + 1);
+
+        let playing = self.playing_phrases(channel_index, &tick_range).into_iter()
+            .flat_map(|(tick_range, sequence_start, phrase_index)| self.playing_patterns(&tick_range, channel_index, phrase_index, sequence_start))
+            .find(|(index, _, _, _, _)| *index == pattern_index);
+
+        let pattern_tick = match playing {
+            Some((_, absolute_start, relative_range, _, _)) => relative_range.start + tick - absolute_start,
+            None => tick,
+        };
+
+        pattern_tick % pattern.length()
+    }
+
+    /*
+     * Play notes of keyboard on channel, and record them into pattern while recording
+     */
+    pub fn process_keyboard_input(&mut self, cycle: &ProcessCycle, channel_index: usize, pattern_index: u8) {
+        let is_recording = self.keyboard.is_recording() && cycle.is_rolling;
+
+        for event in self.keyboard.events(cycle) {
+            match event {
+                KeyboardEvent::NoteOn { frame, note, velocity } => {
+                    self.channels[channel_index].note(frame, 0x90, note, velocity);
+
+                    if is_recording {
+                        let tick = self.pattern_tick(channel_index, pattern_index, cycle.tick_at_frame(frame));
+                        self.keyboard.note_on(tick, note, velocity);
+                    }
+                },
+                KeyboardEvent::NoteOff { frame, note, velocity } => {
+                    self.channels[channel_index].note(frame, 0x80, note, velocity);
+
+                    // Every recorded note can be undone
+                    if is_recording && self.keyboard.is_held(note) {
+                        let tick = self.pattern_tick(channel_index, pattern_index, cycle.tick_at_frame(frame));
+                        self.begin_edit();
+                        let pattern = self.channels[channel_index].pattern_mut(pattern_index);
+                        self.keyboard.note_off(pattern, tick, note, velocity);
+                        self.end_edit();
+                    }
+                },
+            }
+        }
+
+        if is_recording && self.keyboard.record_mode() == RecordMode::Replace {
+            let start = self.pattern_tick(channel_index, pattern_index, cycle.tick_range.start);
+            let pattern = self.channels[channel_index].pattern(pattern_index);
+            let ranges = pattern.looping_ranges(&TickRange::new(start, start + cycle.ticks()));
+
+            // Notes replaced in a cycle are undone together, only edit when there's something to replace
+            if ranges.iter().any(|(range, _)| self.keyboard.replaces(pattern, range)) {
+                self.begin_edit();
+                let pattern = self.channels[channel_index].pattern_mut(pattern_index);
+
+                for (range, _) in ranges {
+                    self.keyboard.replace(pattern, &range);
+                }
+                self.end_edit();
+            }
+        }
+    }
+
+    // Get recorded control events in tick_range for channel
+    pub fn control_events(&self, channel_index: usize, tick_range: &TickRange) -> Vec<ControlEvent> {
+        self.playing_phrases(channel_index, tick_range).into_iter()
+            .flat_map(|(tick_range, sequence_start, phrase_index)| {
+                let phrase = self.channel(channel_index).phrase(phrase_index);
+                let relative_range = TickRange::new(tick_range.start - sequence_start, tick_range.stop - sequence_start);
+
+                phrase.control_events(&relative_range, sequence_start)
+            })
+            .collect()
+    }
+
+    /*
+     * Effect knob turned, pass it on to channel & record it into playing phrases of channel when
+     * we're rolling
+     */
+    pub fn knob_turned(&mut self, cycle: &ProcessCycle, frame: u32, channel_index: usize, knob: u8, value: u8) {
+        let controller = Channel::KNOB_CONTROLLER_OFFSET + knob;
+        self.channels[channel_index].control_change(frame, controller, value);
+
+        if cycle.is_rolling {
+            let tick = cycle.tick_at_frame(frame);
+
+            for (_, sequence_start, phrase_index) in self.playing_phrases(channel_index, &TickRange::new(tick, tick + 1)) {
+                let phrase = self.channels[channel_index].phrase_mut(phrase_index);
+                let relative_tick = (tick - sequence_start) % phrase.length();
+                phrase.record_control(relative_tick, controller, value);
+            }
+
+            // Hold back playback of the lane from this cycle on, including what we just recorded
+            let ticks = TickRange::new(cycle.tick_range.start, tick.saturating_add(Self::LANE_RELEASE_TICKS));
+            self.written_lanes.retain(|(index, lane_controller, _)| *index != channel_index || *lane_controller != controller);
+            self.written_lanes.push((channel_index, controller, ticks));
+        }
+    }
+
+    /*
+     * Get all notes in the timeline for every channel. We walk the timeline in steps of a beat, as
+     * playing_phrases & playing_patterns expect ranges the size of a process cycle
+     */
+    pub fn arrangement(&self) -> Vec<Vec<PlayingNoteEvent>> {
+        let timeline_end = self.get_timeline_end();
+        let ticks_per_step = TimebaseHandler::TICKS_PER_BEAT as u32;
+
+        (0 .. self.channels.len())
+            .map(|channel_index| {
+                (0 .. timeline_end).step_by(ticks_per_step as usize)
+                    .flat_map(|start| self.starting_notes(channel_index, &TickRange::new(start, start + ticks_per_step)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn set_routing(&mut self, channel: usize, routing: Routing) {
+        self.channels[channel].set_routing(routing);
+    }
+
     // TODO - Direct queueing
     pub fn output_midi(&mut self, cycle: &ProcessCycle) {
-        if ! cycle.is_rolling {
-            return
-        }
+        self.clock.output_midi(cycle);
+
+        // Jack clears a port every time we write to it, collect messages of channels sharing a port
+        let mut messages: Vec<Vec<TimedMessage>> = self.outputs.iter().map(|_| vec![]).collect();
 
         for channel_index in 0 .. self.channels.len() {
-            let playing_phrases = self.playing_phrases(channel_index, &cycle.tick_range);
-
-            //let mut starting_notes = vec![];
-            let notes: Vec<PlayingNoteEvent> = playing_phrases.into_iter()
-                .flat_map(|(tick_range, sequence_start, phrase_index)| {
-                    // TODO - Make the switch to first getting pattern events, then converting
-                    // those to notes
-                    self.playing_patterns(&tick_range, channel_index, phrase_index, sequence_start).into_iter()
-                        .flat_map(|(pattern_index, absolute_start, relative_range, pattern_event_length, absolute_offset)| {
-                            let pattern = self.channels[channel_index].pattern(pattern_index);
-
-                            // Get pattern based starting notes, and add offset based on phrase
-                            // iteration & sequence start
-                            pattern.starting_notes(absolute_start, relative_range, pattern_event_length).into_iter()
-                                .map(move |mut playing_note| {
-                                    playing_note.start += absolute_offset;
-                                    playing_note.stop += absolute_offset;
-                                    playing_note
-                                })
-                        })
-                })
-                .collect();
-
-            self.channels[channel_index].output_midi(cycle, notes);
+            if cycle.is_rolling {
+                let notes = self.starting_notes(channel_index, &cycle.tick_range);
+                let control_events = self.control_events(channel_index, &cycle.tick_range).into_iter()
+                    .filter(|event| ! self.is_lane_written(channel_index, event))
+                    .collect();
+                self.channels[channel_index].output_midi(cycle, notes, control_events);
+            }
+
+            let channel = &mut self.channels[channel_index];
+            messages[channel.routing().port].append(&mut channel.take_messages());
+        }
+
+        for (output, mut messages) in self.outputs.iter_mut().zip(messages) {
+            output.write_midi(cycle, &mut messages);
         }
+
+        // Lanes are released when their knob rests, or when transport stops or jumps back
+        if cycle.is_rolling {
+            let tick_range = cycle.tick_range;
+            self.written_lanes.retain(|(_, _, ticks)| ticks.start <= tick_range.start && ticks.stop > tick_range.stop);
+        } else {
+            self.written_lanes.clear();
+        }
+    }
+
+    fn is_lane_written(&self, channel_index: usize, event: &ControlEvent) -> bool {
+        self.written_lanes.iter()
+            .any(|(index, controller, ticks)| *index == channel_index && *controller == event.controller && ticks.contains(event.tick))
+    }
+
+    pub fn notify(&mut self, notice: Notice) {
+        self.notices.push(notice);
+    }
+
+    // Notices since last time we asked
+    pub fn take_notices(&mut self) -> Vec<Notice> {
+        mem::take(&mut self.notices)
+    }
+
+    // Messages outputs collected while rendering without jack, with the output they were written to
+    pub fn take_rendered(&mut self) -> Vec<(usize, TimedMessage)> {
+        // Nobody listens to the clock offline
+        self.clock.take_rendered();
+
+        self.outputs.iter_mut().enumerate()
+            .flat_map(|(port, output)| output.take_rendered().into_iter().map(move |message| (port, message)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn undo() {
+        let (sender, _receiver) = mpsc::channel();
+        let mut sequencer = Sequencer::new(None, sender, SyncSource::Internal);
+        let tick = sequencer.tempo_map().bar_to_tick(4);
+        let routing = sequencer.channel(2).routing();
+
+        sequencer.begin_edit();
+        sequencer.set_tempo(4, Some(90.0), false);
+        sequencer.end_edit();
+        sequencer.begin_edit();
+        sequencer.set_routing(2, Routing::new(5, 9, 12));
+        sequencer.end_edit();
+
+        sequencer.undo();
+        assert_eq!(sequencer.channel(2).routing(), routing);
+        assert_eq!(sequencer.beats_per_minute(tick), 90.0);
+
+        sequencer.undo();
+        assert_eq!(sequencer.beats_per_minute(tick), TimebaseHandler::DEFAULT_BEATS_PER_MINUTE);
+
+        sequencer.redo();
+        sequencer.redo();
+        assert_eq!(sequencer.beats_per_minute(tick), 90.0);
+        assert_eq!(sequencer.channel(2).routing(), Routing::new(5, 9, 12));
     }
 }
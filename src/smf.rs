@@ -0,0 +1,476 @@
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::convert::TryFrom;
+use std::path::Path;
+use super::channel::Channel;
+use super::events::*;
+use super::loopable::*;
+use super::tempo::TempoMap;
+use super::TimebaseHandler;
+
+pub struct TrackEvent {
+    pub tick: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl TrackEvent {
+    // Note offs go before note ons on the same tick, otherwise repeated notes get cut short
+    fn order(&self) -> (u32, u8) {
+        let is_note_on = self.bytes[0] & 0xF0 == 0x90 && self.bytes.len() > 2 && self.bytes[2] > 0;
+        (self.tick, is_note_on as u8)
+    }
+}
+
+pub struct Track {
+    pub events: Vec<TrackEvent>,
+}
+
+impl Track {
+    pub fn new() -> Self {
+        Track { events: vec![] }
+    }
+
+    pub fn push(&mut self, tick: u32, bytes: Vec<u8>) {
+        self.events.push(TrackEvent { tick, bytes });
+    }
+
+    pub fn push_meta(&mut self, tick: u32, meta_type: u8, data: &[u8]) {
+        let mut bytes = vec![0xFF, meta_type];
+        write_variable_length(data.len() as u32, &mut bytes);
+        bytes.extend_from_slice(data);
+        self.push(tick, bytes);
+    }
+
+    fn read(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader { bytes, position: 0 };
+        let mut track = Track::new();
+        let mut tick = 0;
+        let mut running_status = 0;
+
+        while reader.position < bytes.len() {
+            tick = reader.variable_length()?.checked_add(tick)
+                .ok_or_else(|| invalid("track is too long"))?;
+            let mut status = reader.u8()?;
+
+            match status {
+                0xFF => {
+                    let meta_type = reader.u8()?;
+                    let length = reader.variable_length()? as usize;
+                    let data = reader.take(length)?;
+
+                    // We write our own end of track
+                    if meta_type != 0x2F {
+                        track.push_meta(tick, meta_type, data);
+                    }
+                },
+                0xF0 | 0xF7 => {
+                    let length = reader.variable_length()? as usize;
+                    let mut message = vec![status];
+                    message.extend_from_slice(reader.take(length)?);
+                    track.push(tick, message);
+                },
+                _ => {
+                    // Running status, data byte without status byte uses status of previous event
+                    if status < 0x80 {
+                        reader.position -= 1;
+                        status = running_status;
+                    }
+                    if status < 0x80 {
+                        return Err(invalid("data byte without status"));
+                    }
+                    running_status = status;
+
+                    let data_length = if (0xC0 ..= 0xDF).contains(&status) { 1 } else { 2 };
+                    let mut message = vec![status];
+                    message.extend_from_slice(reader.take(data_length)?);
+                    track.push(tick, message);
+                },
+            }
+        }
+
+        Ok(track)
+    }
+
+    fn write(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        self.events.sort_by_key(|event| event.order());
+
+        let mut data = vec![];
+        let mut previous_tick = 0;
+
+        for event in self.events.iter() {
+            write_variable_length(event.tick - previous_tick, &mut data);
+            data.extend_from_slice(&event.bytes);
+            previous_tick = event.tick;
+        }
+
+        // End of track
+        data.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+        writer.write_all(b"MTrk")?;
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(&data)
+    }
+}
+
+/*
+ * Standard MIDI file, we always write type 1 files with a tempo track followed by a track for
+ * every channel
+ */
+pub struct MidiFile {
+    pub ticks_per_beat: u16,
+    pub tracks: Vec<Track>,
+}
+
+impl MidiFile {
+    pub fn new(ticks_per_beat: u16) -> Self {
+        MidiFile { ticks_per_beat, tracks: vec![] }
+    }
+
+    // Create file from notes that were played for every channel
+    pub fn from_arrangement(channels: Vec<Vec<PlayingNoteEvent>>, tempo_map: &TempoMap) -> Self {
+        let mut file = MidiFile::new(TimebaseHandler::TICKS_PER_BEAT as u16);
+        let ticks_per_beat = TimebaseHandler::TICKS_PER_BEAT as u32;
+
+        let mut tempo_track = Track::new();
+        let tempo_changes = tempo_map.tempo_changes();
+        for (index, change) in tempo_changes.iter().enumerate() {
+            // Midi files can't ramp tempo, write a tempo change every beat instead
+            let ticks: Vec<u32> = match tempo_changes.get(index + 1) {
+                Some(next) if change.is_ramp => (change.tick .. next.tick).step_by(ticks_per_beat as usize).collect(),
+                _ => vec![change.tick],
+            };
+
+            for tick in ticks {
+                let usecs_per_beat = (60000000.0 / tempo_map.beats_per_minute_at(tick)) as u32;
+                tempo_track.push_meta(tick, 0x51, &usecs_per_beat.to_be_bytes()[1..]);
+            }
+        }
+
+        for change in tempo_map.meter_changes() {
+            // Denominator is written as power of 2, 24 clocks per click & 8 32nd notes per beat
+            let denominator = change.beat_type.trailing_zeros() as u8;
+            tempo_track.push_meta(tempo_map.bar_to_tick(change.bar), 0x58, &[change.beats_per_bar, denominator, 24, 8]);
+        }
+        file.tracks.push(tempo_track);
+
+        for (index, notes) in channels.into_iter().enumerate() {
+            let mut track = Track::new();
+            track.push_meta(0, 0x03, format!("channel {}", index).as_bytes());
+
+            for note in notes {
+                track.push(note.start, vec![0x90 + index as u8, note.note, note.start_velocity]);
+                track.push(note.stop, vec![0x80 + index as u8, note.note, note.stop_velocity]);
+            }
+
+            file.tracks.push(track);
+        }
+
+        file
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read(&fs::read(path)?)
+    }
+
+    pub fn read(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(4)? != b"MThd" {
+            return Err(invalid("not a standard midi file"));
+        }
+        let header_length = reader.u32()? as usize;
+        let _format = reader.u16()?;
+        let track_count = reader.u16()?;
+        let ticks_per_beat = reader.u16()?;
+        // Skip header bytes we don't know about
+        reader.take(header_length.saturating_sub(6))?;
+
+        if ticks_per_beat & 0x8000 != 0 {
+            return Err(invalid("SMPTE timed files are not supported"));
+        }
+        // Ticks of events are divided by this when converting them to our resolution
+        if ticks_per_beat == 0 {
+            return Err(invalid("file has no ticks per beat"));
+        }
+
+        let mut file = MidiFile::new(ticks_per_beat);
+
+        while file.tracks.len() < track_count as usize {
+            let chunk_type = reader.take(4)?;
+            let length = reader.u32()? as usize;
+            let chunk = reader.take(length)?;
+
+            // Skip chunks that are not tracks
+            if chunk_type == b"MTrk" {
+                file.tracks.push(Track::read(chunk)?);
+            }
+        }
+
+        Ok(file)
+    }
+
+    /*
+     * Get complete notes of track, with ticks converted to our own resolution
+     */
+    pub fn notes(&self, track_index: usize) -> io::Result<Vec<LoopableNoteEvent>> {
+        let mut notes = vec![];
+        let mut playing: Vec<(u8, LoopableNoteEvent)> = vec![];
+        let ticks_per_beat = TimebaseHandler::TICKS_PER_BEAT as u64;
+        let track = match self.tracks.get(track_index) { Some(track) => track, None => return Ok(notes) };
+
+        for event in track.events.iter().filter(|event| event.bytes.len() == 3) {
+            // Files with less ticks per beat than us could hold ticks we can't store
+            let tick = u32::try_from(event.tick as u64 * ticks_per_beat / self.ticks_per_beat as u64)
+                .map_err(|_| invalid("track is too long"))?;
+            let channel = event.bytes[0] & 0x0F;
+            let (note, velocity) = (event.bytes[1], event.bytes[2]);
+
+            match event.bytes[0] & 0xF0 {
+                0x90 if velocity > 0 => playing.push((channel, LoopableNoteEvent::new(tick, note, velocity))),
+                // Note on with velocity 0 is a note off
+                0x80 | 0x90 => {
+                    let index = playing.iter().position(|(playing_channel, playing_note)| *playing_channel == channel && playing_note.note == note);
+
+                    if let Some(index) = index {
+                        let (_, mut playing_note) = playing.remove(index);
+                        playing_note.stop = Some(tick);
+                        playing_note.stop_velocity = Some(if event.bytes[0] & 0xF0 == 0x80 { velocity } else { 64 });
+                        notes.push(playing_note);
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        notes.sort_by_key(|note| note.start);
+        Ok(notes)
+    }
+
+    // First track that contains notes, type 1 files usually start with a tempo track. Tracks we
+    // can't read count as well, so asking for their patterns reports why
+    pub fn first_note_track(&self) -> Option<usize> {
+        (0 .. self.tracks.len()).find(|index| self.notes(*index).map(|notes| ! notes.is_empty()).unwrap_or(true))
+    }
+
+    /*
+     * Put notes of track in patterns. When split_length is given, the track is cut in patterns of
+     * that length, otherwise we put the whole track in 1 pattern
+     */
+    pub fn patterns(&self, track_index: usize, split_length: Option<u32>) -> io::Result<Vec<Pattern>> {
+        let notes = self.notes(track_index)?;
+        let last_stop = notes.iter().filter_map(|note| note.stop).max().unwrap_or(0);
+        // Round length up to whole bars
+        let bars = last_stop.checked_add(Pattern::minimum_length() - 1)
+            .ok_or_else(|| invalid("track is too long"))? / Pattern::minimum_length();
+        let track_length = bars.max(1) * Pattern::minimum_length();
+        let pattern_length = split_length.filter(|length| *length > 0).unwrap_or(track_length);
+        let pattern_count = (track_length - 1) / pattern_length + 1;
+
+        // Don't build patterns that could never fit in a channel
+        if pattern_count as usize > Channel::LOOPABLES {
+            return Err(invalid(&format!("track splits in {} patterns, a channel holds {}", pattern_count, Channel::LOOPABLES)));
+        }
+
+        let patterns = (0 .. pattern_count)
+            .map(|index| {
+                let start = index * pattern_length;
+                let mut pattern = Pattern::new();
+                pattern.set_length(pattern_length);
+
+                for note in notes.iter().filter(|note| note.start >= start && note.start - start < pattern_length) {
+                    let mut note = *note;
+                    note.start -= start;
+                    // Notes that cross the end of the pattern are cut short
+                    note.stop = Some((note.stop.unwrap() - start).min(pattern_length));
+                    pattern.add_complete_event(note);
+                }
+
+                pattern
+            })
+            .collect();
+
+        Ok(patterns)
+    }
+
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(b"MThd")?;
+        writer.write_all(&6u32.to_be_bytes())?;
+        writer.write_all(&1u16.to_be_bytes())?;
+        writer.write_all(&(self.tracks.len() as u16).to_be_bytes())?;
+        writer.write_all(&self.ticks_per_beat.to_be_bytes())?;
+
+        for track in self.tracks.iter_mut() {
+            track.write(writer)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.position + length > self.bytes.len() {
+            return Err(invalid("unexpected end of file"));
+        }
+
+        let bytes = &self.bytes[self.position .. self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> { Ok(self.take(1)?[0]) }
+    fn u16(&mut self) -> io::Result<u16> { let bytes = self.take(2)?; Ok(u16::from_be_bytes([bytes[0], bytes[1]])) }
+    fn u32(&mut self) -> io::Result<u32> { let bytes = self.take(4)?; Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])) }
+
+    fn variable_length(&mut self) -> io::Result<u32> {
+        let mut value = 0;
+
+        loop {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Variable length quantity, 7 bits per byte, most significant bit set on all but the last byte
+fn write_variable_length(value: u32, bytes: &mut Vec<u8>) {
+    let mut buffer = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+
+    while value > 0 {
+        buffer.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+
+    bytes.extend(buffer.into_iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variable_length() {
+        let encode = |value| { let mut bytes = vec![]; write_variable_length(value, &mut bytes); bytes };
+
+        assert_eq!(encode(0), vec![0x00]);
+        assert_eq!(encode(0x7F), vec![0x7F]);
+        assert_eq!(encode(0x80), vec![0x81, 0x00]);
+        assert_eq!(encode(1920), vec![0x8F, 0x00]);
+        assert_eq!(encode(0x0FFFFFFF), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn write_arrangement() {
+        let note = PlayingNoteEvent { start: 0, stop: 1920, note: 60, start_velocity: 100, stop_velocity: 64 };
+        let mut file = MidiFile::from_arrangement(vec![vec![note]], &TempoMap::new(120.0, 4, 4));
+
+        let mut bytes = vec![];
+        file.write(&mut bytes).unwrap();
+
+        assert_eq!(&bytes[0 .. 4], b"MThd");
+        // Type 1, tempo track + 1 channel, 1920 ticks per beat
+        assert_eq!(&bytes[8 .. 14], &[0x00, 0x01, 0x00, 0x02, 0x07, 0x80]);
+        // 500000 usecs per beat
+        assert_eq!(&bytes[22 .. 29], &[0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]);
+        // Note on, followed by note off a beat later
+        let track = &bytes[bytes.len() - 13 ..];
+        assert_eq!(track, &[0x00, 0x90, 60, 100, 0x8F, 0x00, 0x80, 60, 64, 0x00, 0xFF, 0x2F, 0x00]);
+    }
+
+    #[test]
+    fn read_notes() {
+        // Track with 96 ticks per beat, using running status & note on with velocity 0 as note off
+        let mut bytes = vec![];
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1, 0, 96]);
+        let track = [0x00, 0x91, 36, 100, 0x60, 36, 0, 0x00, 38, 90, 0x30, 0x81, 38, 20, 0x00, 0xFF, 0x2F, 0x00];
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+
+        let file = MidiFile::read(&bytes).unwrap();
+        let notes = file.notes(0).unwrap();
+        let beat = TimebaseHandler::TICKS_PER_BEAT as u32;
+
+        assert_eq!(notes.len(), 2);
+        assert_eq!((notes[0].note, notes[0].start, notes[0].stop, notes[0].start_velocity), (36, 0, Some(beat), 100));
+        assert_eq!((notes[1].note, notes[1].start, notes[1].stop, notes[1].stop_velocity), (38, beat, Some(beat * 3 / 2), Some(20)));
+    }
+
+    #[test]
+    fn read_invalid() {
+        let file = |division: u8, track: &[u8]| {
+            let mut bytes = vec![];
+            bytes.extend_from_slice(b"MThd");
+            bytes.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1, 0, division]);
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+            bytes
+        };
+
+        assert!(MidiFile::read(&file(96, &[0x00, 0xFF, 0x2F, 0x00])).is_ok());
+        assert!(MidiFile::read(&file(0, &[0x00, 0xFF, 0x2F, 0x00])).is_err());
+
+        // Deltas that add up past the largest tick we can store
+        let mut track = vec![];
+        for _ in 0 .. 20 {
+            track.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x7F, 0x90, 60, 100]);
+        }
+        assert!(MidiFile::read(&file(96, &track)).is_err());
+
+        // Tick fits file resolution, but not ours
+        let file = MidiFile::read(&file(1, &[0xFF, 0xFF, 0xFF, 0x7F, 0x90, 60, 100, 0x01, 0x80, 60, 64])).unwrap();
+        assert!(file.notes(0).is_err());
+        assert!(file.patterns(0, None).is_err());
+    }
+
+    #[test]
+    fn split_patterns() {
+        let bar = Pattern::minimum_length();
+        let notes = vec![
+            PlayingNoteEvent { start: 0, stop: bar / 2, note: 36, start_velocity: 100, stop_velocity: 64 },
+            PlayingNoteEvent { start: bar + bar / 2, stop: bar * 2 + 10, note: 38, start_velocity: 100, stop_velocity: 64 },
+        ];
+        let mut file = MidiFile::from_arrangement(vec![notes], &TempoMap::new(120.0, 4, 4));
+        let mut bytes = vec![];
+        file.write(&mut bytes).unwrap();
+
+        let file = MidiFile::read(&bytes).unwrap();
+        assert_eq!(file.first_note_track(), Some(1));
+
+        let patterns = file.patterns(1, None).unwrap();
+        assert_eq!(patterns.len(), 1);
+        assert_eq!(patterns[0].length(), bar * 3);
+
+        let patterns = file.patterns(1, Some(bar)).unwrap();
+        assert_eq!(patterns.len(), 3);
+        assert_eq!(patterns[1].note_events[0].start, bar / 2);
+        assert_eq!(patterns[1].note_events[0].stop, Some(bar));
+        assert_eq!(patterns[2].note_events.len(), 0);
+
+        // More patterns than a channel holds
+        assert!(file.patterns(1, Some(bar / 16)).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::SystemTime;
+use super::options::SyncSource;
+use super::project::{Project, ProjectChange};
+use super::sequencer::Sequencer;
+use super::smf::MidiFile;
+
+pub enum StorageRequest {
+    Save(PathBuf, Project),
+    // Changes since last autosave, these are applied to the autosave copy of the project
+    Autosave(Vec<ProjectChange>),
+    // Timeline is rendered here, as walking all of it takes too long for the process cycle
+    Export(PathBuf, Project),
+}
+
+/*
+ * Autosave keeps a copy of the project that is kept up to date with changes from the process
+ * thread. Every time we receive changes, a snapshot is written to the autosave directory
+ */
+pub struct Autosave {
+    directory: PathBuf,
+    project: Project,
+}
+
+impl Autosave {
+    // Keep some snapshots around in case the last one was written while things were already going wrong
+    const SNAPSHOTS: usize = 10;
+    const PREFIX: &'static str = "snapshot-";
+
+    pub fn new(directory: PathBuf, project: Project) -> Self {
+        Autosave { directory, project }
+    }
+
+    // Autosave directory that belongs to project file
+    pub fn directory(project_path: &Path) -> PathBuf {
+        let mut directory = project_path.as_os_str().to_owned();
+        directory.push(".autosave");
+        PathBuf::from(directory)
+    }
+
+    fn snapshots(directory: &Path) -> Vec<(SystemTime, PathBuf)> {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        let mut snapshots: Vec<(SystemTime, PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name().and_then(|name| name.to_str())
+                    .map(|name| name.starts_with(Self::PREFIX))
+                    .unwrap_or(false)
+            })
+            .filter_map(|path| {
+                fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()
+                    .map(|modified| (modified, path))
+            })
+            .collect();
+
+        snapshots.sort();
+        snapshots
+    }
+
+    // Most recent snapshot, only when it was written after the project was last saved. Snapshots
+    // are removed when saving, so those left over hold changes the last session did not save
+    pub fn latest_snapshot(project_path: &Path) -> Option<(SystemTime, PathBuf)> {
+        let saved = fs::metadata(project_path).and_then(|metadata| metadata.modified()).ok();
+
+        Self::snapshots(&Self::directory(project_path)).pop()
+            .filter(|(modified, _)| saved.map(|saved| *modified > saved).unwrap_or(true))
+    }
+
+    // Nothing changed means nothing new to snapshot
+    pub fn apply(&mut self, changes: Vec<ProjectChange>) {
+        if changes.is_empty() {
+            return;
+        }
+
+        for change in changes {
+            self.project.apply(change);
+        }
+
+        if let Err(e) = self.write_snapshot() {
+            println!("Error: could not write autosave snapshot to {:?}: {}", self.directory, e);
+        }
+    }
+
+    // Project file holds everything our snapshots do once it's saved
+    fn remove_snapshots(&self) -> std::io::Result<()> {
+        for (_, path) in Self::snapshots(&self.directory) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn write_snapshot(&self) -> std::io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+
+        let seconds = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        self.project.save(&self.directory.join(format!("{}{}.project", Self::PREFIX, seconds)))?;
+
+        // Remove oldest snapshots
+        let snapshots = Self::snapshots(&self.directory);
+        if snapshots.len() > Self::SNAPSHOTS {
+            for (_, path) in snapshots[.. snapshots.len() - Self::SNAPSHOTS].iter() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/*
+ * Storage writes files from it's own thread, the process handler only hands over a copy of the
+ * sequencer state as we can't do file I/O in the process cycle
+ */
+pub struct Storage {
+    request_receive: Receiver<StorageRequest>,
+    autosave: Autosave,
+}
+
+impl Storage {
+    pub fn new(request_receive: Receiver<StorageRequest>, autosave: Autosave) -> Self {
+        Storage { request_receive, autosave }
+    }
+
+    pub fn handle_request(&mut self, request: StorageRequest) {
+        match request {
+            StorageRequest::Save(path, project) => {
+                match project.save(&path) {
+                    Ok(_) => {
+                        println!("Saved project to {:?}", path);
+
+                        // Saving under another name leaves our project file as it was
+                        if Autosave::directory(&path) == self.autosave.directory {
+                            if let Err(e) = self.autosave.remove_snapshots() {
+                                println!("Error: could not remove autosave snapshots from {:?}: {}", self.autosave.directory, e);
+                            }
+                        }
+                    },
+                    Err(e) => println!("Error: could not save project to {:?}: {}", path, e),
+                }
+
+                // Process handler forgets its changes when saving, the ones it hands us next apply to this state
+                self.autosave.project = project;
+            },
+            StorageRequest::Autosave(changes) => self.autosave.apply(changes),
+            StorageRequest::Export(path, project) => {
+                match Self::render(project).save(&path) {
+                    Ok(_) => println!("Exported timeline to {:?}", path),
+                    Err(e) => println!("Error: could not export timeline to {:?}: {}", path, e),
+                }
+            },
+        }
+    }
+
+    // Play project on a sequencer of our own, instruments only matter for live playback
+    fn render(mut project: Project) -> MidiFile {
+        let (timebase_sender, _timebase_receiver) = channel();
+        let mut sequencer = Sequencer::new(None, timebase_sender, SyncSource::Internal);
+
+        project.channels.iter_mut().for_each(|channel| channel.instrument = None);
+        sequencer.load_project(project);
+
+        MidiFile::from_arrangement(sequencer.arrangement(), sequencer.tempo_map())
+    }
+
+    // Start handling requests, this function halts until the process handler hangs up
+    pub fn start(&mut self) {
+        while let Ok(request) = self.request_receive.recv() {
+            self.handle_request(request);
+        }
+    }
+}
@@ -2,7 +2,9 @@
 use super::controller::input::*;
 use super::TimebaseHandler;
 use super::Sequencer;
+use super::channel::Channel;
 use super::loopable::*;
+use super::tempo::TapTempo;
 
 #[derive(Debug, PartialEq)]
 pub enum View {
@@ -15,9 +17,14 @@ pub struct Surface {
     pub view: View,
     pub button_memory: ButtonMemory,
     pub event_memory: EventMemory,
+    pub tap_tempo: TapTempo,
+    // In velocity mode grid buttons select notes for velocity editing instead of toggling them
+    pub is_editing_velocity: bool,
 
     channel_shown: u8,
     sequence_shown: u8,
+    // Side buttons & grid rows show patterns, phrases & sequences of this bank
+    bank: u8,
     timeline_offset: u32,
 
     phrase_shown: [u8; 16],
@@ -28,21 +35,28 @@ pub struct Surface {
     pattern_zoom_level: u8,
     pattern_offsets: [u32; 16],
     pattern_base_notes: [u8; 16],
+    // Velocity of notes we add to patterns, default velocity of instrument until we choose one
+    input_velocity: Option<u8>,
 }
 
 impl Surface {
     pub const PATTERN_TICKS_PER_BUTTON: u32 = TimebaseHandler::TICKS_PER_BEAT as u32 * 2;
     pub const PHRASE_TICKS_PER_BUTTON: u32 = Self::PATTERN_TICKS_PER_BUTTON * 4;
     pub const TIMELINE_TICKS_PER_BUTTON: u32 = Self::PHRASE_TICKS_PER_BUTTON * 1;
+    // Velocity is chosen in 8 levels, one for every button in a row
+    pub const VELOCITY_PER_LEVEL: u8 = 16;
 
     pub fn new() -> Self {
         Surface { 
             view: View::Channel, 
             button_memory: ButtonMemory::new(),
             event_memory: EventMemory::new(),
+            tap_tempo: TapTempo::new(),
+            is_editing_velocity: false,
 
             channel_shown: 0,
             sequence_shown: 0,
+            bank: 0,
             timeline_offset: 0,
 
             phrase_shown: [0; 16],
@@ -53,9 +67,14 @@ impl Surface {
             pattern_zoom_level: 4,
             pattern_offsets: [0; 16],
             pattern_base_notes: [60; 16],
+            input_velocity: None,
         }
     }
 
+    pub fn input_velocity(&self, default_velocity: u8) -> u8 { self.input_velocity.unwrap_or(default_velocity) }
+    pub fn input_velocity_level(&self, default_velocity: u8) -> u8 { self.input_velocity(default_velocity) / Self::VELOCITY_PER_LEVEL }
+    pub fn set_input_velocity_level(&mut self, level: u8) { self.input_velocity = Some((level + 1) * Self::VELOCITY_PER_LEVEL - 1) }
+
     pub fn switch_view(&mut self, view: View) { 
         self.view = view;
     }
@@ -69,6 +88,22 @@ impl Surface {
     pub fn pattern_shown(&self, channel_index: usize) -> u8 { self.pattern_shown[channel_index] }
     pub fn show_pattern(&mut self, channel_index: usize, index: u8) { self.pattern_shown[channel_index] = index }
 
+    pub fn bank(&self) -> u8 { self.bank }
+    pub fn switch_bank(&mut self, delta: i8) {
+        self.bank = (self.bank as i8 + delta).max(0).min(Channel::BANKS as i8 - 1) as u8;
+    }
+    pub fn bank_start(&self) -> u8 { self.bank * Channel::BANK_SIZE }
+    // Index of pattern, phrase or sequence under side button or grid row
+    pub fn bank_index(&self, button: u8) -> u8 { self.bank_start() + button }
+    // Side button or grid row of index, when it's in the shown bank
+    pub fn bank_button(&self, index: u8) -> Option<u8> {
+        if index >= self.bank_start() && index < self.bank_start() + Channel::BANK_SIZE {
+            Some(index - self.bank_start())
+        } else {
+            None
+        }
+    }
+
     pub fn pattern_ticks_per_button(&self) -> u32 { Self::PATTERN_TICKS_PER_BUTTON / self.pattern_zoom_level() as u32 }
     pub fn pattern_ticks_in_grid(&self) -> u32 { self.pattern_ticks_per_button() * 8 }
     pub fn phrase_ticks_per_button(&self) -> u32 { Self::PHRASE_TICKS_PER_BUTTON / self.phrase_zoom_level() as u32 }
@@ -263,6 +298,18 @@ impl ButtonMemory {
             .and_then(|pressed_button| Some(pressed_button.button_type))
     }
 
+    // Buttons that are held down on controller
+    pub fn pressed(&self, controller_channel_offset: u8) -> Vec<ButtonType> {
+        self.pressed_buttons.iter()
+            .filter(|pressed_button| pressed_button.controller_channel_offset == controller_channel_offset)
+            .map(|pressed_button| pressed_button.button_type)
+            .collect()
+    }
+
+    pub fn is_pressed(&self, button_type: ButtonType) -> bool {
+        self.pressed_buttons.iter().any(|pressed_button| pressed_button.button_type == button_type)
+    }
+
     pub fn global_modifier(&self, button_type: ButtonType) -> Option<&ButtonPress> {
         self.pressed_buttons.iter()
             .filter(|pressed_button| pressed_button.button_type != button_type)
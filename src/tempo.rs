@@ -0,0 +1,317 @@
+
+use super::TimebaseHandler;
+
+const TICKS_PER_BEAT: u32 = TimebaseHandler::TICKS_PER_BEAT as u32;
+
+/*
+ * Calculate tempo from the time between button presses
+ */
+pub struct TapTempo {
+    taps: Vec<u64>,
+}
+
+impl TapTempo {
+    // Average over this amount of taps
+    const TAPS: usize = 4;
+    // Start over when we didn't tap for a while
+    const TIMEOUT_USECS: u64 = 2000000;
+
+    pub fn new() -> Self {
+        TapTempo { taps: vec![] }
+    }
+
+    // Register tap, returns beats per minute once we have enough taps
+    pub fn tap(&mut self, usecs: u64) -> Option<f64> {
+        if let Some(last) = self.taps.last() {
+            if usecs < *last || usecs - last > Self::TIMEOUT_USECS {
+                self.taps.clear();
+            }
+        }
+
+        self.taps.push(usecs);
+
+        if self.taps.len() > Self::TAPS {
+            self.taps.remove(0);
+        }
+
+        if self.taps.len() < 2 {
+            return None;
+        }
+
+        let usecs_per_beat = (self.taps[self.taps.len() - 1] - self.taps[0]) as f64 / (self.taps.len() - 1) as f64;
+        Some(60000000.0 / usecs_per_beat)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoChange {
+    pub tick: u32,
+    pub beats_per_minute: f64,
+    // Ramp linearly towards tempo of next change
+    pub is_ramp: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeterChange {
+    pub bar: u32,
+    pub beats_per_bar: u8,
+    pub beat_type: u8,
+}
+
+// Position on the timeline, bar & beat are counted from 0
+#[derive(Debug, PartialEq)]
+pub struct BarBeatTick {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick: u32,
+    pub bar_start_tick: u32,
+    pub beats_per_bar: u8,
+    pub beat_type: u8,
+}
+
+/*
+ * Tempo & meter changes on the timeline. A beat is always TICKS_PER_BEAT ticks, so tempo changes
+ * are positioned in ticks, meter changes are positioned on bars. There's always a tempo change at
+ * tick 0 and a meter change at bar 0
+ */
+#[derive(Clone, Debug)]
+pub struct TempoMap {
+    tempo_changes: Vec<TempoChange>,
+    meter_changes: Vec<MeterChange>,
+}
+
+impl TempoMap {
+    pub fn new(beats_per_minute: f64, beats_per_bar: u8, beat_type: u8) -> Self {
+        TempoMap {
+            tempo_changes: vec![TempoChange { tick: 0, beats_per_minute, is_ramp: false }],
+            meter_changes: vec![MeterChange { bar: 0, beats_per_bar, beat_type }],
+        }
+    }
+
+    pub fn tempo_changes(&self) -> &[TempoChange] { &self.tempo_changes }
+    pub fn meter_changes(&self) -> &[MeterChange] { &self.meter_changes }
+
+    pub fn set_tempo(&mut self, tick: u32, beats_per_minute: f64, is_ramp: bool) {
+        let change = TempoChange { tick, beats_per_minute, is_ramp };
+
+        match self.tempo_changes.iter_mut().find(|change| change.tick == tick) {
+            Some(existing) => *existing = change,
+            None => {
+                self.tempo_changes.push(change);
+                self.tempo_changes.sort_by_key(|change| change.tick);
+            },
+        }
+    }
+
+    // Tempo at tick 0 can only be changed, not removed
+    pub fn remove_tempo(&mut self, tick: u32) {
+        self.tempo_changes.retain(|change| change.tick == 0 || change.tick != tick);
+    }
+
+    pub fn set_meter(&mut self, bar: u32, beats_per_bar: u8, beat_type: u8) {
+        let change = MeterChange { bar, beats_per_bar, beat_type };
+
+        match self.meter_changes.iter_mut().find(|change| change.bar == bar) {
+            Some(existing) => *existing = change,
+            None => {
+                self.meter_changes.push(change);
+                self.meter_changes.sort_by_key(|change| change.bar);
+            },
+        }
+    }
+
+    pub fn remove_meter(&mut self, bar: u32) {
+        self.meter_changes.retain(|change| change.bar == 0 || change.bar != bar);
+    }
+
+    fn tempo_index_at(&self, tick: f64) -> usize {
+        self.tempo_changes.iter().rposition(|change| change.tick as f64 <= tick).unwrap_or(0)
+    }
+
+    // Start, stop, tempo at start & tempo change per tick of segment following tempo change
+    fn segment(&self, index: usize) -> (f64, Option<f64>, f64, f64) {
+        let change = &self.tempo_changes[index];
+        let next = self.tempo_changes.get(index + 1);
+        let start = change.tick as f64;
+
+        let slope = match next {
+            Some(next) if change.is_ramp => (next.beats_per_minute - change.beats_per_minute) / (next.tick as f64 - start),
+            _ => 0.0,
+        };
+
+        (start, next.map(|next| next.tick as f64), change.beats_per_minute, slope)
+    }
+
+    pub fn beats_per_minute_at(&self, tick: u32) -> f64 {
+        let (start, _, beats_per_minute, slope) = self.segment(self.tempo_index_at(tick as f64));
+        beats_per_minute + slope * (tick as f64 - start)
+    }
+
+    // Change tempo of the segment we're in, keeping it's position on the timeline
+    pub fn set_beats_per_minute_at(&mut self, tick: u32, beats_per_minute: f64) {
+        let index = self.tempo_index_at(tick as f64);
+        self.tempo_changes[index].beats_per_minute = beats_per_minute;
+    }
+
+    pub fn seconds_at_tick(&self, tick: f64) -> f64 {
+        let mut seconds = 0.0;
+
+        for index in 0 .. self.tempo_changes.len() {
+            let (start, stop, beats_per_minute, slope) = self.segment(index);
+
+            match stop {
+                Some(stop) if stop < tick => seconds += segment_seconds(stop - start, beats_per_minute, slope),
+                _ => return seconds + segment_seconds(tick - start, beats_per_minute, slope),
+            }
+        }
+
+        seconds
+    }
+
+    pub fn tick_at_seconds(&self, seconds: f64) -> f64 {
+        let mut seconds = seconds;
+
+        for index in 0 .. self.tempo_changes.len() {
+            let (start, stop, beats_per_minute, slope) = self.segment(index);
+
+            if let Some(stop) = stop {
+                let length = segment_seconds(stop - start, beats_per_minute, slope);
+
+                if length < seconds {
+                    seconds -= length;
+                    continue;
+                }
+            }
+
+            return start + segment_ticks(seconds, beats_per_minute, slope);
+        }
+
+        0.0
+    }
+
+    pub fn bar_to_tick(&self, bar: u32) -> u32 {
+        let mut tick = 0;
+
+        for (index, change) in self.meter_changes.iter().enumerate() {
+            let ticks_per_bar = change.beats_per_bar as u32 * TICKS_PER_BEAT;
+
+            match self.meter_changes.get(index + 1) {
+                Some(next) if next.bar <= bar => tick += (next.bar - change.bar) * ticks_per_bar,
+                _ => return tick + (bar - change.bar) * ticks_per_bar,
+            }
+        }
+
+        tick
+    }
+
+    pub fn bar_beat_tick(&self, tick: u32) -> BarBeatTick {
+        let mut bar_start_tick = 0;
+        let mut index = 0;
+
+        // Find meter change we're in
+        while let Some(next) = self.meter_changes.get(index + 1) {
+            let change = &self.meter_changes[index];
+            let length = (next.bar - change.bar) * change.beats_per_bar as u32 * TICKS_PER_BEAT;
+
+            if bar_start_tick + length > tick {
+                break;
+            }
+
+            bar_start_tick += length;
+            index += 1;
+        }
+
+        let change = &self.meter_changes[index];
+        let ticks_per_bar = change.beats_per_bar as u32 * TICKS_PER_BEAT;
+        let bars = (tick - bar_start_tick) / ticks_per_bar;
+        let bar_start_tick = bar_start_tick + bars * ticks_per_bar;
+        let ticks_in_bar = tick - bar_start_tick;
+
+        BarBeatTick {
+            bar: change.bar + bars,
+            beat: ticks_in_bar / TICKS_PER_BEAT,
+            tick: ticks_in_bar % TICKS_PER_BEAT,
+            bar_start_tick,
+            beats_per_bar: change.beats_per_bar,
+            beat_type: change.beat_type,
+        }
+    }
+}
+
+// Seconds it takes to play ticks of a segment that starts at given tempo
+fn segment_seconds(ticks: f64, beats_per_minute: f64, slope: f64) -> f64 {
+    if slope == 0.0 {
+        ticks / TimebaseHandler::TICKS_PER_BEAT / beats_per_minute * 60.0
+    } else {
+        60.0 / (TimebaseHandler::TICKS_PER_BEAT * slope) * (1.0 + slope * ticks / beats_per_minute).ln()
+    }
+}
+
+// Inverse of segment_seconds
+fn segment_ticks(seconds: f64, beats_per_minute: f64, slope: f64) -> f64 {
+    if slope == 0.0 {
+        seconds / 60.0 * beats_per_minute * TimebaseHandler::TICKS_PER_BEAT
+    } else {
+        beats_per_minute * ((seconds * TimebaseHandler::TICKS_PER_BEAT * slope / 60.0).exp() - 1.0) / slope
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tap() {
+        let mut tap_tempo = TapTempo::new();
+
+        assert_eq!(tap_tempo.tap(1000000), None);
+        assert_eq!(tap_tempo.tap(1500000), Some(120.0));
+        assert_eq!(tap_tempo.tap(2000000), Some(120.0));
+        // Only last 4 taps count
+        tap_tempo.tap(2400000);
+        assert_eq!(tap_tempo.tap(2800000), Some(60000000.0 / 433333.3333333333));
+        // Timeout starts over
+        assert_eq!(tap_tempo.tap(10000000), None);
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let mut tempo_map = TempoMap::new(120.0, 4, 4);
+        assert_eq!(tempo_map.seconds_at_tick(1920.0), 0.5);
+
+        // Twice as fast after 2 beats
+        tempo_map.set_tempo(3840, 240.0, false);
+        assert_eq!(tempo_map.seconds_at_tick(5760.0), 1.25);
+        assert_eq!(tempo_map.tick_at_seconds(1.25), 5760.0);
+        assert_eq!(tempo_map.beats_per_minute_at(3839), 120.0);
+
+        // Ramp from 120 to 240 over 2 beats
+        tempo_map.set_tempo(0, 120.0, true);
+        assert_eq!(tempo_map.beats_per_minute_at(1920), 180.0);
+        let seconds = tempo_map.seconds_at_tick(3840.0);
+        assert!(seconds > 0.5 && seconds < 1.0);
+        assert!((tempo_map.tick_at_seconds(seconds) - 3840.0).abs() < 0.001);
+
+        tempo_map.remove_tempo(3840);
+        tempo_map.remove_tempo(0);
+        assert_eq!(tempo_map.tempo_changes().len(), 1);
+    }
+
+    #[test]
+    fn meter_changes() {
+        let mut tempo_map = TempoMap::new(120.0, 4, 4);
+        // 2 bars of 4/4, followed by 7/8
+        tempo_map.set_meter(2, 7, 8);
+
+        assert_eq!(tempo_map.bar_to_tick(2), 15360);
+        assert_eq!(tempo_map.bar_to_tick(3), 15360 + 7 * 1920);
+
+        let position = tempo_map.bar_beat_tick(15360 + 7 * 1920 + 1930);
+        assert_eq!((position.bar, position.beat, position.tick), (3, 1, 10));
+        assert_eq!(position.bar_start_tick, 15360 + 7 * 1920);
+        assert_eq!((position.beats_per_bar, position.beat_type), (7, 8));
+
+        let position = tempo_map.bar_beat_tick(1920 * 5);
+        assert_eq!((position.bar, position.beat, position.tick, position.beats_per_bar), (1, 1, 0, 4));
+    }
+}
@@ -0,0 +1,91 @@
+
+use std::cell::Cell;
+
+/*
+ * Transport that is rolling through our cycles. Usually jack's transport, while rendering offline
+ * a synthetic one that moves exactly one buffer every cycle
+ */
+pub trait Transport {
+    fn frame_rate(&self) -> u32;
+    fn start(&self);
+    fn stop(&self);
+    fn reposition(&self, frame: u32);
+}
+
+impl Transport for jack::Client {
+    fn frame_rate(&self) -> u32 { jack::Client::frame_rate(self) as u32 }
+    fn start(&self) { self.transport_start(); }
+    fn stop(&self) { self.transport_stop(); }
+
+    fn reposition(&self, frame: u32) {
+        let mut position = jack::Position::default();
+        position.frame = frame;
+        self.transport_reposition(position);
+    }
+}
+
+/*
+ * Transport driven by a synthetic clock. Cycles take buffer_size frames, the transport moves these
+ * frames forward every cycle it's rolling. The clock itself keeps going while the transport is
+ * stopped, like jack's does
+ */
+pub struct SyntheticTransport {
+    pub frame_rate: u32,
+    pub buffer_size: u32,
+    is_rolling: Cell<bool>,
+    frame: Cell<u32>,
+    // Frames the clock went through
+    elapsed: Cell<u64>,
+}
+
+impl SyntheticTransport {
+    pub fn new(frame_rate: u32, buffer_size: u32) -> Self {
+        SyntheticTransport { frame_rate, buffer_size, is_rolling: Cell::new(false), frame: Cell::new(0), elapsed: Cell::new(0) }
+    }
+
+    pub fn is_rolling(&self) -> bool { self.is_rolling.get() }
+    pub fn frame(&self) -> u32 { self.frame.get() }
+
+    pub fn usecs(&self) -> u64 {
+        self.elapsed.get() * 1_000_000 / self.frame_rate as u64
+    }
+
+    // Move to next cycle
+    pub fn advance(&self) {
+        self.elapsed.set(self.elapsed.get() + self.buffer_size as u64);
+
+        if self.is_rolling() {
+            self.frame.set(self.frame() + self.buffer_size);
+        }
+    }
+}
+
+impl Transport for SyntheticTransport {
+    fn frame_rate(&self) -> u32 { self.frame_rate }
+    fn start(&self) { self.is_rolling.set(true); }
+    fn stop(&self) { self.is_rolling.set(false); }
+    fn reposition(&self, frame: u32) { self.frame.set(frame); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance() {
+        let transport = SyntheticTransport::new(48000, 256);
+        transport.advance();
+        assert_eq!(transport.frame(), 0);
+
+        transport.start();
+        transport.advance();
+        transport.advance();
+        assert_eq!(transport.frame(), 512);
+
+        transport.stop();
+        transport.reposition(10);
+        transport.advance();
+        assert_eq!((transport.is_rolling(), transport.frame()), (false, 10));
+        assert_eq!(transport.usecs(), 4 * 256 * 1_000_000 / 48000);
+    }
+}
@@ -0,0 +1,291 @@
+
+use std::io::{self, Read, Write};
+use std::process;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+use super::controller::input::*;
+use super::command::Console;
+use super::mirror::{Lights, Snapshot, ROWS};
+
+const HELP: &str = "hjkl cursor  space grid  x hold grid  1-5 side  c/a/s/r/i channel/activator/solo/arm/indicator  \
+    m master  z shift  HJKL arrows  p play  o stop  R record  t tap  q quantize  -/+ nudge  : command";
+
+/*
+ * Keys are mapped on buttons of the controller under the cursor, grid columns 0 - 7 are the APC20,
+ * 8 - 15 the APC40. Buttons only the APC40 has are always pressed on the APC40
+ */
+pub struct Keys {
+    pub cursor: (u8, u8),
+    // Grid button that's held down, so the next grid button can be pressed while holding it
+    held: Option<(usize, ButtonType)>,
+    is_shifted: bool,
+}
+
+impl Keys {
+    pub fn new() -> Self {
+        Keys { cursor: (0, 0), held: None, is_shifted: false }
+    }
+
+    fn controller(&self) -> usize { self.cursor.0 as usize / 8 }
+    fn column(&self) -> u8 { self.cursor.0 % 8 }
+
+    fn click(controller: usize, button_type: ButtonType) -> Vec<(usize, InputEventType)> {
+        vec![(controller, InputEventType::ButtonPressed(button_type)), (controller, InputEventType::ButtonReleased(button_type))]
+    }
+
+    // Events for controller with index key results in
+    pub fn key(&mut self, key: char) -> Vec<(usize, InputEventType)> {
+        let (x, y) = self.cursor;
+        let column = self.column();
+
+        match key {
+            'h' => { self.cursor.0 = x.saturating_sub(1); vec![] },
+            'l' => { self.cursor.0 = (x + 1).min(15); vec![] },
+            'j' => { self.cursor.1 = y.saturating_sub(1); vec![] },
+            'k' => { self.cursor.1 = (y + 1).min(4); vec![] },
+            ' ' => Self::click(self.controller(), ButtonType::Grid(column, y)),
+            'x' => match self.held.take() {
+                Some((controller, button_type)) => vec![(controller, InputEventType::ButtonReleased(button_type))],
+                None => {
+                    self.held = Some((self.controller(), ButtonType::Grid(column, y)));
+                    vec![(self.controller(), InputEventType::ButtonPressed(ButtonType::Grid(column, y)))]
+                },
+            },
+            // Number keys count side buttons from the top
+            '1' ..= '5' => Self::click(self.controller(), ButtonType::Side(4 - (key as u8 - b'1'))),
+            'c' => Self::click(self.controller(), ButtonType::Channel(column)),
+            'a' => Self::click(self.controller(), ButtonType::Activator(column)),
+            's' => Self::click(self.controller(), ButtonType::Solo(column)),
+            'r' => Self::click(self.controller(), ButtonType::Arm(column)),
+            'i' => Self::click(self.controller(), ButtonType::Indicator(column)),
+            'z' => {
+                self.is_shifted = ! self.is_shifted;
+                let event_type = if self.is_shifted { InputEventType::ButtonPressed(ButtonType::Shift) } else { InputEventType::ButtonReleased(ButtonType::Shift) };
+                vec![(1, event_type)]
+            },
+            'm' => Self::click(1, ButtonType::Master),
+            'H' => Self::click(1, ButtonType::Left),
+            'J' => Self::click(1, ButtonType::Down),
+            'K' => Self::click(1, ButtonType::Up),
+            'L' => Self::click(1, ButtonType::Right),
+            'p' => Self::click(1, ButtonType::Play),
+            'o' => Self::click(1, ButtonType::Stop),
+            'R' => Self::click(1, ButtonType::Record),
+            't' => Self::click(1, ButtonType::TapTempo),
+            'q' => Self::click(1, ButtonType::Quantization),
+            '-' => Self::click(1, ButtonType::NudgeDown),
+            '+' => Self::click(1, ButtonType::NudgeUp),
+            _ => vec![],
+        }
+    }
+}
+
+enum TuiEvent {
+    Snapshot(Box<Snapshot>),
+    Key(char),
+}
+
+/*
+ * Terminal UI, takes over stdin from the console. Commands are typed after pressing :
+ */
+pub struct Tui {
+    snapshot_receiver: Option<Receiver<Snapshot>>,
+    input_sender: Sender<(usize, InputEventType)>,
+    console: Console,
+}
+
+impl Tui {
+    pub fn new(snapshot_receiver: Receiver<Snapshot>, input_sender: Sender<(usize, InputEventType)>, console: Console) -> Self {
+        Tui { snapshot_receiver: Some(snapshot_receiver), input_sender, console }
+    }
+
+    // Led colors of APC's, 1 green, 3 red & 5 yellow, the even values blink
+    fn cell(value: u8, is_cursor: bool) -> String {
+        let color = match value {
+            0 => return format!("{}· \x1b[0m", if is_cursor { "\x1b[7m" } else { "" }),
+            1 | 2 => 32,
+            3 | 4 => 31,
+            _ => 33,
+        };
+        let blink = if matches!(value, 2 | 4 | 6) { ";5" } else { "" };
+        let inverse = if is_cursor { ";7" } else { "" };
+
+        format!("\x1b[{}{}{}m■ \x1b[0m", color, blink, inverse)
+    }
+
+    // Screen lines showing the lights, cursor is drawn on the grid
+    pub fn render(lights: &Lights, cursor: (u8, u8)) -> Vec<String> {
+        let mut lines = vec![format!("{:10}{:16}{:16}", "", "APC20", "APC40")];
+
+        for y in (0 .. 5).rev() {
+            let cells: String = (0 .. 16)
+                .map(|x| Self::cell(lights.grid[y][x], cursor == (x as u8, y as u8)))
+                .collect();
+            lines.push(format!("{:10}{} {}{}", format!("row {}", y), cells, Self::cell(lights.side[0][y], false), Self::cell(lights.side[1][y], false)));
+        }
+
+        for (index, (_, name)) in ROWS.iter().enumerate() {
+            let cells: String = lights.rows[index].iter().map(|value| Self::cell(*value, false)).collect();
+            lines.push(format!("{:10}{}", name, cells));
+        }
+
+        lines.push(format!("{:10}{}", "master", Self::cell(lights.master, false)));
+        lines
+    }
+
+    // Channels, patterns, phrases & sequences are counted from 1 like the console counts bars
+    fn status(snapshot: &Snapshot) -> String {
+        let queued = snapshot.sequence_queued.map(|index| format!(", sequence {} queued", index + 1)).unwrap_or_default();
+
+        format!("{} at {:.1} bpm, channel {} pattern {} phrase {}, sequence {} playing{}",
+            if snapshot.is_rolling { "Playing" } else { "Stopped" }, snapshot.beats_per_minute,
+            snapshot.channel_shown + 1, snapshot.pattern_shown + 1, snapshot.phrase_shown + 1, snapshot.sequence_playing + 1, queued)
+    }
+
+    // Read keys as they're typed instead of per line
+    fn draw(snapshot: &Snapshot, keys: &Keys, command: &Option<String>) {
+        let mut screen = String::from("\x1b[H");
+
+        for line in Self::render(&snapshot.lights, keys.cursor) {
+            screen.push_str(&line);
+            screen.push_str("\x1b[K\n");
+        }
+
+        screen.push_str(&format!("\n{}\x1b[K\n{}\x1b[K\n", Self::status(snapshot), HELP));
+        if let Some(command) = command {
+            screen.push_str(&format!(":{}\x1b[K", command));
+        }
+        // Leave room below for messages of the sequencer
+        screen.push_str("\n\x1b[J");
+
+        print!("{}", screen);
+        let _ = io::stdout().flush();
+    }
+
+    // Start drawing & reading keys, this function halts until stdin is closed
+    pub fn start(&mut self) {
+        let _terminal_mode = TerminalMode::keys();
+        print!("\x1b[2J");
+
+        // Wait for keys & snapshots at the same time
+        let (event_sender, event_receiver) = mpsc::channel();
+
+        let key_sender = event_sender.clone();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let byte = match byte { Ok(byte) => byte, Err(_) => break };
+                if key_sender.send(TuiEvent::Key(byte as char)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        if let Some(snapshot_receiver) = self.snapshot_receiver.take() {
+            thread::spawn(move || {
+                for snapshot in snapshot_receiver {
+                    if event_sender.send(TuiEvent::Snapshot(Box::new(snapshot))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let mut keys = Keys::new();
+        let mut snapshot = None;
+        // Command that's being typed
+        let mut command: Option<String> = None;
+
+        while let Ok(event) = event_receiver.recv() {
+            match (event, &mut command) {
+                (TuiEvent::Snapshot(new_snapshot), _) => snapshot = Some(*new_snapshot),
+                (TuiEvent::Key('\n'), Some(line)) => {
+                    let is_running = self.console.handle(line);
+                    command = None;
+
+                    if ! is_running {
+                        break;
+                    }
+                },
+                // Escape cancels command
+                (TuiEvent::Key('\x1b'), Some(_)) => command = None,
+                (TuiEvent::Key('\x7f'), Some(line)) => { line.pop(); },
+                (TuiEvent::Key(key), Some(line)) => line.push(key),
+                (TuiEvent::Key(':'), None) => command = Some(String::new()),
+                (TuiEvent::Key(key), None) => {
+                    for input in keys.key(key) {
+                        if self.input_sender.send(input).is_err() {
+                            break;
+                        }
+                    }
+                },
+            }
+
+            if let Some(snapshot) = &snapshot {
+                Self::draw(snapshot, &keys, &command);
+            }
+        }
+    }
+}
+
+/*
+ * Terminal hands us keys as they're pressed without echoing them, as long as this lives. The mode is
+ * restored when dropped, also when the UI thread panics
+ */
+struct TerminalMode;
+
+impl TerminalMode {
+    fn keys() -> Self {
+        Self::set(&["-icanon", "-echo"]);
+        TerminalMode
+    }
+
+    fn set(args: &[&str]) {
+        let _ = process::Command::new("stty").args(args).stdin(process::Stdio::inherit()).status();
+    }
+}
+
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        Self::set(&["sane"]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::controller::emulator::Emulator;
+    use super::super::hardware::{APC20, APC40};
+
+    #[test]
+    fn keys() {
+        let mut keys = Keys::new();
+        assert_eq!(keys.key('?'), vec![]);
+
+        // Cursor stays on the grid
+        "hjllllllllllkkkkkkk".chars().for_each(|key| { keys.key(key); });
+        assert_eq!(keys.cursor, (10, 4));
+        assert_eq!(keys.key(' '), Keys::click(1, ButtonType::Grid(2, 4)));
+        assert_eq!(keys.key('1'), Keys::click(1, ButtonType::Side(4)));
+
+        // Held grid button is released where it was pressed
+        keys.key('x');
+        "hhhh".chars().for_each(|key| { keys.key(key); });
+        assert_eq!(keys.key(' '), Keys::click(0, ButtonType::Grid(6, 4)));
+        assert_eq!(keys.key('x'), vec![(1, InputEventType::ButtonReleased(ButtonType::Grid(2, 4)))]);
+
+        assert_eq!(keys.key('z'), vec![(1, InputEventType::ButtonPressed(ButtonType::Shift))]);
+        assert_eq!(keys.key('z'), vec![(1, InputEventType::ButtonReleased(ButtonType::Shift))]);
+    }
+
+    #[test]
+    fn render() {
+        let mut lights = Lights::new(&Emulator::<APC20>::new(), &Emulator::<APC40>::new());
+        lights.grid[4][9] = 3;
+
+        let lines = Tui::render(&lights, (0, 0));
+        assert_eq!(lines.len(), 1 + 5 + 5 + 1);
+        // Top row shows red light on 2nd column of APC40
+        assert_eq!(lines[1].matches("\x1b[31m■").count(), 1);
+        assert!(lines[5].starts_with("row 0     \x1b[7m·"));
+    }
+}